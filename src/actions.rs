@@ -1,13 +1,19 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 
 use crossterm::style::Stylize;
 use handlebars::Handlebars;
 
-use crate::config::{SymbolicTarget, TemplateTarget, Variables};
-use crate::difference::{self, diff_nonempty, generate_template_diff, print_diff};
-use crate::filesystem::{Filesystem, SymlinkComparison, TemplateComparison};
+use crate::backup::Backups;
+use crate::config::{CopyTarget, FileMode, SymbolicTarget, TemplateTarget, Variables};
+use crate::difference::{
+    self, conflict_markers, diff_nonempty, diff_strings, generate_template_diff, print_diff,
+};
+use crate::filesystem::{self, CopyComparison, Filesystem, SymlinkComparison, TemplateComparison};
+use crate::merge::{self, Merge};
+use crate::secrets::Secrets;
 
 #[cfg_attr(test, mockall::automock)]
 pub trait ActionRunner {
@@ -27,43 +33,183 @@ pub trait ActionRunner {
         cache: &Path,
         target: &TemplateTarget,
     ) -> Result<bool>;
+    fn delete_copy(&mut self, source: &Path, target: &Path, hash: &str) -> Result<bool>;
+    fn create_copy(&mut self, source: &Path, target: &CopyTarget, hash: &str) -> Result<bool>;
+    fn update_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached_hash: &str,
+        hash: &str,
+    ) -> Result<bool>;
+
+    /// Inspects the current state of a symlink without changing anything
+    fn compare_symlink(&mut self, source: &Path, target: &Path) -> Result<SymlinkComparison>;
+    /// Inspects the current state of a template without changing anything
+    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison>;
+    /// Inspects the current state of a copy without changing anything
+    fn compare_copy(&mut self, target: &Path, hash: &str) -> Result<CopyComparison>;
+    /// Hashes the contents of a source, which is what the cache records for copies
+    fn hash_file(&mut self, path: &Path) -> Result<String>;
 }
 
 pub struct RealActionRunner<'a> {
     fs: &'a mut dyn Filesystem,
+    backups: &'a mut Backups,
     handlebars: &'a Handlebars<'a>,
     variables: &'a Variables,
+    secrets: &'a Secrets,
+    prerendered: &'a Prerendered,
+    comparisons: Comparisons,
     force: bool,
+    interactive: bool,
+    merge: bool,
+    conflict_markers: bool,
     diff_context_lines: usize,
 }
 
 impl<'a> RealActionRunner<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         fs: &'a mut dyn Filesystem,
+        backups: &'a mut Backups,
         handlebars: &'a Handlebars<'_>,
         variables: &'a Variables,
+        secrets: &'a Secrets,
+        prerendered: &'a Prerendered,
+        comparisons: Comparisons,
         force: bool,
+        interactive: bool,
+        merge: bool,
+        conflict_markers: bool,
         diff_context_lines: usize,
     ) -> RealActionRunner<'a> {
         RealActionRunner {
             fs,
+            backups,
             handlebars,
             variables,
+            secrets,
+            prerendered,
+            comparisons,
             force,
+            interactive,
+            merge,
+            conflict_markers,
             diff_context_lines,
         }
     }
 }
 
+impl RealActionRunner<'_> {
+    /// Forgets the comparisons that a change to `path` could have made outdated
+    fn forget_comparisons(&mut self, path: &Path) {
+        self.comparisons.retain(|target, (cache, _)| {
+            !(target.starts_with(path) || path.starts_with(target) || cache == path)
+        });
+    }
+
+    /// Takes the comparison of a template that was done ahead of time, if it's still up to date
+    fn take_comparison(&mut self, target: &Path, cache: &Path) -> Option<TemplateComparison> {
+        let comparison = self
+            .comparisons
+            .remove(target)
+            .filter(|(compared_cache, _)| compared_cache == cache)
+            .map(|(_, comparison)| comparison);
+        self.forget_comparisons(target);
+        self.forget_comparisons(cache);
+        comparison
+    }
+
+    /// Directories above the target that don't exist yet, so they're created along with it and
+    /// get `dir_mode` too
+    fn missing_parents(
+        &mut self,
+        target: &Path,
+        dir_mode: Option<FileMode>,
+    ) -> Result<Vec<PathBuf>> {
+        let mut missing = Vec::new();
+        // Modes don't exist on Windows, so every directory would look missing
+        if dir_mode.is_none() || cfg!(windows) {
+            return Ok(missing);
+        }
+        for directory in target.ancestors().skip(1) {
+            if directory.as_os_str().is_empty()
+                || self
+                    .fs
+                    .mode(directory)
+                    .context("check whether parent of target exists")?
+                    .is_some()
+            {
+                break;
+            }
+            missing.push(directory.into());
+        }
+        Ok(missing)
+    }
+
+    /// Applies the configured modes to a target that was deployed
+    fn set_modes(
+        &mut self,
+        deployed: bool,
+        target: &Path,
+        mode: Option<FileMode>,
+        dir_mode: Option<FileMode>,
+        created_directories: &[PathBuf],
+    ) -> Result<bool> {
+        if deployed {
+            set_modes(self.fs, target, mode, dir_mode, created_directories)?;
+        }
+        Ok(deployed)
+    }
+
+    /// A symlink has the mode of the file it points at, so its mode is set on the source in the
+    /// repository
+    fn set_source_mode(
+        &mut self,
+        deployed: bool,
+        source: &Path,
+        mode: Option<FileMode>,
+    ) -> Result<bool> {
+        if let (true, Some(mode)) = (deployed, mode) {
+            self.fs
+                .set_mode(source, mode)
+                .context("set mode of symlinked source")?;
+        }
+        Ok(deployed)
+    }
+}
+
 impl ActionRunner for RealActionRunner<'_> {
     fn delete_symlink(&mut self, source: &Path, target: &Path) -> Result<bool> {
-        delete_symlink(source, target, self.fs, self.force)
+        self.forget_comparisons(target);
+        delete_symlink(source, target, self.fs, self.backups, self.force)
     }
     fn delete_template(&mut self, source: &Path, cache: &Path, target: &Path) -> Result<bool> {
-        delete_template(source, cache, target, self.fs, self.force)
+        self.forget_comparisons(target);
+        self.forget_comparisons(cache);
+        delete_template(source, cache, target, self.fs, self.backups, self.force)
     }
     fn create_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
-        create_symlink(source, target, self.fs, self.force)
+        self.forget_comparisons(&target.target);
+        let created_directories = self.missing_parents(&target.target, target.dir_mode)?;
+        let created = create_symlink(
+            source,
+            target,
+            self.fs,
+            self.backups,
+            self.force,
+            self.interactive,
+            self.diff_context_lines,
+        )?;
+        let created = self.set_modes(
+            created,
+            &target.target,
+            None,
+            target.dir_mode,
+            &created_directories,
+        )?;
+        self.set_source_mode(created, source, target.mode)
     }
     fn create_template(
         &mut self,
@@ -71,18 +217,49 @@ impl ActionRunner for RealActionRunner<'_> {
         cache: &Path,
         target: &TemplateTarget,
     ) -> Result<bool> {
-        create_template(
+        let compared = self.take_comparison(&target.target, cache);
+        let created_directories = self.missing_parents(&target.target, target.dir_mode)?;
+        let created = create_template(
             source,
             cache,
             target,
             self.fs,
+            self.backups,
             self.handlebars,
             self.variables,
+            self.secrets,
+            self.prerendered,
+            compared,
             self.force,
+        )?;
+        self.set_modes(
+            created,
+            &target.target,
+            target.mode,
+            target.dir_mode,
+            &created_directories,
         )
     }
     fn update_symlink(&mut self, source: &Path, target: &SymbolicTarget) -> Result<bool> {
-        update_symlink(source, target, self.fs, self.force)
+        self.forget_comparisons(&target.target);
+        let created_directories = self.missing_parents(&target.target, target.dir_mode)?;
+        let updated = update_symlink(
+            source,
+            target,
+            self.fs,
+            self.backups,
+            self.force,
+            self.interactive,
+            self.diff_context_lines,
+        )?;
+        let updated = self.set_modes(
+            updated,
+            &target.target,
+            None,
+            target.dir_mode,
+            &created_directories,
+        )?;
+        self.set_source_mode(updated, source, target.mode)
     }
     fn update_template(
         &mut self,
@@ -90,17 +267,88 @@ impl ActionRunner for RealActionRunner<'_> {
         cache: &Path,
         target: &TemplateTarget,
     ) -> Result<bool> {
-        update_template(
+        let compared = self.take_comparison(&target.target, cache);
+        let created_directories = self.missing_parents(&target.target, target.dir_mode)?;
+        let updated = update_template(
             source,
             cache,
             target,
             self.fs,
+            self.backups,
             self.handlebars,
             self.variables,
+            self.secrets,
+            self.prerendered,
+            compared,
             self.force,
+            self.interactive,
+            self.merge,
+            self.conflict_markers,
             self.diff_context_lines,
+        )?;
+        self.set_modes(
+            updated,
+            &target.target,
+            target.mode,
+            target.dir_mode,
+            &created_directories,
+        )
+    }
+    fn delete_copy(&mut self, source: &Path, target: &Path, hash: &str) -> Result<bool> {
+        self.forget_comparisons(target);
+        delete_copy(source, target, hash, self.fs, self.backups, self.force)
+    }
+    fn create_copy(&mut self, source: &Path, target: &CopyTarget, hash: &str) -> Result<bool> {
+        self.forget_comparisons(&target.target);
+        let created_directories = self.missing_parents(&target.target, target.dir_mode)?;
+        let created = create_copy(source, target, hash, self.fs, self.backups, self.force)?;
+        self.set_modes(
+            created,
+            &target.target,
+            target.mode,
+            target.dir_mode,
+            &created_directories,
         )
     }
+    fn update_copy(
+        &mut self,
+        source: &Path,
+        target: &CopyTarget,
+        cached_hash: &str,
+        hash: &str,
+    ) -> Result<bool> {
+        self.forget_comparisons(&target.target);
+        let created_directories = self.missing_parents(&target.target, target.dir_mode)?;
+        let updated = update_copy(
+            source,
+            target,
+            cached_hash,
+            hash,
+            self.fs,
+            self.backups,
+            self.force,
+        )?;
+        self.set_modes(
+            updated,
+            &target.target,
+            target.mode,
+            target.dir_mode,
+            &created_directories,
+        )
+    }
+
+    fn compare_symlink(&mut self, source: &Path, target: &Path) -> Result<SymlinkComparison> {
+        self.fs.compare_symlink(source, target)
+    }
+    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
+        self.fs.compare_template(target, cache)
+    }
+    fn compare_copy(&mut self, target: &Path, hash: &str) -> Result<CopyComparison> {
+        self.fs.compare_copy(target, hash)
+    }
+    fn hash_file(&mut self, path: &Path) -> Result<String> {
+        self.fs.hash_file(path)
+    }
 }
 
 // == DELETE ==
@@ -110,6 +358,7 @@ pub fn delete_symlink(
     source: &Path,
     target: &Path,
     fs: &mut dyn Filesystem,
+    backups: &mut Backups,
     force: bool,
 ) -> Result<bool> {
     info!("{} symlink {:?} -> {:?}", "[-]".red(), source, target);
@@ -138,8 +387,11 @@ pub fn delete_symlink(
                 "Deleting symlink {:?} -> {:?} but {}. Forcing.",
                 source, target, comparison
             );
-            perform_symlink_target_deletion(fs, target)
-                .context("perform symlink target deletion")?;
+            backups
+                .backup(fs, target)
+                .context("back up target while forcing")?;
+            fs.delete_parents(target, false)
+                .context("delete parents of symlink")?;
             Ok(true)
         }
         SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink => {
@@ -165,6 +417,7 @@ pub fn delete_template(
     cache: &Path,
     target: &Path,
     fs: &mut dyn Filesystem,
+    backups: &mut Backups,
     force: bool,
 ) -> Result<bool> {
     info!("{} template {:?} -> {:?}", "[-]".red(), source, target);
@@ -195,7 +448,7 @@ pub fn delete_template(
                 "Deleting template {:?} -> {:?} but cache doesn't exist. Cache probably CORRUPTED.",
                 source, target
             );
-            error!("This is probably a bug. Delete cache.toml and cache/ folder.");
+            error!("Run `dotter cache repair` to fix it.");
             Ok(false)
         }
         TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile if force => {
@@ -204,8 +457,11 @@ pub fn delete_template(
                 source, target, comparison
             );
             perform_cache_deletion(fs, cache).context("perform cache deletion")?;
-            perform_template_target_deletion(fs, target)
-                .context("perform template target deletion")?;
+            backups
+                .backup(fs, target)
+                .context("back up target while forcing")?;
+            fs.delete_parents(target, false)
+                .context("delete parent directory in target location")?;
             Ok(true)
         }
         TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile => {
@@ -232,6 +488,59 @@ fn perform_template_target_deletion(fs: &mut dyn Filesystem, target: &Path) -> R
     Ok(())
 }
 
+/// Returns true if copy should be deleted from cache
+pub fn delete_copy(
+    source: &Path,
+    target: &Path,
+    hash: &str,
+    fs: &mut dyn Filesystem,
+    backups: &mut Backups,
+    force: bool,
+) -> Result<bool> {
+    info!("{} copy {:?} -> {:?}", "[-]".red(), source, target);
+
+    let comparison = fs
+        .compare_copy(target, hash)
+        .context("detect copied file's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        CopyComparison::Identical => {
+            debug!("Performing deletion");
+            fs.remove_file(target).context("delete target file")?;
+            fs.delete_parents(target, false)
+                .context("delete parent directory in target location")?;
+            Ok(true)
+        }
+        CopyComparison::TargetMissing => {
+            warn!(
+                "Deleting copy {:?} -> {:?} but {}. Removing from cache anyways.",
+                source, target, comparison
+            );
+            Ok(true)
+        }
+        CopyComparison::Changed | CopyComparison::TargetNotRegularFile if force => {
+            warn!(
+                "Deleting copy {:?} -> {:?} but {}. Forcing.",
+                source, target, comparison
+            );
+            backups
+                .backup(fs, target)
+                .context("back up target while forcing")?;
+            fs.delete_parents(target, false)
+                .context("delete parent directory in target location")?;
+            Ok(true)
+        }
+        CopyComparison::Changed | CopyComparison::TargetNotRegularFile => {
+            error!(
+                "Deleting copy {:?} -> {:?} but {}. Skipping.",
+                source, target, comparison
+            );
+            Ok(false)
+        }
+    }
+}
+
 // == CREATE ==
 
 /// Returns true if symlink should be added to cache
@@ -239,7 +548,10 @@ pub fn create_symlink(
     source: &Path,
     target: &SymbolicTarget,
     fs: &mut dyn Filesystem,
+    backups: &mut Backups,
     force: bool,
+    interactive: bool,
+    diff_context_lines: usize,
 ) -> Result<bool> {
     info!(
         "{} symlink {:?} -> {:?}",
@@ -262,9 +574,10 @@ pub fn create_symlink(
                     .parent()
                     .context("get parent of target file")?,
                 &target.owner,
+                &target.group,
             )
             .context("create parent for target file")?;
-            fs.make_symlink(&target.target, source, &target.owner)
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
                 .context("create target symlink")?;
             Ok(true)
         }
@@ -284,12 +597,20 @@ pub fn create_symlink(
                 "Creating symlink {:?} -> {:?} but {}. Forcing.",
                 source, target.target, comparison
             );
-            fs.remove_file(&target.target)
-                .context("remove symlink target while forcing")?;
-            fs.make_symlink(&target.target, source, &target.owner)
+            backups
+                .backup(fs, &target.target)
+                .context("back up symlink target while forcing")?;
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
                 .context("create target symlink")?;
             Ok(true)
         }
+        SymlinkComparison::TargetNotSymlink if interactive => {
+            warn!(
+                "Creating symlink {:?} -> {:?} but {}.",
+                source, target.target, comparison
+            );
+            resolve_symlink_conflict(source, target, fs, backups, diff_context_lines)
+        }
         SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink => {
             error!(
                 "Creating symlink {:?} -> {:?} but {}. Skipping.",
@@ -301,13 +622,18 @@ pub fn create_symlink(
 }
 
 /// Returns true if the template should be added to cache
+#[allow(clippy::too_many_arguments)]
 pub fn create_template(
     source: &Path,
     cache: &Path,
     target: &TemplateTarget,
     fs: &mut dyn Filesystem,
+    backups: &mut Backups,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
+    compared: Option<TemplateComparison>,
     force: bool,
 ) -> Result<bool> {
     info!(
@@ -317,9 +643,12 @@ pub fn create_template(
         target.target
     );
 
-    let comparison = fs
-        .compare_template(&target.target, cache)
-        .context("detect templated file's current state")?;
+    let comparison = match compared {
+        Some(comparison) => comparison,
+        None => fs
+            .compare_template(&target.target, cache)
+            .context("detect templated file's current state")?,
+    };
     debug!("Current state: {}", comparison);
 
     match comparison {
@@ -331,10 +660,20 @@ pub fn create_template(
                     .parent()
                     .context("get parent of target file")?,
                 &target.owner,
+                &target.group,
             )
             .context("create parent for target file")?;
-            perform_template_deploy(source, cache, Some(target), fs, handlebars, variables)
-                .context("perform template cache")?;
+            perform_template_deploy(
+                source,
+                cache,
+                Some(target),
+                fs,
+                handlebars,
+                variables,
+                secrets,
+                prerendered,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
         TemplateComparison::OnlyCacheExists | TemplateComparison::Identical => {
@@ -348,10 +687,20 @@ pub fn create_template(
                     .parent()
                     .context("get parent of target file")?,
                 &target.owner,
+                &target.group,
             )
             .context("create parent for target file")?;
-            perform_template_deploy(source, cache, Some(target), fs, handlebars, variables)
-                .context("perform template cache")?;
+            perform_template_deploy(
+                source,
+                cache,
+                Some(target),
+                fs,
+                handlebars,
+                variables,
+                secrets,
+                prerendered,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
         TemplateComparison::TargetNotRegularFile
@@ -363,18 +712,29 @@ pub fn create_template(
                 "Creating template {:?} -> {:?} but target file already exists. Forcing.",
                 source, target.target
             );
-            fs.remove_file(&target.target)
-                .context("remove existing file while forcing")?;
+            backups
+                .backup(fs, &target.target)
+                .context("back up existing file while forcing")?;
             fs.create_dir_all(
                 target
                     .target
                     .parent()
                     .context("get parent of target file")?,
                 &target.owner,
+                &target.group,
             )
             .context("create parent for target file")?;
-            perform_template_deploy(source, cache, Some(target), fs, handlebars, variables)
-                .context("perform template cache")?;
+            perform_template_deploy(
+                source,
+                cache,
+                Some(target),
+                fs,
+                handlebars,
+                variables,
+                secrets,
+                prerendered,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
         TemplateComparison::TargetNotRegularFile
@@ -389,6 +749,62 @@ pub fn create_template(
     }
 }
 
+/// Returns true if the copy should be added to cache
+pub fn create_copy(
+    source: &Path,
+    target: &CopyTarget,
+    hash: &str,
+    fs: &mut dyn Filesystem,
+    backups: &mut Backups,
+    force: bool,
+) -> Result<bool> {
+    info!("{} copy {:?} -> {:?}", "[+]".green(), source, target.target);
+
+    let comparison = fs
+        .compare_copy(&target.target, hash)
+        .context("detect copied file's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        CopyComparison::TargetMissing => {
+            debug!("Performing creation");
+            fs.create_dir_all(
+                target
+                    .target
+                    .parent()
+                    .context("get parent of target file")?,
+                &target.owner,
+                &target.group,
+            )
+            .context("create parent for target file")?;
+            perform_copy(source, target, fs).context("perform copy")?;
+            Ok(true)
+        }
+        CopyComparison::Identical => {
+            warn!("Creating copy {:?} -> {:?} but target already has the same contents. Adding to cache anyways", source, target.target);
+            Ok(true)
+        }
+        CopyComparison::Changed | CopyComparison::TargetNotRegularFile if force => {
+            warn!(
+                "Creating copy {:?} -> {:?} but target file already exists. Forcing.",
+                source, target.target
+            );
+            backups
+                .backup(fs, &target.target)
+                .context("back up existing file while forcing")?;
+            perform_copy(source, target, fs).context("perform copy")?;
+            Ok(true)
+        }
+        CopyComparison::Changed | CopyComparison::TargetNotRegularFile => {
+            error!(
+                "Creating copy {:?} -> {:?} but target file already exists. Skipping.",
+                source, target.target
+            );
+            Ok(false)
+        }
+    }
+}
+
 // == UPDATE ==
 
 /// Returns true if the symlink wasn't skipped
@@ -396,7 +812,10 @@ pub fn update_symlink(
     source: &Path,
     target: &SymbolicTarget,
     fs: &mut dyn Filesystem,
+    backups: &mut Backups,
     force: bool,
+    interactive: bool,
+    diff_context_lines: usize,
 ) -> Result<bool> {
     debug!("Updating symlink {:?} -> {:?}...", source, target.target);
 
@@ -422,12 +841,20 @@ pub fn update_symlink(
                 "Updating symlink {:?} -> {:?} but {}. Forcing.",
                 source, target.target, comparison
             );
-            fs.remove_file(&target.target)
-                .context("remove symlink target while forcing")?;
-            fs.make_symlink(&target.target, source, &target.owner)
+            backups
+                .backup(fs, &target.target)
+                .context("back up symlink target while forcing")?;
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
                 .context("create target symlink")?;
             Ok(true)
         }
+        SymlinkComparison::TargetNotSymlink if interactive => {
+            warn!(
+                "Updating symlink {:?} -> {:?} but {}.",
+                source, target.target, comparison
+            );
+            resolve_symlink_conflict(source, target, fs, backups, diff_context_lines)
+        }
         SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink => {
             error!(
                 "Updating symlink {:?} -> {:?} but {}. Skipping.",
@@ -446,9 +873,10 @@ pub fn update_symlink(
                     .parent()
                     .context("get parent of target file")?,
                 &target.owner,
+                &target.group,
             )
             .context("create parent for target file")?;
-            fs.make_symlink(&target.target, source, &target.owner)
+            fs.make_symlink(&target.target, source, &target.owner, &target.group)
                 .context("create target symlink")?;
             Ok(true)
         }
@@ -462,15 +890,25 @@ pub fn update_template(
     cache: &Path,
     target: &TemplateTarget,
     fs: &mut dyn Filesystem,
+    backups: &mut Backups,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
+    compared: Option<TemplateComparison>,
     force: bool,
+    interactive: bool,
+    merge: bool,
+    conflict_markers: bool,
     diff_context_lines: usize,
 ) -> Result<bool> {
     debug!("Updating template {:?} -> {:?}...", source, target.target);
-    let comparison = fs
-        .compare_template(&target.target, cache)
-        .context("detect templated file's current state")?;
+    let comparison = match compared {
+        Some(comparison) => comparison,
+        None => fs
+            .compare_template(&target.target, cache)
+            .context("detect templated file's current state")?,
+    };
     debug!("Current state: {}", comparison);
 
     match comparison {
@@ -481,12 +919,23 @@ pub fn update_template(
                 target,
                 handlebars,
                 variables,
+                secrets,
+                prerendered,
                 diff_context_lines,
             );
-            fs.set_owner(&target.target, &target.owner)
+            fs.set_owner(&target.target, &target.owner, &target.group)
                 .context("set target file owner")?;
-            perform_template_deploy(source, cache, Some(target), fs, handlebars, variables)
-                .context("perform template cache")?;
+            perform_template_deploy(
+                source,
+                cache,
+                Some(target),
+                fs,
+                handlebars,
+                variables,
+                secrets,
+                prerendered,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
         TemplateComparison::OnlyCacheExists => {
@@ -500,10 +949,20 @@ pub fn update_template(
                     .parent()
                     .context("get parent of target file")?,
                 &target.owner,
+                &target.group,
             )
             .context("create parent for target file")?;
-            perform_template_deploy(source, cache, Some(target), fs, handlebars, variables)
-                .context("perform template cache")?;
+            perform_template_deploy(
+                source,
+                cache,
+                Some(target),
+                fs,
+                handlebars,
+                variables,
+                secrets,
+                prerendered,
+            )
+            .context("perform template cache")?;
             Ok(true)
         }
         TemplateComparison::OnlyTargetExists | TemplateComparison::BothMissing => {
@@ -511,33 +970,170 @@ pub fn update_template(
                 "Updating template {:?} -> {:?} but cache is missing. Cache is CORRUPTED.",
                 source, target.target
             );
-            error!("This is probably a bug. Delete cache.toml and cache/ folder.");
+            error!("Run `dotter cache repair` to fix it.");
             Ok(true)
         }
+        TemplateComparison::Changed if merge => {
+            // At this point, we're not sure if there's a difference between the rendered source
+            // and target, only that the target has been modified in some way.
+            let diff = generate_template_diff(
+                source,
+                target,
+                handlebars,
+                variables,
+                secrets,
+                prerendered,
+                false,
+            )
+            .context("diff source and target")?;
+            if !diff_nonempty(&diff) {
+                perform_template_deploy(
+                    source,
+                    cache,
+                    Some(target),
+                    fs,
+                    handlebars,
+                    variables,
+                    secrets,
+                    prerendered,
+                )
+                .context("perform template cache")?;
+                return Ok(true);
+            }
+
+            // The cache holds the previous render, which both the target and the new render
+            // are based on
+            let rendered = render_template(
+                source,
+                Some(target),
+                fs,
+                handlebars,
+                variables,
+                secrets,
+                prerendered,
+            )
+            .context("render template")?;
+            if fs.read_to_string(cache).ok().as_ref() == Some(&rendered) {
+                // Merging again would give back the target as it is
+                debug!("Render didn't change, keeping the changes in target");
+                return Ok(true);
+            }
+            let merged = merge_template(source, cache, target, fs, &rendered)
+                .context("merge changes in target with new render")?;
+            if merged.conflicts == 0 || conflict_markers {
+                if merged.conflicts == 0 {
+                    info!(
+                        "Updating template {:?} -> {:?} but {}. Merging.",
+                        source, target.target, comparison
+                    );
+                } else {
+                    warn!(
+                        "Updating template {:?} -> {:?} but {}. Merging with {} conflicts marked in target.",
+                        source, target.target, comparison, merged.conflicts
+                    );
+                }
+                perform_template_merge(cache, target, fs, merged.text, rendered)
+                    .context("perform template merge")?;
+                return Ok(true);
+            }
+
+            if force {
+                warn!(
+                    "Updating template {:?} -> {:?} but {} in {} conflicting places. Forcing.",
+                    source, target.target, comparison, merged.conflicts
+                );
+                force_template(
+                    source,
+                    cache,
+                    target,
+                    fs,
+                    backups,
+                    handlebars,
+                    variables,
+                    secrets,
+                    prerendered,
+                    diff_context_lines,
+                )?;
+                Ok(true)
+            } else if interactive {
+                warn!(
+                    "Updating template {:?} -> {:?} but {} in {} conflicting places.",
+                    source, target.target, comparison, merged.conflicts
+                );
+                print_diff(&diff, diff_context_lines);
+                resolve_template_conflict(
+                    source,
+                    cache,
+                    target,
+                    fs,
+                    backups,
+                    handlebars,
+                    variables,
+                    secrets,
+                    prerendered,
+                )
+            } else {
+                error!(
+                    "Updating template {:?} -> {:?} but {} in {} conflicting places. Skipping",
+                    source, target.target, comparison, merged.conflicts
+                );
+                if log_enabled!(log::Level::Info) {
+                    info!("Refusing because of the following changes in target location: ");
+                    print_diff(&diff, diff_context_lines);
+                }
+                Ok(false)
+            }
+        }
         TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile if force => {
             warn!(
                 "Updating template {:?} -> {:?} but {}. Forcing.",
                 source, target.target, comparison
             );
-            difference::print_template_diff(
+            force_template(
                 source,
+                cache,
                 target,
+                fs,
+                backups,
                 handlebars,
                 variables,
+                secrets,
+                prerendered,
                 diff_context_lines,
-            );
-            fs.remove_file(&target.target)
-                .context("remove target while forcing")?;
-            perform_template_deploy(source, cache, Some(target), fs, handlebars, variables)
-                .context("perform template cache")?;
+            )?;
             Ok(true)
         }
         TemplateComparison::Changed => {
             // At this point, we're not sure if there's a difference between the rendered source
             // and target, only that the target has been modified in some way.
-            let diff = generate_template_diff(source, target, handlebars, variables, false)
-                .context("diff source and target")?;
-            if diff_nonempty(&diff) {
+            let diff = generate_template_diff(
+                source,
+                target,
+                handlebars,
+                variables,
+                secrets,
+                prerendered,
+                false,
+            )
+            .context("diff source and target")?;
+            if diff_nonempty(&diff) && interactive {
+                warn!(
+                    "Updating template {:?} -> {:?} but {}.",
+                    source, target.target, comparison
+                );
+                print_diff(&diff, diff_context_lines);
+                resolve_template_conflict(
+                    source,
+                    cache,
+                    target,
+                    fs,
+                    backups,
+                    handlebars,
+                    variables,
+                    secrets,
+                    prerendered,
+                )
+            } else if diff_nonempty(&diff) {
                 error!(
                     "Updating template {:?} -> {:?} but {}. Skipping",
                     source, target.target, comparison
@@ -548,8 +1144,17 @@ pub fn update_template(
                 }
                 Ok(false)
             } else {
-                perform_template_deploy(source, cache, Some(target), fs, handlebars, variables)
-                    .context("perform template cache")?;
+                perform_template_deploy(
+                    source,
+                    cache,
+                    Some(target),
+                    fs,
+                    handlebars,
+                    variables,
+                    secrets,
+                    prerendered,
+                )
+                .context("perform template cache")?;
                 Ok(true)
             }
         }
@@ -564,34 +1169,518 @@ pub fn update_template(
     }
 }
 
-pub(crate) fn perform_template_deploy(
+/// Returns true if the copy was not skipped.
+/// `cached_hash` is the hash of what was copied last time, `hash` is that of the source now.
+pub fn update_copy(
+    source: &Path,
+    target: &CopyTarget,
+    cached_hash: &str,
+    hash: &str,
+    fs: &mut dyn Filesystem,
+    backups: &mut Backups,
+    force: bool,
+) -> Result<bool> {
+    debug!("Updating copy {:?} -> {:?}...", source, target.target);
+    let comparison = fs
+        .compare_copy(&target.target, cached_hash)
+        .context("detect copied file's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        CopyComparison::Identical => {
+            debug!("Performing update");
+            if cached_hash != hash {
+                perform_copy(source, target, fs).context("perform copy")?;
+            } else {
+                fs.set_owner(&target.target, &target.owner, &target.group)
+                    .context("set target file owner")?;
+            }
+            Ok(true)
+        }
+        CopyComparison::TargetMissing => {
+            warn!(
+                "Updating copy {:?} -> {:?} but {}. Creating it anyways.",
+                source, target.target, comparison
+            );
+            fs.create_dir_all(
+                target
+                    .target
+                    .parent()
+                    .context("get parent of target file")?,
+                &target.owner,
+                &target.group,
+            )
+            .context("create parent for target file")?;
+            perform_copy(source, target, fs).context("perform copy")?;
+            Ok(true)
+        }
+        CopyComparison::Changed
+            if fs
+                .compare_copy(&target.target, hash)
+                .context("compare target with source")?
+                == CopyComparison::Identical =>
+        {
+            debug!("Target was changed to the same contents as the source");
+            Ok(true)
+        }
+        CopyComparison::Changed | CopyComparison::TargetNotRegularFile if force => {
+            warn!(
+                "Updating copy {:?} -> {:?} but {}. Forcing.",
+                source, target.target, comparison
+            );
+            backups
+                .backup(fs, &target.target)
+                .context("back up target while forcing")?;
+            perform_copy(source, target, fs).context("perform copy")?;
+            Ok(true)
+        }
+        CopyComparison::Changed | CopyComparison::TargetNotRegularFile => {
+            error!(
+                "Updating copy {:?} -> {:?} but {}. Skipping.",
+                source, target.target, comparison
+            );
+            Ok(false)
+        }
+    }
+}
+
+// == CONFLICTS ==
+
+/// A way to deal with a target that was changed outside of Dotter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resolution {
+    Overwrite,
+    /// Leaves the target as it is without failing the deploy. It's still managed by Dotter, so
+    /// it comes up again on the next deploy.
+    Keep,
+    Edit,
+    /// Only offered for sources that aren't templates, since a render can't be copied back
+    CopyToSource,
+}
+
+fn ask_resolution(source: &Path, target: &Path, template: bool) -> Resolution {
+    let choice = if template {
+        filesystem::ask_choice(
+            &format!(
+                "What to do with {target:?}? [o]verwrite it, [k]eep it, [e]dit a merge of it into {source:?}: "
+            ),
+            &['o', 'k', 'e'],
+        )
+    } else {
+        filesystem::ask_choice(
+            &format!(
+                "What to do with {target:?}? [o]verwrite it, [k]eep it, [e]dit a merge of it into {source:?}, [c]opy it over {source:?}: "
+            ),
+            &['o', 'k', 'e', 'c'],
+        )
+    };
+    match choice {
+        Some('o') => Resolution::Overwrite,
+        Some('e') => Resolution::Edit,
+        Some('c') => Resolution::CopyToSource,
+        _ => Resolution::Keep,
+    }
+}
+
+/// Lets the user edit the source with the target's differences marked as conflicts
+fn edit_merged_source(source: &Path, target: &Path, fs: &mut dyn Filesystem) -> Result<()> {
+    let source_contents = fs
+        .read_to_string(source)
+        .context("read contents of source")?;
+    let target_contents = fs
+        .read_to_string(target)
+        .context("read contents of target")?;
+
+    // Keep the file name so the editor can pick up on the file type. The file is only readable
+    // by the current user, and removed when it goes out of scope.
+    let mut merge_file = tempfile::Builder::new()
+        .prefix("dotter-merge-")
+        .suffix(&format!(
+            "-{}",
+            source
+                .file_name()
+                .context("get file name of source")?
+                .to_string_lossy()
+        ))
+        .tempfile()
+        .context("create merged file")?;
+    std::io::Write::write_all(
+        merge_file.as_file_mut(),
+        conflict_markers(
+            &source_contents,
+            &target_contents,
+            &source.to_string_lossy(),
+            &target.to_string_lossy(),
+        )
+        .as_bytes(),
+    )
+    .context("write merged file")?;
+    filesystem::open_editor(merge_file.path()).context("edit merged file")?;
+
+    // Editors might have replaced the file instead of writing into it
+    let merged = std::fs::read_to_string(merge_file.path()).context("read merged file")?;
+    if merged
+        .lines()
+        .any(|l| l.starts_with("<<<<<<< ") || l.starts_with(">>>>>>> "))
+    {
+        // Don't throw away the user's edits
+        let (_, merge_file) = merge_file.keep().context("keep merged file")?;
+        anyhow::bail!(
+            "merged file {:?} still contains conflict markers, leaving source as is",
+            merge_file
+        );
+    }
+
+    fs.write(source, merged.into())
+        .context("write merged file into source")?;
+    Ok(())
+}
+
+/// Returns true if the symlink was created
+fn resolve_symlink_conflict(
+    source: &Path,
+    target: &SymbolicTarget,
+    fs: &mut dyn Filesystem,
+    backups: &mut Backups,
+    diff_context_lines: usize,
+) -> Result<bool> {
+    // Both could be directories or binary files, in which case there's nothing to show
+    if let (Ok(source_contents), Ok(target_contents)) =
+        (fs.read_to_string(source), fs.read_to_string(&target.target))
+    {
+        let diff = diff_strings(&source_contents, &target_contents);
+        if diff_nonempty(&diff) {
+            println!("Changes in {:?} compared to {:?}:", target.target, source);
+            print_diff(&diff, diff_context_lines);
+        }
+    }
+
+    match ask_resolution(source, &target.target, false) {
+        Resolution::Overwrite => {}
+        Resolution::Keep => {
+            info!("Keeping {:?}.", target.target);
+            return Ok(true);
+        }
+        Resolution::Edit => {
+            edit_merged_source(source, &target.target, fs).context("merge target into source")?;
+        }
+        Resolution::CopyToSource => {
+            fs.copy_file(&target.target, source, &None, &None)
+                .context("copy target over source")?;
+        }
+    }
+
+    backups
+        .backup(fs, &target.target)
+        .context("back up target after resolving")?;
+    fs.make_symlink(&target.target, source, &target.owner, &target.group)
+        .context("create target symlink")?;
+    Ok(true)
+}
+
+/// Returns true if the template was deployed
+#[allow(clippy::too_many_arguments)]
+fn resolve_template_conflict(
     source: &Path,
     cache: &Path,
-    target: Option<&TemplateTarget>,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    backups: &mut Backups,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
+) -> Result<bool> {
+    let resolution = ask_resolution(source, &target.target, true);
+    deploy_template_resolution(
+        resolution,
+        source,
+        cache,
+        target,
+        fs,
+        backups,
+        handlebars,
+        variables,
+        secrets,
+        prerendered,
+    )
+}
+
+/// Returns true if the template was deployed
+#[allow(clippy::too_many_arguments)]
+fn deploy_template_resolution(
+    resolution: Resolution,
+    source: &Path,
+    cache: &Path,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    backups: &mut Backups,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
+) -> Result<bool> {
+    // The render made ahead of time is of the source from before it was edited
+    let edited = Prerendered::new();
+    let prerendered = match resolution {
+        Resolution::Overwrite => {
+            backups
+                .backup(fs, &target.target)
+                .context("back up target before overwriting")?;
+            prerendered
+        }
+        Resolution::Keep => {
+            info!("Keeping {:?}.", target.target);
+            return Ok(true);
+        }
+        Resolution::CopyToSource => {
+            unreachable!("copying over the source isn't offered for templates")
+        }
+        Resolution::Edit if target.is_encrypted() => {
+            error!(
+                "Can't write {:?} into {:?} because it's encrypted. Skipping.",
+                target.target, source
+            );
+            return Ok(false);
+        }
+        Resolution::Edit => {
+            edit_merged_source(source, &target.target, fs).context("merge target into source")?;
+            &edited
+        }
+    };
+
+    perform_template_deploy(
+        source,
+        cache,
+        Some(target),
+        fs,
+        handlebars,
+        variables,
+        secrets,
+        prerendered,
+    )
+    .context("perform template cache")?;
+    Ok(true)
+}
+
+/// Three-way merges the target and the new render, using the previous render in the cache as
+/// the base
+fn merge_template(
+    source: &Path,
+    cache: &Path,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    rendered: &str,
+) -> Result<Merge> {
+    let base = fs
+        .read_to_string(cache)
+        .context("read previous render from cache")?;
+    let target_contents = fs
+        .read_to_string(&target.target)
+        .context("read template target file")?;
+    Ok(merge::merge(
+        &base,
+        &target_contents,
+        rendered,
+        &target.target.to_string_lossy(),
+        &source.to_string_lossy(),
+    ))
+}
+
+/// Overwrites a changed target with the new render, after backing it up
+#[allow(clippy::too_many_arguments)]
+fn force_template(
+    source: &Path,
+    cache: &Path,
+    target: &TemplateTarget,
     fs: &mut dyn Filesystem,
+    backups: &mut Backups,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
+    diff_context_lines: usize,
 ) -> Result<()> {
-    let file_contents = fs
-        .read_to_string(source)
-        .context("read template source file")?;
+    difference::print_template_diff(
+        source,
+        target,
+        handlebars,
+        variables,
+        secrets,
+        prerendered,
+        diff_context_lines,
+    );
+    backups
+        .backup(fs, &target.target)
+        .context("back up target while forcing")?;
+    perform_template_deploy(
+        source,
+        cache,
+        Some(target),
+        fs,
+        handlebars,
+        variables,
+        secrets,
+        prerendered,
+    )
+    .context("perform template cache")
+}
+
+/// Writes the merged contents to the target, and the new render to the cache so it's the base of
+/// the next merge. The target keeps differing from the cache afterwards, so `status` keeps
+/// reporting it as changed.
+fn perform_template_merge(
+    cache: &Path,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    merged: String,
+    rendered: String,
+) -> Result<()> {
+    // Go through the cache so the target gets the right owner
+    fs.write_private(cache, merged.into())
+        .context("write merged template to cache")?;
+    fs.copy_file(cache, &target.target, &target.owner, &target.group)
+        .context("copy merged template from cache to target")?;
+    fs.write_private(cache, rendered.into())
+        .context("write rendered template to cache")?;
+    Ok(())
+}
+
+/// Renders of templates that were done ahead of time, by source. Failed renders are kept along
+/// with their error, so that they aren't rendered a second time when they're deployed.
+pub type Prerendered = BTreeMap<PathBuf, Result<String, String>>;
+
+/// Comparisons of template targets with their cache that were done ahead of time, by target
+pub type Comparisons = BTreeMap<PathBuf, (PathBuf, TemplateComparison)>;
+
+/// Calls `f` on every item, spread over as many threads as there are cores
+fn in_parallel<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> Option<R> + Sync) -> Vec<R> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = ((items.len() + threads - 1) / threads).max(1);
+    let f = &f;
+    std::thread::scope(|scope| {
+        let workers = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().filter_map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("worker thread panicked"))
+            .collect()
+    })
+}
+
+/// Renders templates in parallel, since helpers like `command_output` can make each render slow.
+/// This means that the commands of different templates can run at the same time. Encrypted
+/// templates are left out, so the decrypt command is never run concurrently, and so are templates
+/// whose source can't be read.
+pub fn prerender_templates(
+    templates: &BTreeMap<PathBuf, TemplateTarget>,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+) -> Prerendered {
+    let sources = templates
+        .iter()
+        .filter(|(_, target)| !target.is_encrypted())
+        .filter_map(|(source, target)| {
+            let contents = fs.read_to_string(source).ok()?;
+            Some((source, target.apply_actions(contents)))
+        })
+        .collect::<Vec<_>>();
+    debug!("Prerendering {} templates...", sources.len());
+    in_parallel(&sources, |(source, contents)| {
+        let rendered = handlebars
+            .render_template(contents, variables)
+            .map_err(|e| e.to_string());
+        Some((source.to_path_buf(), rendered))
+    })
+    .into_iter()
+    .collect()
+}
+
+/// Compares the targets of templates with their cache in parallel. Ones that can't be compared
+/// are left out, so that the error is reported when they're deployed.
+pub fn compare_templates(
+    templates: &BTreeMap<PathBuf, TemplateTarget>,
+    cache_directory: &Path,
+) -> Comparisons {
+    let templates = templates.iter().collect::<Vec<_>>();
+    in_parallel(&templates, |(source, target)| {
+        let cache = cache_directory.join(source);
+        let comparison = filesystem::compare_template_files(&target.target, &cache).ok()?;
+        Some((target.target.clone(), (cache, comparison)))
+    })
+    .into_iter()
+    .collect()
+}
+
+pub(crate) fn render_template(
+    source: &Path,
+    target: Option<&TemplateTarget>,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
+) -> Result<String> {
+    match prerendered.get(source) {
+        Some(Ok(rendered)) => return Ok(rendered.clone()),
+        // Rendering it again would run helpers like `command_output` a second time
+        Some(Err(error)) => return Err(anyhow::anyhow!(error.clone())).context("render template"),
+        None => {}
+    }
+
+    let file_contents = match target {
+        Some(t) if t.is_encrypted() => secrets.decrypt_file(source)?,
+        _ => fs
+            .read_to_string(source)
+            .context("read template source file")?,
+    };
     let file_contents = match target {
         Some(t) => t.apply_actions(file_contents),
         None => file_contents,
     };
-    let rendered = handlebars
+    handlebars
         .render_template(&file_contents, variables)
-        .context("render template")?;
+        .context("render template")
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn perform_template_deploy(
+    source: &Path,
+    cache: &Path,
+    target: Option<&TemplateTarget>,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
+) -> Result<()> {
+    let rendered = render_template(
+        source,
+        target,
+        fs,
+        handlebars,
+        variables,
+        secrets,
+        prerendered,
+    )?;
 
     // Cache
-    fs.create_dir_all(cache.parent().context("get parent of cache file")?, &None)
-        .context("create parent for cache file")?;
-    fs.write(cache, rendered)
+    fs.create_dir_all(
+        cache.parent().context("get parent of cache file")?,
+        &None,
+        &None,
+    )
+    .context("create parent for cache file")?;
+    // Renders can contain decrypted secrets
+    fs.write_private(cache, rendered.into())
         .context("write rendered template to cache")?;
 
     // Target
     if let Some(target) = target {
-        fs.copy_file(cache, &target.target, &target.owner)
+        fs.copy_file(cache, &target.target, &target.owner, &target.group)
             .context("copy template from cache to target")?;
         fs.copy_permissions(source, &target.target, &target.owner)
             .context("copy permissions from source to target")?;
@@ -599,3 +1688,172 @@ pub(crate) fn perform_template_deploy(
 
     Ok(())
 }
+
+fn perform_copy(source: &Path, target: &CopyTarget, fs: &mut dyn Filesystem) -> Result<()> {
+    fs.copy_file(source, &target.target, &target.owner, &target.group)
+        .context("copy source to target")?;
+    fs.copy_permissions(source, &target.target, &target.owner)
+        .context("copy permissions from source to target")?;
+    Ok(())
+}
+
+/// Sets the mode of the target, and of the directory containing it along with the other
+/// directories that were created for it
+fn set_modes(
+    fs: &mut dyn Filesystem,
+    target: &Path,
+    mode: Option<FileMode>,
+    dir_mode: Option<FileMode>,
+    created_directories: &[PathBuf],
+) -> Result<()> {
+    if let Some(dir_mode) = dir_mode {
+        let parent = target.parent().context("get parent of target file")?;
+        for directory in created_directories.iter().filter(|d| *d != parent) {
+            fs.set_mode(directory, dir_mode)
+                .with_context(|| format!("set mode of created directory {directory:?}"))?;
+        }
+        fs.set_mode(parent, dir_mode)
+            .context("set mode of target's directory")?;
+    }
+    if let Some(mode) = mode {
+        fs.set_mode(target, mode).context("set mode of target")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prerender_keeps_failures() {
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut templates = BTreeMap::new();
+        for i in 0..20 {
+            templates.insert(
+                PathBuf::from(format!("{i}.txt")),
+                TemplateTarget::from(format!("out_{i}")),
+            );
+        }
+        templates.insert("broken.txt".into(), TemplateTarget::from("out_broken"));
+        templates.insert("missing.txt".into(), TemplateTarget::from("out_missing"));
+        fs.expect_read_to_string()
+            .times(22)
+            .returning(|source| match source.to_str().unwrap() {
+                "broken.txt" => Ok("{{missing}}".into()),
+                "missing.txt" => Err(anyhow::anyhow!("not found")),
+                source => Ok(format!("{} {{{{name}}}}", source.trim_end_matches(".txt"))),
+            });
+
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+        let variables: Variables = toml::from_str("name = 'dotter'").unwrap();
+        let prerendered = prerender_templates(&templates, &mut fs, &handlebars, &variables);
+
+        assert_eq!(prerendered.len(), 21);
+        assert!(prerendered[Path::new("broken.txt")].is_err());
+        assert!(!prerendered.contains_key(Path::new("missing.txt")));
+        assert_eq!(prerendered[Path::new("7.txt")].as_deref(), Ok("7 dotter"));
+
+        // The failure is reported without rendering again
+        let error = render_template(
+            Path::new("broken.txt"),
+            None,
+            &mut fs,
+            &handlebars,
+            &variables,
+            &Secrets::default(),
+            &prerendered,
+        )
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("missing"), "{error:#}");
+    }
+    #[cfg(unix)]
+    #[test]
+    fn edited_source_is_rendered_again() {
+        use mockall::predicate::*;
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for the user fixing up the merge in their editor
+        let dir = tempfile::tempdir().unwrap();
+        let editor = dir.path().join("editor");
+        std::fs::write(&editor, "#!/bin/sh\necho edited > \"$1\"\n").unwrap();
+        std::fs::set_permissions(&editor, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("VISUAL", &editor);
+
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+        let path_eq = |expected: &'static str| move |actual: &Path| actual == Path::new(expected);
+
+        // Merging the target into the source
+        fs.expect_read_to_string()
+            .with(function(path_eq("a_in")))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("source".into()));
+        fs.expect_read_to_string()
+            .with(function(path_eq("a_out")))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("changed target".into()));
+        fs.expect_write()
+            .with(function(path_eq("a_in")), eq(b"edited\n".to_vec()))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+
+        // Deploying the render of the edited source rather than the one made ahead of time
+        fs.expect_read_to_string()
+            .with(function(path_eq("a_in")))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("edited\n".into()));
+        fs.expect_create_dir_all()
+            .with(function(path_eq("cache")), eq(None), eq(None))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_write_private()
+            .with(function(path_eq("cache/a_in")), eq(b"edited\n".to_vec()))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_copy_file()
+            .with(
+                function(path_eq("cache/a_in")),
+                function(path_eq("a_out")),
+                eq(None),
+                eq(None),
+            )
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Ok(()));
+        fs.expect_copy_permissions()
+            .with(
+                function(path_eq("a_in")),
+                function(path_eq("a_out")),
+                eq(None),
+            )
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+
+        let prerendered = maplit::btreemap! {
+            PathBuf::from("a_in") => Ok("source".to_string()),
+        };
+        let deployed = deploy_template_resolution(
+            Resolution::Edit,
+            Path::new("a_in"),
+            Path::new("cache/a_in"),
+            &TemplateTarget::from("a_out"),
+            &mut fs,
+            &mut Backups::new(Path::new("backups")),
+            &Handlebars::new(),
+            &Variables::new(),
+            &Secrets::default(),
+            &prerendered,
+        )
+        .unwrap();
+        assert!(deployed);
+    }
+}
@@ -0,0 +1,511 @@
+use anyhow::{Context, Result};
+
+use std::path::{Path, PathBuf};
+
+use crate::actions::{self, Prerendered};
+use crate::args::Options;
+use crate::cache;
+use crate::config::{self, Cache, FileTarget, Files, SymbolicTarget, TemplateTarget};
+use crate::filesystem::{self, DryRunFilesystem, Filesystem, RealFilesystem};
+use crate::handlebars_helpers::create_new_handlebars;
+use crate::plan::Rendering;
+use crate::secrets;
+
+/// Moves `target` into the repository, registers it in `package` and deploys it in its place
+pub fn adopt(
+    opt: &Options,
+    target: &Path,
+    package: &str,
+    template: bool,
+    source: Option<&Path>,
+) -> Result<()> {
+    let target = if target.is_absolute() {
+        target.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .context("get current directory")?
+            .join(target)
+    };
+    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, None)
+        .context("get a configuration")?;
+    match config.packages.get(package) {
+        Some(true) => {}
+        Some(false) => anyhow::bail!(
+            "package {:?} isn't selected in the local config, so the file wouldn't be deployed",
+            package
+        ),
+        None => anyhow::bail!("package {:?} doesn't exist", package),
+    }
+
+    let mut cache: Cache = cache::load(&opt.cache_file)?.unwrap_or_default();
+    ensure_unmanaged(&cache, &config.files, &target)?;
+
+    let source = match source {
+        Some(source) => source.to_path_buf(),
+        None => default_source(&target)?,
+    };
+    if source.symlink_metadata().is_ok() {
+        anyhow::bail!("{:?} already exists in the repository", source);
+    }
+    if config.files.contains_key(&source) {
+        anyhow::bail!("{:?} is already configured", source);
+    }
+
+    let metadata = target
+        .symlink_metadata()
+        .with_context(|| format!("get metadata of {target:?}"))?;
+    if metadata.is_symlink() {
+        anyhow::bail!(
+            "{:?} is a symbolic link, adopt the file it points to",
+            target
+        );
+    }
+    if template && metadata.is_dir() {
+        anyhow::bail!("{:?} is a directory, which can't be a template", target);
+    }
+
+    let secrets = secrets::decrypt_variables(&mut config).context("decrypt variables")?;
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    if template {
+        // The rendered template has to be identical to the original file, otherwise the next
+        // deploy would change it
+        let contents = std::fs::read_to_string(&target).context("read contents of target")?;
+        let rendered = handlebars
+            .render_template(&contents, &config.variables)
+            .context("render contents of target as a template")?;
+        if rendered != contents {
+            anyhow::bail!(
+                "{:?} changes when rendered as a template, escape its handlebars syntax or adopt it as a symlink",
+                target
+            );
+        }
+    }
+
+    // The config is edited before anything is moved, so a config that can't take the file
+    // doesn't leave it half adopted
+    let configured_target = with_tilde(&target);
+    let file_target = if template {
+        FileTarget::ComplexTemplate(configured_target.into())
+    } else {
+        FileTarget::Symbolic(SymbolicTarget {
+            // Otherwise the directory would be expanded into its files on deploy
+            recurse: metadata.is_dir().then_some(false),
+            ..configured_target.into()
+        })
+    };
+    let local = config::load_local_config(&opt.local_config).context("load local config")?;
+    let (config_path, document) = config::add_file(
+        &opt.global_config,
+        &local.includes,
+        package,
+        source.clone(),
+        file_target,
+    )
+    .context("add file to config")?;
+
+    let (mut real_fs, mut dry_run_fs);
+    let fs: &mut dyn Filesystem = if !opt.dry_run {
+        real_fs = RealFilesystem::new(opt.noconfirm);
+        &mut real_fs
+    } else {
+        dry_run_fs = DryRunFilesystem::new();
+        &mut dry_run_fs
+    };
+
+    let rendering = Rendering {
+        handlebars: &handlebars,
+        variables: &config.variables,
+        secrets: &secrets,
+        prerendered: &Prerendered::new(),
+        #[cfg(feature = "scripting")]
+        helpers: &config.helpers,
+    };
+    move_and_deploy(
+        fs,
+        &mut cache,
+        &target,
+        &source,
+        template,
+        &rendering,
+        opt,
+        || {
+            if opt.dry_run {
+                return Ok(());
+            }
+            filesystem::save_document(&config_path, &document)
+                .with_context(|| format!("save config {config_path:?}"))
+        },
+    )?;
+    if !opt.dry_run {
+        cache::save(&opt.cache_file, &cache).context("save cache")?;
+    }
+    info!(
+        "Adopted {:?} into package {:?} as {:?}",
+        target, package, source
+    );
+
+    Ok(())
+}
+
+/// Fails if `target` is deployed or configured already
+fn ensure_unmanaged(cache: &Cache, files: &Files, target: &Path) -> Result<()> {
+    if cache.symlinks.values().any(|t| t == target)
+        || cache.templates.values().any(|t| t == target)
+        || cache.copies.values().any(|c| c.target == target)
+        || files.values().any(|t| t.path() == target)
+    {
+        anyhow::bail!("{:?} is already managed by Dotter", target);
+    }
+    Ok(())
+}
+
+/// Moves `target` to `source` and deploys it back in its place, adding it to the cache.
+/// `save_config` is called once it's deployed. If deploying or `save_config` fails, the file is
+/// moved back to where it was.
+#[allow(clippy::too_many_arguments)]
+fn move_and_deploy(
+    fs: &mut dyn Filesystem,
+    cache: &mut Cache,
+    target: &Path,
+    source: &Path,
+    template: bool,
+    rendering: &Rendering<'_>,
+    opt: &Options,
+    save_config: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    info!("Moving {:?} to {:?}", target, source);
+    if let Some(parent) = source.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs.create_dir_all(parent, &None, &None)
+            .context("create parent for source file")?;
+    }
+    fs.move_file(target, source)
+        .context("move target into repository")?;
+
+    let deployed = if template {
+        actions::perform_template_deploy(
+            source,
+            &opt.cache_directory.join(source),
+            Some(&TemplateTarget::from(target)),
+            fs,
+            rendering.handlebars,
+            rendering.variables,
+            rendering.secrets,
+            rendering.prerendered,
+        )
+    } else {
+        fs.make_symlink(target, source, &None, &None)
+            .context("create target symlink")
+    };
+    if let Err(e) = deployed {
+        fs.move_file(source, target)
+            .context("move file back after failing to deploy it")?;
+        return Err(e.context("deploy adopted file"));
+    }
+
+    if let Err(e) = save_config() {
+        fs.remove_file(target)
+            .context("remove deployed file after failing to save config")?;
+        if template {
+            fs.remove_file(&opt.cache_directory.join(source))
+                .context("remove cache of template after failing to save config")?;
+        }
+        fs.move_file(source, target)
+            .context("move file back after failing to save config")?;
+        return Err(e);
+    }
+
+    if template {
+        cache.templates.insert(source.into(), target.into());
+    } else {
+        cache.symlinks.insert(source.into(), target.into());
+    }
+    Ok(())
+}
+
+/// The file's name, without the leading dot that hides it
+fn default_source(target: &Path) -> Result<PathBuf> {
+    let name = target
+        .file_name()
+        .context("get file name of target")?
+        .to_string_lossy();
+    let name = name.strip_prefix('.').unwrap_or(&name);
+    if name.is_empty() {
+        anyhow::bail!(
+            "can't name source after {:?}, specify it with --source",
+            target
+        );
+    }
+    Ok(name.into())
+}
+
+/// Writes paths inside the home directory relative to `~`, so the config works for other users
+fn with_tilde(path: &Path) -> PathBuf {
+    let home = PathBuf::from(shellexpand::tilde("~").as_ref());
+    match path.strip_prefix(&home) {
+        Ok(relative) if home.is_absolute() => Path::new("~").join(relative),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::CachedCopy;
+    use crate::filesystem::MockFilesystem;
+    use crate::secrets::Secrets;
+
+    use super::*;
+
+    use mockall::predicate::*;
+
+    fn path_eq(expected: &str) -> impl Fn(&Path) -> bool {
+        let expected = PathBuf::from(expected);
+        move |actual| actual == expected
+    }
+
+    fn adopt_into(
+        fs: &mut MockFilesystem,
+        cache: &mut Cache,
+        template: bool,
+        save_config: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        move_and_deploy(
+            fs,
+            cache,
+            Path::new("home/.bashrc"),
+            Path::new("shell/bashrc"),
+            template,
+            &Rendering {
+                handlebars: &handlebars::Handlebars::new(),
+                variables: &config::Variables::new(),
+                secrets: &Secrets::default(),
+                prerendered: &Prerendered::new(),
+                #[cfg(feature = "scripting")]
+                helpers: &config::Helpers::new(),
+            },
+            &Options {
+                cache_directory: "cache".into(),
+                ..Options::default()
+            },
+            save_config,
+        )
+    }
+
+    fn expect_move(fs: &mut MockFilesystem, seq: &mut mockall::Sequence) {
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("shell")), eq(None), eq(None))
+            .in_sequence(seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_move_file()
+            .times(1)
+            .with(
+                function(path_eq("home/.bashrc")),
+                function(path_eq("shell/bashrc")),
+            )
+            .in_sequence(seq)
+            .returning(|_, _| Ok(()));
+    }
+
+    fn expect_move_back(fs: &mut MockFilesystem, seq: &mut mockall::Sequence) {
+        fs.expect_move_file()
+            .times(1)
+            .with(
+                function(path_eq("shell/bashrc")),
+                function(path_eq("home/.bashrc")),
+            )
+            .in_sequence(seq)
+            .returning(|_, _| Ok(()));
+    }
+
+    #[test]
+    fn refuse_managed_files() {
+        let target = Path::new("/home/user/.bashrc");
+        let files = Files::new();
+        ensure_unmanaged(&Cache::default(), &files, target).unwrap();
+
+        let cache = Cache {
+            copies: maplit::btreemap! {
+                PathBuf::from("bashrc") => CachedCopy {
+                    target: target.into(),
+                    hash: "hash".into(),
+                },
+            },
+            ..Cache::default()
+        };
+        ensure_unmanaged(&cache, &files, target).unwrap_err();
+
+        let files = maplit::btreemap! {
+            PathBuf::from("bashrc") => FileTarget::Automatic(target.into()),
+        };
+        ensure_unmanaged(&Cache::default(), &files, target).unwrap_err();
+    }
+
+    #[test]
+    fn adopt_as_symlink() {
+        let mut fs = MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+        expect_move(&mut fs, &mut seq);
+        fs.expect_make_symlink()
+            .times(1)
+            .with(
+                function(path_eq("home/.bashrc")),
+                function(path_eq("shell/bashrc")),
+                eq(None),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Ok(()));
+
+        let mut cache = Cache::default();
+        let mut saved = false;
+        adopt_into(&mut fs, &mut cache, false, || {
+            saved = true;
+            Ok(())
+        })
+        .unwrap();
+
+        assert!(saved);
+        assert_eq!(
+            cache.symlinks,
+            maplit::btreemap! { PathBuf::from("shell/bashrc") => PathBuf::from("home/.bashrc") }
+        );
+        assert!(cache.templates.is_empty());
+    }
+
+    #[test]
+    fn adopt_as_template() {
+        let mut fs = MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+        expect_move(&mut fs, &mut seq);
+        fs.expect_read_to_string()
+            .times(1)
+            .with(function(path_eq("shell/bashrc")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("contents".into()));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("cache/shell")), eq(None), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_write_private()
+            .times(1)
+            .with(
+                function(path_eq("cache/shell/bashrc")),
+                eq(b"contents".to_vec()),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_copy_file()
+            .times(1)
+            .with(
+                function(path_eq("cache/shell/bashrc")),
+                function(path_eq("home/.bashrc")),
+                eq(None),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Ok(()));
+        fs.expect_copy_permissions()
+            .times(1)
+            .with(
+                function(path_eq("shell/bashrc")),
+                function(path_eq("home/.bashrc")),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+
+        let mut cache = Cache::default();
+        adopt_into(&mut fs, &mut cache, true, || Ok(())).unwrap();
+
+        assert_eq!(
+            cache.templates,
+            maplit::btreemap! { PathBuf::from("shell/bashrc") => PathBuf::from("home/.bashrc") }
+        );
+        assert!(cache.symlinks.is_empty());
+    }
+
+    #[test]
+    fn move_back_when_deploy_fails() {
+        let mut fs = MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+        expect_move(&mut fs, &mut seq);
+        fs.expect_make_symlink()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Err(anyhow::anyhow!("permission denied")));
+        expect_move_back(&mut fs, &mut seq);
+
+        let mut cache = Cache::default();
+        let mut saved = false;
+        adopt_into(&mut fs, &mut cache, false, || {
+            saved = true;
+            Ok(())
+        })
+        .unwrap_err();
+
+        assert!(!saved);
+        assert!(cache.symlinks.is_empty());
+    }
+
+    #[test]
+    fn move_back_when_saving_config_fails() {
+        let mut fs = MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+        expect_move(&mut fs, &mut seq);
+        fs.expect_read_to_string()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("contents".into()));
+        fs.expect_create_dir_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_write_private()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_copy_file()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Ok(()));
+        fs.expect_copy_permissions()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        // The deployed template and its cache are removed before moving the file back
+        fs.expect_remove_file()
+            .times(1)
+            .with(function(path_eq("home/.bashrc")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        fs.expect_remove_file()
+            .times(1)
+            .with(function(path_eq("cache/shell/bashrc")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        expect_move_back(&mut fs, &mut seq);
+
+        let mut cache = Cache::default();
+        let error = adopt_into(&mut fs, &mut cache, true, || {
+            Err(anyhow::anyhow!("read-only file system"))
+        })
+        .unwrap_err();
+
+        assert!(format!("{error:#}").contains("read-only"), "{error:#}");
+        assert!(cache.templates.is_empty());
+    }
+
+    #[test]
+    fn source_without_leading_dot() {
+        assert_eq!(
+            default_source(Path::new("/home/user/.bashrc")).unwrap(),
+            PathBuf::from("bashrc")
+        );
+        assert_eq!(
+            default_source(Path::new("/etc/hosts")).unwrap(),
+            PathBuf::from("hosts")
+        );
+        default_source(Path::new("/")).unwrap_err();
+    }
+}
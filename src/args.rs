@@ -1,13 +1,16 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
 
 /// A small dotfile manager.
 #[derive(Debug, Parser, Default, Clone)]
 #[clap(author, version, about, long_about = None)]
 pub struct Options {
-    /// Location of the global configuration
+    /// Location of the global configuration.
+    ///
+    /// TOML, YAML and JSON are supported, picked by the extension. If the file doesn't exist,
+    /// a file with the same name and another one of these extensions is used instead.
     #[clap(
         short,
         long,
@@ -17,7 +20,9 @@ pub struct Options {
     )]
     pub global_config: PathBuf,
 
-    /// Location of the local configuration
+    /// Location of the local configuration.
+    ///
+    /// Can be in any format the global configuration can.
     #[clap(
         short,
         long,
@@ -35,6 +40,16 @@ pub struct Options {
     #[clap(long, value_parser, default_value = ".dotter/cache")]
     pub cache_directory: PathBuf,
 
+    /// Directory that files overwritten or deleted by --force are moved into.
+    #[clap(long, value_parser, default_value = ".dotter/backups")]
+    pub backup_directory: PathBuf,
+
+    /// Directory that a deploy records the previous state of every file it changes into, so it
+    /// can be rolled back. It holds copies of targets, including decrypted ones, so keep it out
+    /// of version control like the cache.
+    #[clap(long, value_parser, default_value = ".dotter/journal")]
+    pub journal_directory: PathBuf,
+
     /// Location of optional pre-deploy hook
     #[clap(long, value_parser, default_value = ".dotter/pre_deploy.sh")]
     pub pre_deploy: PathBuf,
@@ -70,7 +85,26 @@ pub struct Options {
     #[clap(short, long, value_parser, global = true)]
     pub force: bool,
 
-    /// Assume "yes" instead of prompting when removing empty directories
+    /// Interactive - instead of skipping, show the changes in target files whose content is
+    /// unexpected and ask whether to overwrite them, keep them, or bring their changes into the
+    /// source. Ignored during --dry-run.
+    #[clap(short, long, value_parser, global = true)]
+    pub interactive: bool,
+
+    /// When a template's target was changed since it was deployed, merge those changes into the
+    /// new render instead of skipping the target. Changes that overlap with changes in the render
+    /// still skip it, or overwrite it with --force. Merged targets keep differing from their
+    /// render, so `status` reports them as changed.
+    #[clap(long, value_parser, global = true)]
+    pub merge: bool,
+
+    /// With --merge, when changes in a template's target overlap with changes in its new render,
+    /// write both versions into the target surrounded by conflict markers instead of skipping it.
+    #[clap(long, value_parser, global = true)]
+    pub conflict_markers: bool,
+
+    /// Assume "yes" instead of prompting when removing empty directories or applying pulled
+    /// changes
     #[clap(short = 'y', long = "noconfirm", global = true)]
     pub noconfirm: bool,
 
@@ -79,6 +113,22 @@ pub struct Options {
     #[clap(short, long, value_parser, global = true)]
     pub patch: bool,
 
+    /// Render every template during a deploy, even the ones whose source and variables didn't
+    /// change since the last deploy
+    #[clap(long, global = true)]
+    pub full: bool,
+
+    /// Stop a deploy at the first file that fails or is skipped, and roll back everything it
+    /// changed so far
+    #[clap(long, global = true)]
+    pub atomic: bool,
+
+    /// Format of the results of deploy and undeploy. `json` prints one record per line for
+    /// every action followed by a summary, and sends all logs to stderr. Turns off --interactive,
+    /// since its diffs would end up between the records.
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    pub output: OutputFormat,
+
     /// Amount of lines that are printed before and after a diff hunk.
     #[clap(long, value_parser, default_value = "3")]
     pub diff_context_lines: usize,
@@ -87,9 +137,20 @@ pub struct Options {
     pub action: Option<Action>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum OutputFormat {
+    /// Human-readable logs
+    #[default]
+    Text,
+    /// JSON lines on stdout
+    Json,
+}
+
 #[derive(Debug, Clone, Subcommand, Default)]
 pub enum Action {
     /// Deploy the files to their respective targets. This is the default subcommand.
+    /// Templates are rendered in parallel, so helpers like `command_output` can run at the same
+    /// time for different templates.
     #[default]
     Deploy,
 
@@ -97,8 +158,95 @@ pub enum Action {
     /// Note that this operates on all files that are currently in cache.
     Undeploy,
 
+    /// Show the state of every deployed and configured file without changing any files.
+    /// Exits with an error code if a target was changed, is missing, or isn't deployed yet.
+    /// Evaluates the `if` conditions of packages and files like a deploy does, so helpers such as
+    /// `command_success` in them still run commands.
+    Status,
+
+    /// List the backups of files that were overwritten or deleted by --force, or restore the
+    /// files from one of them. Existing files are only replaced when used with --force.
+    Restore {
+        /// Backup to restore from. Lists all backups if omitted.
+        backup: Option<String>,
+
+        /// Only restore these files, by their original location
+        files: Vec<PathBuf>,
+    },
+
+    /// Undo the last deploy if it failed or was interrupted, restoring every file it changed and
+    /// the cache to how they were before it.
+    Rollback,
+
+    /// Move an existing file into the repository, add it to a package in global.toml, and deploy
+    /// it in its original location.
+    Adopt {
+        /// File to adopt
+        target: PathBuf,
+
+        /// Package to add the file to. Has to be selected in the local config.
+        #[clap(long, short = 'P')]
+        package: String,
+
+        /// Add the file as a template instead of a symlink
+        #[clap(long)]
+        template: bool,
+
+        /// Where to put the file in the repository. Defaults to its name without a leading dot.
+        #[clap(long)]
+        source: Option<PathBuf>,
+    },
+
+    /// Bring changes made in the targets of templates back into their sources. Changes that only
+    /// touch literal lines are applied after confirmation, the rest are shown for review.
+    /// Exits with an error code if some changes have to be ported by hand.
+    Pull {
+        /// Only pull these templates, by their source or target
+        files: Vec<PathBuf>,
+    },
+
+    /// Show where a file comes from: which package, include, local config or patch configured it,
+    /// whether it was generated by expanding a directory, its expanded target, and whether its
+    /// condition passed. For templates, also shows where their variables come from.
+    Explain {
+        /// Source or target of the file
+        path: PathBuf,
+    },
+
+    /// Work out everything a deploy would do without changing anything, including the state of
+    /// each target and hashes of what would be written to it, and save it as JSON for review.
+    Plan {
+        /// File to write the plan to. Prints it if omitted.
+        #[clap(long = "out", short = 'o')]
+        out: Option<PathBuf>,
+    },
+
+    /// Deploy, but only if a plan made by `dotter plan` is still exactly what the deploy would
+    /// do. The plan is checked against the very renders that get deployed, after the pre-deploy
+    /// hook ran. Fails without changing any files if a target, source or the configuration
+    /// changed since. Templates that are encrypted or use encrypted variables have no hash in the
+    /// plan, so changes to what they render to aren't caught.
+    Apply {
+        /// Plan file made by `dotter plan`
+        plan: PathBuf,
+    },
+
+    /// Manage the cache of deployed files.
+    Cache {
+        #[clap(subcommand)]
+        action: CacheAction,
+    },
+
+    /// List, enable, disable or inspect packages.
+    Packages {
+        #[clap(subcommand)]
+        action: PackagesAction,
+    },
+
     /// Initialize global.toml with a single package containing all the files in the current
     /// directory pointing to a dummy value and a local.toml that selects that package.
+    /// With --force, existing configuration files aren't replaced: the files are added to them,
+    /// and everything that's already configured, including comments, is kept.
     Init,
 
     /// Run continuously, watching the repository for changes and deploying as soon as they
@@ -118,6 +266,40 @@ pub enum Action {
     },
 }
 
+#[derive(Debug, Clone, Subcommand)]
+pub enum CacheAction {
+    /// Make the cache file, the cache directory and the deployed files agree again: rebuild
+    /// missing template caches when the target matches the current render, forget files that are
+    /// gone, add configured files that are already deployed, and delete unused cache files.
+    /// Exits with an error code if a template had to be forgotten while its target still exists.
+    Repair,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum PackagesAction {
+    /// List all packages and whether they're enabled, either in the local config or as a
+    /// dependency of an enabled package.
+    List,
+
+    /// Select a package in the local config.
+    Enable {
+        /// Name of the package
+        name: String,
+    },
+
+    /// Remove a package from the local config.
+    Disable {
+        /// Name of the package
+        name: String,
+    },
+
+    /// Print the files, variables and dependency tree of a package.
+    Show {
+        /// Name of the package
+        name: String,
+    },
+}
+
 pub fn get_options() -> Options {
     let mut opt = Options::parse();
     if opt.dry_run {
@@ -127,5 +309,15 @@ pub fn get_options() -> Options {
     if opt.patch {
         opt.noconfirm = true;
     }
+    if opt.dry_run || opt.patch {
+        // Nothing to resolve during a dry run, and stdin is taken by the patch
+        opt.interactive = false;
+    }
+    if opt.output == OutputFormat::Json {
+        // Info-level logs are human-readable progress, which the JSON records replace
+        opt.verbosity = 0;
+        // Conflicts are resolved by looking at diffs printed to stdout
+        opt.interactive = false;
+    }
     opt
 }
@@ -0,0 +1,283 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::args::Options;
+use crate::filesystem::{self, DryRunFilesystem, Filesystem, RealFilesystem};
+
+/// Records every backup that hasn't been restored yet
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub backups: Vec<BackupEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BackupEntry {
+    /// Name of the directory the backup was taken into, which is the UTC time of the run
+    pub id: String,
+    /// Where the file was before it was backed up
+    pub original: PathBuf,
+    /// Where the file is now
+    pub stored: PathBuf,
+}
+
+/// Moves targets out of the way instead of deleting them, so they can be restored later.
+/// All backups taken during a single run share one directory.
+#[derive(Debug)]
+pub struct Backups {
+    directory: PathBuf,
+    id: Option<String>,
+    taken: Vec<BackupEntry>,
+}
+
+impl Backups {
+    pub fn new(directory: &Path) -> Backups {
+        Backups {
+            directory: directory.into(),
+            id: None,
+            taken: Vec::new(),
+        }
+    }
+
+    /// Moves `target` into this run's backup directory
+    pub fn backup(&mut self, fs: &mut dyn Filesystem, target: &Path) -> Result<()> {
+        let id = self.id();
+        let stored = self.directory.join(&id).join(relative_to_root(target));
+        info!("Backing up {:?} to {:?}", target, stored);
+
+        fs.create_dir_all(
+            stored.parent().context("get parent of backup location")?,
+            &None,
+            &None,
+        )
+        .context("create parent for backup location")?;
+        fs.move_file(target, &stored)
+            .context("move target into backup location")?;
+
+        self.taken.push(BackupEntry {
+            id,
+            original: target.into(),
+            stored,
+        });
+        Ok(())
+    }
+
+    /// Adds the backups taken during this run to the manifest
+    pub fn save(self) -> Result<()> {
+        if self.taken.is_empty() {
+            return Ok(());
+        }
+
+        let manifest_file = manifest_file(&self.directory);
+        let mut manifest: Manifest = filesystem::load_file(&manifest_file)
+            .context("load backup manifest")?
+            .unwrap_or_default();
+        manifest.backups.extend(self.taken);
+        filesystem::save_file(&manifest_file, manifest).context("save backup manifest")?;
+
+        warn!("Files that were overwritten were backed up. To list backups, use `dotter restore`.");
+        Ok(())
+    }
+
+    fn id(&mut self) -> String {
+        self.id
+            .get_or_insert_with(|| {
+                let now = time::OffsetDateTime::now_utc();
+                let timestamp = format!(
+                    "{:04}{:02}{:02}-{:02}{:02}{:02}",
+                    now.year(),
+                    u8::from(now.month()),
+                    now.day(),
+                    now.hour(),
+                    now.minute(),
+                    now.second()
+                );
+
+                // Two runs within the same second shouldn't mix their backups
+                let mut id = timestamp.clone();
+                let mut counter = 1;
+                while self.directory.join(&id).exists() {
+                    counter += 1;
+                    id = format!("{timestamp}-{counter}");
+                }
+                id
+            })
+            .clone()
+    }
+}
+
+fn manifest_file(directory: &Path) -> PathBuf {
+    directory.join("manifest.toml")
+}
+
+/// Strips the root and any prefix from a path, so it can be put inside another directory
+fn relative_to_root(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+/// Lists the backups if `id` is `None`, otherwise restores the files from that backup.
+/// Returns true if some files couldn't be restored.
+pub fn restore(opt: &Options, id: Option<&str>, files: &[PathBuf]) -> Result<bool> {
+    let manifest_file = manifest_file(&opt.backup_directory);
+    let mut manifest: Manifest = filesystem::load_file(&manifest_file)
+        .context("load backup manifest")?
+        .unwrap_or_default();
+
+    let id = match id {
+        Some(id) => id,
+        None => {
+            list_backups(&manifest);
+            return Ok(false);
+        }
+    };
+
+    let (selected, mut remaining): (Vec<_>, Vec<_>) = manifest
+        .backups
+        .into_iter()
+        .partition(|entry| entry.id == id && (files.is_empty() || files.contains(&entry.original)));
+    if selected.is_empty() {
+        anyhow::bail!("find backup {:?} containing the requested files", id);
+    }
+
+    let (mut real_fs, mut dry_run_fs);
+    let fs: &mut dyn Filesystem = if !opt.dry_run {
+        real_fs = RealFilesystem::new(opt.noconfirm);
+        &mut real_fs
+    } else {
+        dry_run_fs = DryRunFilesystem::new();
+        &mut dry_run_fs
+    };
+
+    let mut error_occurred = false;
+    for entry in selected {
+        match restore_entry(&entry, fs, opt.force) {
+            Ok(true) => {}
+            Ok(false) => {
+                error_occurred = true;
+                remaining.push(entry);
+            }
+            Err(e) => {
+                crate::display_error(e.context(format!(
+                    "restore {:?} -> {:?}",
+                    entry.stored, entry.original
+                )));
+                error_occurred = true;
+                remaining.push(entry);
+            }
+        }
+    }
+
+    if error_occurred {
+        error!("Some files were not restored. To overwrite existing files, use the --force flag.");
+    }
+
+    if !opt.dry_run {
+        manifest.backups = remaining;
+        filesystem::save_file(&manifest_file, manifest).context("save backup manifest")?;
+    }
+
+    Ok(error_occurred)
+}
+
+fn list_backups(manifest: &Manifest) {
+    if manifest.backups.is_empty() {
+        println!("There are no backups.");
+        return;
+    }
+
+    let mut by_id = BTreeMap::<_, Vec<_>>::new();
+    for entry in &manifest.backups {
+        by_id.entry(&entry.id).or_default().push(entry);
+    }
+    for (id, entries) in by_id {
+        println!("{id}:");
+        for entry in entries {
+            println!("    {:?}", entry.original);
+        }
+    }
+}
+
+/// Returns true if the file was restored
+fn restore_entry(entry: &BackupEntry, fs: &mut dyn Filesystem, force: bool) -> Result<bool> {
+    info!("Restoring {:?} -> {:?}", entry.stored, entry.original);
+
+    if entry.original.symlink_metadata().is_ok() {
+        if !force {
+            error!(
+                "Restoring {:?} but it already exists. Skipping.",
+                entry.original
+            );
+            return Ok(false);
+        }
+        warn!(
+            "Restoring {:?} but it already exists. Forcing.",
+            entry.original
+        );
+        fs.remove_file(&entry.original)
+            .context("remove existing file while forcing")?;
+    }
+
+    fs.create_dir_all(
+        entry
+            .original
+            .parent()
+            .context("get parent of original location")?,
+        &None,
+        &None,
+    )
+    .context("create parent for original location")?;
+    fs.move_file(&entry.stored, &entry.original)
+        .context("move backup into original location")?;
+    fs.delete_parents(&entry.stored, true)
+        .context("delete empty directories in backup location")?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::filesystem::MockFilesystem;
+
+    use mockall::predicate::*;
+
+    #[test]
+    fn backups_share_a_directory() {
+        let mut fs = MockFilesystem::new();
+        fs.expect_create_dir_all()
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_move_file()
+            .with(eq(PathBuf::from("/home/user/.bashrc")), always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+        fs.expect_move_file()
+            .with(eq(PathBuf::from("/etc/hosts")), always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut backups = Backups::new(Path::new("backups"));
+        backups
+            .backup(&mut fs, Path::new("/home/user/.bashrc"))
+            .unwrap();
+        backups.backup(&mut fs, Path::new("/etc/hosts")).unwrap();
+
+        let id = &backups.taken[0].id;
+        assert_eq!(&backups.taken[1].id, id);
+        assert_eq!(
+            backups.taken[0].stored,
+            Path::new("backups").join(id).join("home/user/.bashrc")
+        );
+        assert_eq!(
+            backups.taken[1].stored,
+            Path::new("backups").join(id).join("etc/hosts")
+        );
+    }
+}
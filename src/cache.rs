@@ -0,0 +1,518 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use toml::value::{Table, Value};
+
+use std::path::{Path, PathBuf};
+
+use crate::actions::{self, Prerendered};
+use crate::args::Options;
+use crate::config::{self, Cache, CachedCopy};
+use crate::deploy::{desired_files, DesiredFiles};
+use crate::filesystem::{self, Filesystem};
+use crate::filesystem::{CopyComparison, SymlinkComparison, TemplateComparison};
+use crate::handlebars_helpers::create_new_handlebars;
+use crate::plan::Rendering;
+use crate::secrets;
+
+/// Version of the cache format that's written. Only bumped when older versions of Dotter can't
+/// read the new format anymore, new fields are just ignored by them.
+pub const CACHE_VERSION: i64 = 1;
+
+/// Each migration brings a cache from the version at its index to the next one
+const MIGRATIONS: &[fn(&mut Table)] = &[migrate_unversioned];
+
+/// Caches written before the format was versioned could be missing the tables that were empty
+fn migrate_unversioned(cache: &mut Table) {
+    for table in ["symlinks", "templates"] {
+        cache
+            .entry(table.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+    }
+}
+
+/// Loads the cache, migrating it from older versions of the format
+pub fn load(path: &Path) -> Result<Option<Cache>> {
+    let Some(mut cache) = filesystem::load_file::<Table>(path)? else {
+        return Ok(None);
+    };
+
+    let version = match cache.remove("version") {
+        None => 0,
+        Some(Value::Integer(version)) if version >= 0 => version,
+        Some(version) => anyhow::bail!("invalid cache version {}", version),
+    };
+    if version > CACHE_VERSION {
+        anyhow::bail!(
+            "the cache was written by a newer version of Dotter (cache version {}, this one understands up to {})",
+            version,
+            CACHE_VERSION
+        );
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut cache);
+    }
+    if version < CACHE_VERSION {
+        debug!(
+            "Migrated cache from version {} to {}",
+            version, CACHE_VERSION
+        );
+    }
+
+    Cache::deserialize(Value::Table(cache))
+        .map(Some)
+        .context("parse cache")
+}
+
+/// Saves the cache so only the current user can read it, since it holds hashes of renders
+pub fn save(path: &Path, cache: &Cache) -> Result<()> {
+    let mut value = Value::try_from(cache).context("serialize cache")?;
+    if let Value::Table(table) = &mut value {
+        table.insert("version".into(), CACHE_VERSION.into());
+    }
+    filesystem::save_private_file(path, value)
+}
+
+/// Reconciles the cache file, the cache directory and the targets:
+/// - Rebuilds missing cache files of templates whose target is the same as their current render
+/// - Forgets files whose target and cache are both gone
+/// - Adds configured files that are deployed but missing from the cache
+/// - Deletes files in the cache directory that no template uses
+///
+/// Returns true if some templates had to be forgotten while their target still exists.
+pub fn repair(opt: &Options) -> Result<bool> {
+    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, None)
+        .context("get a configuration")?;
+    let mut cache = if let Some(cache) = load(&opt.cache_file).context("load cache")? {
+        cache
+    } else {
+        warn!("Cache file not found. Rebuilding it from the deployed files.");
+        Cache::default()
+    };
+
+    let secrets = secrets::decrypt_variables(&mut config).context("decrypt variables")?;
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let desired =
+        desired_files(config.files).context("sort files into symlinks, templates and copies")?;
+    let orphans = orphaned_cache_files(opt, &cache).context("find unused cache files")?;
+
+    let (mut real_fs, mut dry_run_fs);
+    let fs: &mut dyn Filesystem = if !opt.dry_run {
+        real_fs = crate::filesystem::RealFilesystem::new(opt.noconfirm);
+        &mut real_fs
+    } else {
+        dry_run_fs = crate::filesystem::DryRunFilesystem::new();
+        &mut dry_run_fs
+    };
+
+    let rendering = Rendering {
+        handlebars: &handlebars,
+        variables: &config.variables,
+        secrets: &secrets,
+        prerendered: &Prerendered::new(),
+        #[cfg(feature = "scripting")]
+        helpers: &config.helpers,
+    };
+    let (repaired, lost) = repair_files(fs, &mut cache, &desired, &rendering, orphans, opt)?;
+
+    if repaired == 0 {
+        info!("Nothing to repair.");
+    } else if !opt.dry_run {
+        save(&opt.cache_file, &cache).context("save cache")?;
+    }
+
+    Ok(lost)
+}
+
+/// Repairs `cache` against the filesystem, removing the `orphans` from the cache directory.
+/// Returns how many things were repaired, and whether some templates had to be forgotten while
+/// their target still exists.
+fn repair_files(
+    fs: &mut dyn Filesystem,
+    cache: &mut Cache,
+    desired: &DesiredFiles,
+    rendering: &Rendering<'_>,
+    orphans: Vec<PathBuf>,
+    opt: &Options,
+) -> Result<(usize, bool)> {
+    // The current render, if it's what the target already contains
+    let deployed_render = |fs: &mut dyn Filesystem, source: &Path, target: &Path| {
+        let template = desired
+            .templates
+            .get(source)
+            .filter(|t| t.target == target)?;
+        let rendered = actions::render_template(
+            source,
+            Some(template),
+            fs,
+            rendering.handlebars,
+            rendering.variables,
+            rendering.secrets,
+            rendering.prerendered,
+        )
+        .ok()?;
+        (fs.read_to_string(target).ok()? == rendered).then_some(rendered)
+    };
+
+    let mut repaired = 0;
+    let mut lost = false;
+
+    for (source, target) in cache.templates.clone() {
+        let cache_file = opt.cache_directory.join(&source);
+        let comparison = fs
+            .compare_template(&target, &cache_file)
+            .with_context(|| format!("compare template {source:?} -> {target:?}"))?;
+        match comparison {
+            TemplateComparison::OnlyTargetExists => {
+                if let Some(rendered) = deployed_render(fs, &source, &target) {
+                    write_cache_file(fs, &cache_file, rendered)
+                        .with_context(|| format!("rebuild cache of template {source:?}"))?;
+                    info!(
+                        "Rebuilt the missing cache of template {:?} -> {:?}.",
+                        source, target
+                    );
+                } else {
+                    cache.templates.remove(&source);
+                    warn!(
+                        "Forgot template {:?} -> {:?} because its cache is missing and the target isn't its current render. Use --force on the next deploy to overwrite the target.",
+                        source, target
+                    );
+                    lost = true;
+                }
+                repaired += 1;
+            }
+            TemplateComparison::BothMissing => {
+                cache.templates.remove(&source);
+                info!(
+                    "Forgot template {:?} -> {:?} because neither its target nor its cache exist.",
+                    source, target
+                );
+                repaired += 1;
+            }
+            _ => {}
+        }
+    }
+
+    for (source, target) in cache.symlinks.clone() {
+        let comparison = fs
+            .compare_symlink(&source, &target)
+            .with_context(|| format!("compare symlink {source:?} -> {target:?}"))?;
+        if comparison == SymlinkComparison::BothMissing {
+            cache.symlinks.remove(&source);
+            info!(
+                "Forgot symlink {:?} -> {:?} because neither its source nor its target exist.",
+                source, target
+            );
+            repaired += 1;
+        }
+    }
+
+    for (source, target) in &desired.symlinks {
+        if cache.symlinks.contains_key(source)
+            || fs.compare_symlink(source, &target.target)? != SymlinkComparison::Identical
+        {
+            continue;
+        }
+        cache.symlinks.insert(source.clone(), target.target.clone());
+        info!(
+            "Added deployed symlink {:?} -> {:?} to the cache.",
+            source, target.target
+        );
+        repaired += 1;
+    }
+
+    for (source, target) in &desired.templates {
+        if cache.templates.contains_key(source) {
+            continue;
+        }
+        if let Some(rendered) = deployed_render(fs, source, &target.target) {
+            write_cache_file(fs, &opt.cache_directory.join(source), rendered)
+                .with_context(|| format!("rebuild cache of template {source:?}"))?;
+            cache
+                .templates
+                .insert(source.clone(), target.target.clone());
+            info!(
+                "Added deployed template {:?} -> {:?} to the cache.",
+                source, target.target
+            );
+            repaired += 1;
+        }
+    }
+
+    for (source, target) in &desired.copies {
+        if cache.copies.contains_key(source) {
+            continue;
+        }
+        let hash = fs
+            .hash_file(source)
+            .with_context(|| format!("hash source {source:?}"))?;
+        if fs.compare_copy(&target.target, &hash)? == CopyComparison::Identical {
+            cache.copies.insert(
+                source.clone(),
+                CachedCopy {
+                    target: target.target.clone(),
+                    hash,
+                },
+            );
+            info!(
+                "Added deployed copy {:?} -> {:?} to the cache.",
+                source, target.target
+            );
+            repaired += 1;
+        }
+    }
+
+    for orphan in orphans {
+        fs.remove_file(&orphan)
+            .with_context(|| format!("remove unused cache file {orphan:?}"))?;
+        info!("Removed unused cache file {:?}.", orphan);
+        repaired += 1;
+    }
+
+    let templates = &cache.templates;
+    cache
+        .template_hashes
+        .retain(|source, _| templates.contains_key(source));
+
+    Ok((repaired, lost))
+}
+
+fn write_cache_file(fs: &mut dyn Filesystem, cache_file: &Path, rendered: String) -> Result<()> {
+    fs.create_dir_all(
+        cache_file.parent().context("get parent of cache file")?,
+        &None,
+        &None,
+    )
+    .context("create parent for cache file")?;
+    // Renders can contain decrypted secrets
+    fs.write_private(cache_file, rendered.into())
+        .context("write rendered template to cache")
+}
+
+/// Files in the cache directory that aren't the cache of a template or a rendered hook. The
+/// cache file, journal and backups are left alone if they were configured to be in there.
+fn orphaned_cache_files(opt: &Options, cache: &Cache) -> Result<Vec<PathBuf>> {
+    let hooks = [
+        &opt.pre_deploy,
+        &opt.post_deploy,
+        &opt.pre_undeploy,
+        &opt.post_undeploy,
+    ];
+    let is_used = |relative: &Path| {
+        cache.templates.contains_key(relative)
+            || hooks
+                .iter()
+                .any(|hook| *hook == relative || hook.with_extension("bat") == relative)
+    };
+
+    let kept = [
+        &opt.cache_file,
+        &opt.journal_directory,
+        &opt.backup_directory,
+    ]
+    .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()));
+    let is_kept = |path: &Path| {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        kept.contains(&path)
+    };
+
+    let mut orphans = Vec::new();
+    let mut directories = vec![opt.cache_directory.clone()];
+    while let Some(directory) = directories.pop() {
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("read directory {directory:?}")),
+        };
+        for entry in entries {
+            let path = entry.context("read directory entry")?.path();
+            if is_kept(&path) {
+                continue;
+            } else if path.is_dir() {
+                directories.push(path);
+            } else if !is_used(path.strip_prefix(&opt.cache_directory)?) {
+                orphans.push(path);
+            }
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::config::{TemplateHashes, TemplateTarget, Variables};
+    use crate::filesystem::MockFilesystem;
+    use crate::secrets::Secrets;
+
+    use super::*;
+
+    use mockall::predicate::*;
+
+    fn path_eq(expected: &str) -> impl Fn(&Path) -> bool {
+        let expected = PathBuf::from(expected);
+        move |actual| actual == expected
+    }
+
+    #[test]
+    fn migrate_unversioned_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.toml");
+        std::fs::write(&path, "[symlinks]\n\"a\" = \"b\"\n").unwrap();
+        let cache = load(&path).unwrap().unwrap();
+        assert_eq!(cache.symlinks[Path::new("a")], Path::new("b"));
+        assert!(cache.templates.is_empty());
+
+        save(&path, &cache).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::write(&path, "version = 99\n").unwrap();
+        let newer = load(&path);
+
+        assert!(saved.starts_with("version = 1\n"), "{saved}");
+        assert!(newer.is_err());
+    }
+    #[test]
+    fn repair_templates() {
+        // State
+        let desired = DesiredFiles {
+            symlinks: BTreeMap::new(),
+            templates: maplit::btreemap! {
+                PathBuf::from("a_in") => TemplateTarget::from("a_out"),
+                PathBuf::from("b_in") => TemplateTarget::from("b_out"),
+            },
+            copies: BTreeMap::new(),
+        };
+        let prerendered = maplit::btreemap! {
+            PathBuf::from("a_in") => Ok("a".to_string()),
+            PathBuf::from("b_in") => Ok("b".to_string()),
+        };
+        let mut cache = Cache {
+            templates: maplit::btreemap! {
+                PathBuf::from("a_in") => PathBuf::from("a_out"),
+                PathBuf::from("b_in") => PathBuf::from("b_out"),
+                PathBuf::from("c_in") => PathBuf::from("c_out"),
+            },
+            template_hashes: maplit::btreemap! {
+                PathBuf::from("c_in") => TemplateHashes {
+                    source: "source".into(),
+                    variables: "variables".into(),
+                    rendered: "rendered".into(),
+                },
+            },
+            ..Cache::default()
+        };
+
+        // Expectation:
+        // a_in's cache is rebuilt, since its target is its current render
+        // b_in is forgotten, since its target was changed
+        // c_in is forgotten, since nothing of it is left
+        // The unused cache file is removed
+        let mut fs = MockFilesystem::new();
+        fs.expect_compare_template()
+            .with(function(path_eq("a_out")), function(path_eq("cache/a_in")))
+            .times(1)
+            .returning(|_, _| Ok(TemplateComparison::OnlyTargetExists));
+        fs.expect_compare_template()
+            .with(function(path_eq("b_out")), function(path_eq("cache/b_in")))
+            .times(1)
+            .returning(|_, _| Ok(TemplateComparison::OnlyTargetExists));
+        fs.expect_compare_template()
+            .with(function(path_eq("c_out")), function(path_eq("cache/c_in")))
+            .times(1)
+            .returning(|_, _| Ok(TemplateComparison::BothMissing));
+        fs.expect_read_to_string()
+            .with(function(path_eq("a_out")))
+            .times(1)
+            .returning(|_| Ok("a".into()));
+        fs.expect_read_to_string()
+            .with(function(path_eq("b_out")))
+            .returning(|_| Ok("changed".into()));
+        fs.expect_create_dir_all()
+            .with(function(path_eq("cache")), eq(None), eq(None))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_write_private()
+            .with(function(path_eq("cache/a_in")), eq(b"a".to_vec()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        fs.expect_remove_file()
+            .with(function(path_eq("cache/old")))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        // Reality
+        let (repaired, lost) = repair_files(
+            &mut fs,
+            &mut cache,
+            &desired,
+            &Rendering {
+                handlebars: &handlebars::Handlebars::new(),
+                variables: &Variables::new(),
+                secrets: &Secrets::default(),
+                prerendered: &prerendered,
+                #[cfg(feature = "scripting")]
+                helpers: &crate::config::Helpers::new(),
+            },
+            vec!["cache/old".into()],
+            &Options {
+                cache_directory: "cache".into(),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(repaired, 4);
+        assert!(lost);
+        assert_eq!(
+            cache.templates,
+            maplit::btreemap! { PathBuf::from("a_in") => PathBuf::from("a_out") }
+        );
+        assert!(cache.template_hashes.is_empty());
+    }
+
+    #[test]
+    fn orphans_leave_hooks_journal_and_backups_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_directory = dir.path().join("cache");
+        for file in [
+            "a_in",
+            "old",
+            "pre_deploy.sh",
+            "journal/journal.toml",
+            "backups/backups.toml",
+            "cache.toml",
+        ] {
+            let path = cache_directory.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let cache = Cache {
+            templates: maplit::btreemap! {
+                PathBuf::from("a_in") => PathBuf::from("a_out"),
+            },
+            ..Cache::default()
+        };
+
+        let orphans = orphaned_cache_files(
+            &Options {
+                cache_directory: cache_directory.clone(),
+                cache_file: cache_directory.join("cache.toml"),
+                journal_directory: cache_directory.join("journal"),
+                backup_directory: cache_directory.join("backups"),
+                pre_deploy: "pre_deploy.sh".into(),
+                ..Options::default()
+            },
+            &cache,
+        )
+        .unwrap();
+
+        assert_eq!(orphans, vec![cache_directory.join("old")]);
+    }
+}
@@ -2,9 +2,10 @@ use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::filesystem;
+use crate::handlebars_helpers;
 
 use core::fmt;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -24,11 +25,64 @@ impl fmt::Display for UnixUser {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum UnixGroup {
+    Gid(i32),
+    Name(String),
+}
+
+impl fmt::Display for UnixGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnixGroup::Gid(gid) => write!(f, "{gid}"),
+            UnixGroup::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Unix permission bits, written in octal like `"0600"`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct FileMode(pub u32);
+
+impl TryFrom<String> for FileMode {
+    type Error = String;
+
+    fn try_from(mode: String) -> Result<Self, Self::Error> {
+        let digits = mode.strip_prefix("0o").unwrap_or(&mode);
+        match u32::from_str_radix(digits, 8) {
+            Ok(bits) if bits <= 0o7777 => Ok(FileMode(bits)),
+            _ => Err(format!(
+                "invalid mode {mode:?}, expected octal like \"0644\""
+            )),
+        }
+    }
+}
+
+impl From<FileMode> for String {
+    fn from(mode: FileMode) -> Self {
+        mode.to_string()
+    }
+}
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04o}", self.0)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(deny_unknown_fields)]
 pub struct SymbolicTarget {
     pub target: PathBuf,
     pub owner: Option<UnixUser>,
+    pub group: Option<UnixGroup>,
+    /// Set on the source in the repository, since a symlink has the mode of the file it points
+    /// at. Without symlinks, it's set on the target that the source is rendered to.
+    pub mode: Option<FileMode>,
+    /// Mode of the directory containing the target
+    pub dir_mode: Option<FileMode>,
     pub recurse: Option<bool>,
     #[serde(rename = "if")]
     pub condition: Option<String>,
@@ -39,8 +93,27 @@ pub struct SymbolicTarget {
 pub struct TemplateTarget {
     pub target: PathBuf,
     pub owner: Option<UnixUser>,
+    pub group: Option<UnixGroup>,
+    pub mode: Option<FileMode>,
+    /// Mode of the directory containing the target
+    pub dir_mode: Option<FileMode>,
     pub append: Option<String>,
     pub prepend: Option<String>,
+    /// The source is decrypted with `decrypt_command` before it's rendered
+    pub encrypted: Option<bool>,
+    #[serde(rename = "if")]
+    pub condition: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(deny_unknown_fields)]
+pub struct CopyTarget {
+    pub target: PathBuf,
+    pub owner: Option<UnixUser>,
+    pub group: Option<UnixGroup>,
+    pub mode: Option<FileMode>,
+    /// Mode of the directory containing the target
+    pub dir_mode: Option<FileMode>,
     #[serde(rename = "if")]
     pub condition: Option<String>,
 }
@@ -52,6 +125,7 @@ pub enum FileTarget {
     Symbolic(SymbolicTarget),
     #[serde(rename = "template")]
     ComplexTemplate(TemplateTarget),
+    Copy(CopyTarget),
 }
 
 // Shims to allow Serde to represent FileTarget::Automatic as untagged while the
@@ -69,6 +143,7 @@ enum FileTargetInnerRepr {
     Symbolic(SymbolicTarget),
     #[serde(rename = "template")]
     ComplexTemplate(TemplateTarget),
+    Copy(CopyTarget),
 }
 
 pub type Files = BTreeMap<PathBuf, FileTarget>;
@@ -81,6 +156,7 @@ pub type Helpers = BTreeMap<String, PathBuf>;
 pub enum DefaultTargetType {
     Symbolic,
     Template,
+    Copy,
     #[default]
     Automatic,
 }
@@ -89,6 +165,9 @@ pub enum DefaultTargetType {
 pub struct Settings {
     #[serde(default)]
     default_target_type: DefaultTargetType,
+    /// Shell command that gets an encrypted value on stdin and prints it decrypted,
+    /// like `age -d -i ~/.config/age/key.txt` or `gpg --decrypt`
+    pub(crate) decrypt_command: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -106,28 +185,78 @@ pub struct Configuration {
     /// are readable.
     pub recurse: bool,
 
-    #[allow(dead_code)]
     pub settings: Settings,
+
+    pub origins: Origins,
+}
+
+/// Human-readable steps that led to each file and variable being configured the way it is,
+/// including the files that were dropped along the way
+#[derive(Debug, Clone, Default)]
+pub struct Origins {
+    pub files: BTreeMap<PathBuf, Vec<String>>,
+    pub variables: BTreeMap<String, Vec<String>>,
+}
+
+impl Origins {
+    pub fn file(&mut self, source: &Path, step: impl Into<String>) {
+        self.files
+            .entry(source.into())
+            .or_default()
+            .push(step.into());
+    }
+
+    pub fn variable(&mut self, name: &str, step: impl Into<String>) {
+        self.variables
+            .entry(name.into())
+            .or_default()
+            .push(step.into());
+    }
+
+    fn package(&mut self, package: &Package, step: &str) {
+        for source in package.files.keys() {
+            self.file(source, step);
+        }
+        for name in package.variables.keys() {
+            self.variable(name, step);
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Package {
     #[serde(default)]
-    depends: Vec<String>,
+    pub(crate) depends: Vec<String>,
+    /// Enabled along with this package, unless they're disabled with `!name` in the local config
     #[serde(default)]
-    files: Files,
+    pub(crate) recommends: Vec<String>,
+    /// Packages that can't be enabled at the same time as this one
     #[serde(default)]
-    variables: Variables,
+    pub(crate) conflicts: Vec<String>,
+    /// The package is only enabled if this evaluates to true. It can use `dotter.os`,
+    /// `dotter.hostname` and the variables of packages that were enabled before it
+    #[serde(rename = "if")]
+    pub(crate) condition: Option<String>,
+    #[serde(default)]
+    pub(crate) files: Files,
+    #[serde(default)]
+    pub(crate) variables: Variables,
+    /// Globs of TOML, JSON or YAML files whose variables are added on top of `variables`
+    #[serde(default)]
+    pub(crate) variables_files: Vec<String>,
+    /// Environment variables that are added as variables of the same name, if they're set
+    #[serde(default)]
+    pub(crate) env: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-struct GlobalConfig {
+pub(crate) struct GlobalConfig {
     #[serde(default)]
     #[cfg(feature = "scripting")]
     helpers: Helpers,
     #[serde(flatten)]
-    packages: BTreeMap<String, Package>,
+    pub(crate) packages: BTreeMap<String, Package>,
     #[serde(default)]
     settings: Settings,
 }
@@ -136,43 +265,32 @@ type IncludedConfig = BTreeMap<String, Package>;
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
-struct LocalConfig {
+pub(crate) struct LocalConfig {
     #[serde(default)]
-    includes: Vec<PathBuf>,
-    packages: Vec<String>,
+    pub(crate) includes: Vec<PathBuf>,
+    pub(crate) packages: Vec<String>,
     #[serde(default)]
     files: Files,
     #[serde(default)]
     variables: Variables,
+    #[serde(default)]
+    variables_files: Vec<String>,
+    #[serde(default)]
+    env: Vec<String>,
 }
 
+/// Environment variables with this prefix are added as variables, without the prefix
+const ENV_VARIABLE_PREFIX: &str = "DOTTER_VAR_";
+
 pub fn load_configuration(
     local_config: &Path,
     global_config: &Path,
     patch: Option<Package>,
 ) -> Result<Configuration> {
-    let global: GlobalConfig = filesystem::load_file(global_config)
-        .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
-        .with_context(|| format!("load global config {global_config:?}"))?;
+    let global = load_global_config(global_config)?;
     trace!("Global config: {:#?}", global);
 
-    // If local.toml can't be found, look for a file named <hostname>.toml instead
-    let mut local_config_buf = local_config.to_path_buf();
-    if !local_config_buf.exists() {
-        let hostname = hostname::get()
-            .context("failed to get the computer hostname")?
-            .into_string()
-            .expect("hostname cannot be converted to string");
-        info!(
-            "{:?} not found, using {}.toml instead (based on hostname)",
-            local_config, hostname
-        );
-        local_config_buf.set_file_name(format!("{hostname}.toml"));
-    }
-
-    let local: LocalConfig = filesystem::load_file(local_config_buf.as_path())
-        .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
-        .with_context(|| format!("load local config {local_config:?}"))?;
+    let local = load_local_config(local_config)?;
     trace!("Local config: {:#?}", local);
 
     let mut merged_config =
@@ -180,8 +298,9 @@ pub fn load_configuration(
     trace!("Merged config: {:#?}", merged_config);
 
     debug!("Expanding files which are directories...");
-    merged_config.files =
-        expand_directories(&merged_config).context("expand files that are directories")?;
+    let mut origins = std::mem::take(&mut merged_config.origins);
+    merged_config.files = expand_directories(&merged_config, &mut origins)
+        .context("expand files that are directories")?;
 
     debug!("Expanding tildes to home directory...");
     merged_config.files = merged_config
@@ -189,13 +308,17 @@ pub fn load_configuration(
         .into_iter()
         .map(|(k, mut v)| -> Result<_, anyhow::Error> {
             let path = v.path();
-            let path = shellexpand::full(&path.to_string_lossy())
+            let expanded = shellexpand::full(&path.to_string_lossy())
                 .context("failed to expand file path")?
                 .to_string();
-            v.set_path(path);
+            if Path::new(&expanded) != path {
+                origins.file(&k, format!("target {path:?} expanded to {expanded:?}"));
+            }
+            v.set_path(expanded);
             Ok((k, v))
         })
         .collect::<Result<_, _>>()?;
+    merged_config.origins = origins;
 
     trace!("Final files: {:#?}", merged_config.files);
     trace!("Final variables: {:#?}", merged_config.variables);
@@ -205,34 +328,100 @@ pub fn load_configuration(
     Ok(merged_config)
 }
 
+pub(crate) fn load_global_config(global_config: &Path) -> Result<GlobalConfig> {
+    filesystem::load_file(&filesystem::find_in_any_format(global_config))
+        .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
+        .with_context(|| format!("load global config {global_config:?}"))
+}
+
+/// If local.toml can't be found in any format, look for a file named <hostname>.toml instead
+pub(crate) fn local_config_path(local_config: &Path) -> Result<PathBuf> {
+    let mut local_config_buf = filesystem::find_in_any_format(local_config);
+    if !local_config_buf.exists() {
+        let hostname = hostname::get()
+            .context("failed to get the computer hostname")?
+            .into_string()
+            .expect("hostname cannot be converted to string");
+        info!(
+            "{:?} not found, using {}.toml instead (based on hostname)",
+            local_config, hostname
+        );
+        local_config_buf.set_file_name(format!("{hostname}.toml"));
+        local_config_buf = filesystem::find_in_any_format(&local_config_buf);
+    }
+    Ok(local_config_buf)
+}
+
+pub(crate) fn load_local_config(local_config: &Path) -> Result<LocalConfig> {
+    filesystem::load_file(local_config_path(local_config)?.as_path())
+        .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
+        .with_context(|| format!("load local config {local_config:?}"))
+}
+
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct Cache {
     pub symlinks: BTreeMap<PathBuf, PathBuf>,
     pub templates: BTreeMap<PathBuf, PathBuf>,
+    #[serde(default)]
+    pub copies: BTreeMap<PathBuf, CachedCopy>,
+    /// What the templates were rendered from, so unchanged ones don't have to be rendered again
+    #[serde(default)]
+    pub template_hashes: BTreeMap<PathBuf, TemplateHashes>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CachedCopy {
+    pub target: PathBuf,
+    /// Hash of the contents that were copied, to detect changes in the target
+    pub hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TemplateHashes {
+    /// Hash of the source, including what's prepended and appended to it
+    pub source: String,
+    /// Hash of the variables that the source uses
+    pub variables: String,
+    /// Hash of the render that's in the cache directory
+    pub rendered: String,
 }
 
+/// Adds the files to the `default` package and selects it, keeping anything that's already in the
+/// configuration files
 pub fn save_dummy_config(
     files: Vec<String>,
     local_config_path: &Path,
     global_config_path: &Path,
 ) -> Result<()> {
     debug!("Saving dummy config...");
-    let package = Package {
-        files: files.into_iter().map(|f| (f.into(), "".into())).collect(),
-        variables: Variables::new(),
-        depends: vec![],
-    };
-    trace!("Default package: {:#?}", package);
+    // Existing configuration is kept in whatever format it's in
+    let global_config_path = &filesystem::find_in_any_format(global_config_path);
+    let local_config_path = &filesystem::find_in_any_format(local_config_path);
+    let mut global_config = filesystem::load_document(global_config_path)
+        .context("load existing global config")?
+        .unwrap_or_default();
+    let default_files = global_config
+        .entry("default")
+        .or_insert_with(|| {
+            // Only the `[default.files]` header is needed
+            let mut package = toml_edit::Table::new();
+            package.set_implicit(true);
+            toml_edit::Item::Table(package)
+        })
+        .as_table_like_mut()
+        .context("get package `default` of global config")?
+        .entry("files")
+        .or_insert_with(toml_edit::table)
+        .as_table_like_mut()
+        .context("get files of package `default`")?;
+    for file in files {
+        // Files that were already configured keep their targets
+        default_files
+            .entry(&file)
+            .or_insert_with(|| toml_edit::value(""));
+    }
+    trace!("Global config: {}", global_config);
 
-    let mut packages = BTreeMap::new();
-    packages.insert("default".into(), package);
-    let global_config = GlobalConfig {
-        #[cfg(feature = "scripting")]
-        helpers: Helpers::new(),
-        packages,
-        settings: Settings::default(),
-    };
     debug!("Saving global config...");
     // Assume default args so all parents are the same
     std::fs::create_dir_all(
@@ -241,20 +430,69 @@ pub fn save_dummy_config(
             .context("get parent of global config")?,
     )
     .context("create parent of global config")?;
-    filesystem::save_file(global_config_path, global_config).context("save global config")?;
-
-    let local_config = LocalConfig {
-        includes: vec![],
-        packages: vec!["default".into()],
-        files: Files::default(),
-        variables: Variables::default(),
-    };
-    trace!("Local config: {:#?}", local_config);
-    filesystem::save_file(local_config_path, local_config).context("save local config")?;
+    filesystem::save_document(global_config_path, &global_config).context("save global config")?;
+
+    let mut local_config = filesystem::load_document(local_config_path)
+        .context("load existing local config")?
+        .unwrap_or_default();
+    let packages = local_config
+        .entry("packages")
+        .or_insert_with(|| toml_edit::value(toml_edit::Array::new()))
+        .as_array_mut()
+        .context("get packages of local config")?;
+    if !packages.iter().any(|p| p.as_str() == Some("default")) {
+        packages.push("default");
+    }
+    trace!("Local config: {}", local_config);
+    filesystem::save_document(local_config_path, &local_config).context("save local config")?;
 
     Ok(())
 }
 
+/// Adds a file to a package in the global configuration, or in the include that defines the
+/// package if the global configuration doesn't. Returns the edited file without saving it, so
+/// that it can be validated before anything else is changed.
+pub fn add_file(
+    global_config_path: &Path,
+    includes: &[PathBuf],
+    package: &str,
+    source: PathBuf,
+    target: FileTarget,
+) -> Result<(PathBuf, toml_edit::DocumentMut)> {
+    let candidates = std::iter::once(filesystem::find_in_any_format(global_config_path))
+        .chain(includes.iter().cloned());
+    for path in candidates {
+        let mut document = filesystem::load_document(&path)
+            .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
+            .with_context(|| format!("load config {path:?}"))?;
+        let Some(package_table) = document
+            .get_mut(package)
+            .and_then(|p| p.as_table_like_mut())
+        else {
+            continue;
+        };
+
+        let files = package_table
+            .entry("files")
+            .or_insert_with(toml_edit::table)
+            .as_table_like_mut()
+            .with_context(|| format!("get files of package {package:?} in {path:?}"))?;
+        let source = source.to_string_lossy();
+        if files.contains_key(&source) {
+            anyhow::bail!("file {:?} already exists in package {:?}", source, package);
+        }
+        let target = target
+            .serialize(toml_edit::ser::ValueSerializer::new())
+            .context("serialize file target")?;
+        files.insert(&source, toml_edit::Item::Value(target));
+        return Ok((path, document));
+    }
+    anyhow::bail!(
+        "find package {:?} in the global config or its includes",
+        package
+    )
+}
+
 fn recursive_extend_map(
     original: &mut BTreeMap<String, toml::Value>,
     new: BTreeMap<String, toml::Value>,
@@ -278,14 +516,68 @@ fn recursive_extend_map(
     }
 }
 
-#[allow(clippy::map_entry)]
-fn merge_configuration_files(
-    mut global: GlobalConfig,
-    local: LocalConfig,
-    patch: Option<Package>,
-) -> Result<Configuration> {
-    // Patch each package with included.toml's
-    for included_path in &local.includes {
+/// Adds the variables of `variables_files` and then `env` on top of `variables`
+fn extend_variables(
+    variables: &mut Variables,
+    variables_files: &[String],
+    env: &[String],
+    origin: &str,
+    origins: &mut Origins,
+) -> Result<()> {
+    for pattern in variables_files {
+        let paths = glob::glob(pattern)
+            .with_context(|| format!("parse glob {pattern:?}"))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("expand glob {pattern:?}"))?;
+        if paths.is_empty() {
+            warn!(
+                "Variables file {:?} of {} doesn't match any files.",
+                pattern, origin
+            );
+        }
+
+        for path in paths {
+            let new = load_variables_file(&path)
+                .with_context(|| format!("load variables file {path:?}"))?;
+            for name in new.keys() {
+                origins.variable(name, format!("set in variables file {path:?} of {origin}"));
+            }
+            recursive_extend_map(variables, new);
+        }
+    }
+
+    for name in env {
+        match std::env::var(name) {
+            Ok(value) => {
+                origins.variable(
+                    name,
+                    format!("set by environment variable {name} listed in {origin}"),
+                );
+                variables.insert(name.clone(), value.into());
+            }
+            Err(std::env::VarError::NotPresent) => {
+                debug!("Environment variable {} isn't set", name);
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("read environment variable {name}"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn load_variables_file(path: &Path) -> Result<Variables> {
+    filesystem::load_file(path).and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
+}
+
+/// Patch each package with included.toml's
+pub(crate) fn apply_includes(
+    global: &mut GlobalConfig,
+    includes: &[PathBuf],
+    origins: &mut Origins,
+) -> Result<()> {
+    for included_path in includes {
         || -> Result<()> {
             let mut included: IncludedConfig = filesystem::load_file(included_path)
                 .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
@@ -297,13 +589,25 @@ fn merge_configuration_files(
             // If package isn't filtered it's ignored, if package isn't included it's ignored
             for (package_name, package_global) in &mut global.packages {
                 if let Some(package_included) = included.remove(package_name) {
-                    package_global.files.extend(package_included.files);
-                    recursive_extend_map(&mut package_global.variables, package_included.variables);
+                    origins.package(
+                        &package_included,
+                        &format!("set in package {package_name:?} of include {included_path:?}"),
+                    );
+                    extend_package(package_global, package_included)
+                        .with_context(|| format!("extend package {package_name:?}"))?;
                 }
             }
 
             if !included.is_empty() {
                 debug!("append unknown packages: {:?}", included.keys());
+                for (package_name, package) in &included {
+                    origins.package(
+                        package,
+                        &format!(
+                            "defined in package {package_name:?} of include {included_path:?}"
+                        ),
+                    );
+                }
                 global.packages.append(&mut included);
             }
 
@@ -311,26 +615,228 @@ fn merge_configuration_files(
         }()
         .with_context(|| format!("including file {included_path:?}"))?;
     }
+    Ok(())
+}
 
-    // Enable depended packages
-    let mut enabled_packages = local.packages.clone().into_iter().collect::<BTreeSet<_>>();
-    let mut package_count = 0;
-
-    // Keep iterating until there's nothing new added
-    while enabled_packages.len() > package_count {
-        let mut new_packages = BTreeSet::new();
-        for package in &enabled_packages {
-            new_packages.extend(
-                global
-                    .packages
-                    .get(package)
-                    .with_context(|| format!("get info of package {package}"))?
-                    .depends
-                    .clone(),
+/// Adds what an include sets in a package that's already defined on top of it. Conditions can't be
+/// combined, so only one of them can set `if`.
+fn extend_package(package: &mut Package, included: Package) -> Result<()> {
+    // Destructured so that new fields can't be forgotten here
+    let Package {
+        depends,
+        recommends,
+        conflicts,
+        condition,
+        files,
+        variables,
+        variables_files,
+        env,
+    } = included;
+
+    package.depends.extend(depends);
+    package.recommends.extend(recommends);
+    package.conflicts.extend(conflicts);
+    if let Some(condition) = condition {
+        if let Some(existing) = &package.condition {
+            anyhow::bail!(
+                "condition {:?} is set although the package already has condition {:?}",
+                condition,
+                existing
+            );
+        }
+        package.condition = Some(condition);
+    }
+    package.files.extend(files);
+    recursive_extend_map(&mut package.variables, variables);
+    package.variables_files.extend(variables_files);
+    package.env.extend(env);
+    Ok(())
+}
+
+/// Returns the selected packages along with all the packages they depend on or recommend, as long
+/// as their conditions hold. Packages that are selected as `!name` aren't enabled even if they're
+/// recommended.
+pub(crate) fn enabled_packages(
+    global: &GlobalConfig,
+    selected: &[String],
+) -> Result<BTreeSet<String>> {
+    enable_packages(global, selected, &mut Origins::default()).map(|(enabled, _)| enabled)
+}
+
+/// Enables packages like `enabled_packages`, also returning the variables of each enabled
+/// package with its `variables_files` and `env` added. Conditions can use the variables of the
+/// packages that were enabled before them.
+fn enable_packages(
+    global: &GlobalConfig,
+    selected: &[String],
+    origins: &mut Origins,
+) -> Result<(BTreeSet<String>, BTreeMap<String, Variables>)> {
+    let handlebars = handlebars_helpers::new_handlebars(
+        #[cfg(feature = "scripting")]
+        &global.helpers,
+    );
+    let mut condition_variables = Variables::new();
+    condition_variables.insert("dotter".into(), handlebars_helpers::host_variables().into());
+    let mut package_variables = BTreeMap::new();
+    let enabled = resolve_conditional_packages(&global.packages, selected, |name, package| {
+        if let Some(condition) = &package.condition {
+            let enabled =
+                handlebars_helpers::eval_condition(&handlebars, &condition_variables, condition)
+                    .with_context(|| format!("evaluate condition of package {name:?}"))?;
+            origins.package(
+                package,
+                &format!("condition {condition:?} of package {name:?} evaluated to {enabled}"),
+            );
+            if !enabled {
+                return Ok(false);
+            }
+        }
+
+        let mut variables = package.variables.clone();
+        extend_variables(
+            &mut variables,
+            &package.variables_files,
+            &package.env,
+            &format!("package {name:?}"),
+            origins,
+        )
+        .with_context(|| format!("load extra variables of package {name:?}"))?;
+        recursive_extend_map(&mut condition_variables, variables.clone());
+        package_variables.insert(name.to_string(), variables);
+        Ok(true)
+    })?;
+    Ok((enabled, package_variables))
+}
+
+/// Enables the selected packages and what they pull in, as long as `is_enabled` accepts them.
+/// Packages are offered to it in the order they're enabled in: selected ones first, then what
+/// they pull in. Depending on a package that isn't accepted is an error.
+fn resolve_conditional_packages(
+    packages: &BTreeMap<String, Package>,
+    selected: &[String],
+    mut is_enabled: impl FnMut(&str, &Package) -> Result<bool>,
+) -> Result<BTreeSet<String>> {
+    let disabled = selected
+        .iter()
+        .filter_map(|p| p.strip_prefix('!'))
+        .collect::<BTreeSet<_>>();
+    // Along with the package that depends on them, if that's why they're enabled
+    let mut queue = selected
+        .iter()
+        .filter(|p| !p.starts_with('!'))
+        .map(|p| (p.clone(), None))
+        .collect::<VecDeque<(String, Option<String>)>>();
+    if let Some((package, _)) = queue.iter().find(|(p, _)| disabled.contains(p.as_str())) {
+        anyhow::bail!(
+            "package {:?} is both selected and disabled with \"!{}\"",
+            package,
+            package
+        );
+    }
+
+    let mut visited = BTreeSet::new();
+    let mut rejected = BTreeSet::new();
+    let mut enabled_packages = BTreeSet::new();
+    while let Some((name, dependent)) = queue.pop_front() {
+        if visited.insert(name.clone()) {
+            let package = packages
+                .get(&name)
+                .with_context(|| format!("get info of package {name}"))?;
+            if !is_enabled(&name, package)? {
+                rejected.insert(name.clone());
+            }
+        }
+        if rejected.contains(&name) {
+            if let Some(dependent) = dependent {
+                anyhow::bail!(
+                    "package {:?} depends on {:?}, but its condition doesn't hold",
+                    dependent,
+                    name
+                );
+            }
+            continue;
+        }
+        if enabled_packages.contains(&name) {
+            continue;
+        }
+        let package = &packages[&name];
+
+        for dependency in &package.depends {
+            if disabled.contains(dependency.as_str()) {
+                anyhow::bail!(
+                    "package {:?} depends on {:?}, which is disabled with \"!{}\"",
+                    name,
+                    dependency,
+                    dependency
+                );
+            }
+            queue.push_back((dependency.clone(), Some(name.clone())));
+        }
+        queue.extend(
+            package
+                .recommends
+                .iter()
+                .filter(|r| !disabled.contains(r.as_str()))
+                .map(|r| (r.clone(), None)),
+        );
+        enabled_packages.insert(name);
+    }
+
+    Ok(enabled_packages)
+}
+
+/// Fails if any of the enabled packages conflict with each other
+pub(crate) fn check_conflicts(
+    packages: &BTreeMap<String, Package>,
+    enabled_packages: &BTreeSet<String>,
+) -> Result<()> {
+    for name in enabled_packages {
+        let Some(package) = packages.get(name) else {
+            continue;
+        };
+        if let Some(conflict) = package
+            .conflicts
+            .iter()
+            .find(|c| enabled_packages.contains(*c))
+        {
+            anyhow::bail!(
+                "packages {:?} and {:?} conflict, but both are enabled. Deselect one of them in the local config, or disable it with \"!{}\" if another package recommends it",
+                name,
+                conflict,
+                conflict
+            );
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::map_entry)]
+fn merge_configuration_files(
+    mut global: GlobalConfig,
+    local: LocalConfig,
+    patch: Option<Package>,
+) -> Result<Configuration> {
+    let mut origins = Origins::default();
+    for (package_name, package) in &global.packages {
+        origins.package(
+            package,
+            &format!("defined in package {package_name:?} of the global config"),
+        );
+    }
+
+    apply_includes(&mut global, &local.includes, &mut origins)?;
+
+    let (enabled_packages, mut package_variables) =
+        enable_packages(&global, &local.packages, &mut origins)?;
+    check_conflicts(&global.packages, &enabled_packages)?;
+
+    for (package_name, package) in &global.packages {
+        if !enabled_packages.contains(package_name) {
+            origins.package(
+                package,
+                &format!("ignored because package {package_name:?} isn't enabled"),
             );
         }
-        package_count = enabled_packages.len();
-        enabled_packages.extend(new_packages);
     }
 
     let packages_map = global
@@ -342,6 +848,12 @@ fn merge_configuration_files(
     // Apply packages filter
     global.packages.retain(|k, _| enabled_packages.contains(k));
 
+    for (package_name, package) in &mut global.packages {
+        if let Some(variables) = package_variables.remove(package_name) {
+            package.variables = variables;
+        }
+    }
+
     let mut output = Configuration {
         #[cfg(feature = "scripting")]
         helpers: global.helpers,
@@ -349,7 +861,8 @@ fn merge_configuration_files(
         variables: Variables::default(),
         packages: packages_map,
         recurse: true,
-        settings: Settings::default(),
+        settings: global.settings.clone(),
+        origins: Origins::default(),
     };
 
     // Merge all the packages
@@ -393,7 +906,7 @@ fn merge_configuration_files(
     output.files = first_package.files;
     output.variables = first_package.variables;
 
-    for value in output.files.values_mut() {
+    for (source, value) in output.files.iter_mut() {
         if let FileTarget::Automatic(target) = value {
             *value = match global.settings.default_target_type {
                 DefaultTargetType::Symbolic => {
@@ -402,24 +915,68 @@ fn merge_configuration_files(
                 DefaultTargetType::Template => {
                     FileTarget::ComplexTemplate(TemplateTarget::from(target.clone()))
                 }
+                DefaultTargetType::Copy => FileTarget::Copy(CopyTarget::from(target.clone())),
                 _ => continue,
             };
+            origins.file(
+                source,
+                format!(
+                    "type set by default_target_type = {:?}",
+                    global.settings.default_target_type
+                ),
+            );
         }
     }
 
     // Add local.toml's patches
+    origins.package(
+        &Package {
+            files: local.files.clone(),
+            variables: local.variables.clone(),
+            ..Package::default()
+        },
+        "set in the local config",
+    );
     output.files.extend(local.files);
     recursive_extend_map(&mut output.variables, local.variables);
+    extend_variables(
+        &mut output.variables,
+        &local.variables_files,
+        &local.env,
+        "the local config",
+        &mut origins,
+    )
+    .context("load extra variables of the local config")?;
+
+    let prefixed: Variables = std::env::vars()
+        .filter_map(|(name, value)| {
+            let name = name.strip_prefix(ENV_VARIABLE_PREFIX)?.to_string();
+            origins.variable(
+                &name,
+                format!("set by environment variable {ENV_VARIABLE_PREFIX}{name}"),
+            );
+            Some((name, value.into()))
+        })
+        .collect();
+    recursive_extend_map(&mut output.variables, prefixed);
 
     // Add manual patch
     if let Some(patch) = patch {
+        origins.package(&patch, "set by --patch");
         output.files.extend(patch.files);
         recursive_extend_map(&mut output.variables, patch.variables);
     }
 
     // Remove files with target = ""
-    output.files.retain(|_, v| v.path().to_string_lossy() != "");
+    output.files.retain(|source, v| {
+        let keep = v.path().to_string_lossy() != "";
+        if !keep {
+            origins.file(source, "dropped because its target is empty");
+        }
+        keep
+    });
 
+    output.origins = origins;
     Ok(output)
 }
 
@@ -428,7 +985,8 @@ impl FileTarget {
         match self {
             FileTarget::Automatic(path) => path,
             FileTarget::Symbolic(SymbolicTarget { target, .. })
-            | FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => target,
+            | FileTarget::ComplexTemplate(TemplateTarget { target, .. })
+            | FileTarget::Copy(CopyTarget { target, .. }) => target,
         }
     }
 
@@ -436,7 +994,8 @@ impl FileTarget {
         match self {
             FileTarget::Automatic(ref mut path) => *path = new_path.into(),
             FileTarget::Symbolic(SymbolicTarget { target, .. })
-            | FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => {
+            | FileTarget::ComplexTemplate(TemplateTarget { target, .. })
+            | FileTarget::Copy(CopyTarget { target, .. }) => {
                 *target = new_path.into();
             }
         }
@@ -446,7 +1005,8 @@ impl FileTarget {
         match self {
             FileTarget::Automatic(_) => None,
             FileTarget::Symbolic(SymbolicTarget { condition, .. })
-            | FileTarget::ComplexTemplate(TemplateTarget { condition, .. }) => condition.as_ref(),
+            | FileTarget::ComplexTemplate(TemplateTarget { condition, .. })
+            | FileTarget::Copy(CopyTarget { condition, .. }) => condition.as_ref(),
         }
     }
 }
@@ -465,6 +1025,7 @@ impl From<FileTargetOuterRepr> for FileTarget {
             OR::Simple(x) => Self::Automatic(x),
             OR::Complex(IR::Symbolic(x)) => Self::Symbolic(x),
             OR::Complex(IR::ComplexTemplate(x)) => Self::ComplexTemplate(x),
+            OR::Complex(IR::Copy(x)) => Self::Copy(x),
         }
     }
 }
@@ -476,6 +1037,7 @@ impl From<FileTarget> for FileTargetOuterRepr {
             FileTarget::Automatic(x) => Self::Simple(x),
             FileTarget::Symbolic(x) => Self::Complex(IR::Symbolic(x)),
             FileTarget::ComplexTemplate(x) => Self::Complex(IR::ComplexTemplate(x)),
+            FileTarget::Copy(x) => Self::Complex(IR::Copy(x)),
         }
     }
 }
@@ -485,6 +1047,9 @@ impl<T: Into<PathBuf>> From<T> for SymbolicTarget {
         SymbolicTarget {
             target: input.into(),
             owner: None,
+            group: None,
+            mode: None,
+            dir_mode: None,
             condition: None,
             recurse: None,
         }
@@ -496,8 +1061,25 @@ impl<T: Into<PathBuf>> From<T> for TemplateTarget {
         TemplateTarget {
             target: input.into(),
             owner: None,
+            group: None,
+            mode: None,
+            dir_mode: None,
             append: None,
             prepend: None,
+            encrypted: None,
+            condition: None,
+        }
+    }
+}
+
+impl<T: Into<PathBuf>> From<T> for CopyTarget {
+    fn from(input: T) -> Self {
+        CopyTarget {
+            target: input.into(),
+            owner: None,
+            group: None,
+            mode: None,
+            dir_mode: None,
             condition: None,
         }
     }
@@ -508,14 +1090,22 @@ impl SymbolicTarget {
         TemplateTarget {
             target: self.target,
             owner: self.owner,
-            condition: self.condition,
-            prepend: None,
+            group: self.group,
+            mode: self.mode,
+            dir_mode: self.dir_mode,
             append: None,
+            prepend: None,
+            encrypted: None,
+            condition: self.condition,
         }
     }
 }
 
 impl TemplateTarget {
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted == Some(true)
+    }
+
     pub fn apply_actions(&self, mut file: String) -> String {
         if let Some(ref append) = self.append {
             file += append.as_str();
@@ -528,12 +1118,19 @@ impl TemplateTarget {
     }
 }
 
-fn expand_directories(config: &Configuration) -> Result<Files> {
+fn expand_directories(config: &Configuration, origins: &mut Origins) -> Result<Files> {
     let expanded = config
         .files
         .iter()
         .map(|(source, target)| {
-            expand_directory(source, target, config).context(format!("expand file {source:?}"))
+            let files = expand_directory(source, target, config)
+                .context(format!("expand file {source:?}"))?;
+            for child in files.keys().filter(|child| *child != source) {
+                let mut steps = origins.files.get(source).cloned().unwrap_or_default();
+                steps.push(format!("generated by expanding directory {source:?}"));
+                origins.files.insert(child.clone(), steps);
+            }
+            Ok(files)
         })
         .collect::<Result<Vec<Files>>>()?;
     Ok(expanded.into_iter().flatten().collect::<Files>())
@@ -549,10 +1146,7 @@ fn expand_directory(source: &Path, target: &FileTarget, config: &Configuration)
     // precedence over the global default
     let recurse = match target {
         FileTarget::Symbolic(SymbolicTarget {
-            target: _,
-            owner: _,
-            condition: _,
-            recurse: Some(rec),
+            recurse: Some(rec), ..
         }) => *rec,
         _ => config.recurse,
     };
@@ -667,6 +1261,270 @@ mod test {
         .unwrap_err();
     }
 
+    #[test]
+    fn deserialize_copy_target() {
+        #[derive(Debug, Deserialize)]
+        struct Helper {
+            file: FileTarget,
+        }
+
+        assert_eq!(
+            toml::from_str::<Helper>(
+                r#"
+                    [file]
+                    target = '/etc/systemd/system/foo.service.d/override.conf'
+                    type = 'copy'
+                "#,
+            )
+            .unwrap()
+            .file,
+            FileTarget::Copy(
+                PathBuf::from("/etc/systemd/system/foo.service.d/override.conf").into()
+            ),
+        );
+    }
+
+    #[test]
+    fn variables_from_files_of_each_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(
+            dir.join("a.json"),
+            r#"{"editor": "vim", "font": {"size": 12}}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("b.yaml"), "font:\n  family: mono\n").unwrap();
+
+        let mut variables: Variables = toml::from_str("editor = 'nano'").unwrap();
+        let pattern = dir.join("*").to_string_lossy().to_string();
+        let result = extend_variables(
+            &mut variables,
+            &[pattern],
+            &[],
+            "the test",
+            &mut Origins::default(),
+        );
+        result.unwrap();
+
+        assert_eq!(
+            variables,
+            toml::from_str(
+                r#"
+                    editor = 'vim'
+                    font = { size = 12, family = 'mono' }
+                "#
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn same_global_config_in_every_format() {
+        let toml: GlobalConfig = toml::from_str(
+            r#"
+                [settings]
+                default_target_type = "template"
+
+                [shell]
+                depends = ["core"]
+                [shell.files]
+                bashrc = "~/.bashrc"
+                zshrc = { target = "~/.zshrc", type = "symbolic" }
+                [shell.variables]
+                prompt = { color = "green", segments = ["user", "cwd"] }
+            "#,
+        )
+        .unwrap();
+        let yaml: GlobalConfig = serde_yaml::from_str(
+            r#"
+                settings:
+                  default_target_type: template
+                shell:
+                  depends: [core]
+                  files:
+                    bashrc: ~/.bashrc
+                    zshrc: { target: ~/.zshrc, type: symbolic }
+                  variables:
+                    prompt:
+                      color: green
+                      segments: [user, cwd]
+            "#,
+        )
+        .unwrap();
+        let json: GlobalConfig = serde_json::from_str(
+            r#"{
+                "settings": { "default_target_type": "template" },
+                "shell": {
+                    "depends": ["core"],
+                    "files": {
+                        "bashrc": "~/.bashrc",
+                        "zshrc": { "target": "~/.zshrc", "type": "symbolic" }
+                    },
+                    "variables": {
+                        "prompt": { "color": "green", "segments": ["user", "cwd"] }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        for other in [yaml, json] {
+            assert_eq!(
+                other.settings.default_target_type,
+                toml.settings.default_target_type
+            );
+            let (package, expected) = (&other.packages["shell"], &toml.packages["shell"]);
+            assert_eq!(package.depends, expected.depends);
+            assert_eq!(package.files, expected.files);
+            assert_eq!(package.variables, expected.variables);
+        }
+    }
+
+    #[test]
+    fn include_extends_every_field_of_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let include = dir.path().join("include.toml");
+        fs::write(
+            &include,
+            r#"
+                [shell]
+                depends = ["git"]
+                recommends = ["fzf"]
+                conflicts = ["fish"]
+                if = "dotter.hostname == 'work'"
+                variables_files = ["work.toml"]
+                env = ["WORK"]
+                files = { vpn = "~/.vpn" }
+                variables = { font = { size = 12 } }
+            "#,
+        )
+        .unwrap();
+        let mut global: GlobalConfig = toml::from_str(
+            r#"
+                [shell]
+                depends = ["base"]
+                env = ["HOME"]
+                files = { bashrc = "~/.bashrc" }
+                variables = { font = { family = "mono" } }
+            "#,
+        )
+        .unwrap();
+
+        let includes = [include];
+        apply_includes(&mut global, &includes, &mut Origins::default()).unwrap();
+        let shell = &global.packages["shell"];
+        assert_eq!(shell.depends, ["base", "git"]);
+        assert_eq!(shell.recommends, ["fzf"]);
+        assert_eq!(shell.conflicts, ["fish"]);
+        assert_eq!(
+            shell.condition.as_deref(),
+            Some("dotter.hostname == 'work'")
+        );
+        assert_eq!(shell.variables_files, ["work.toml"]);
+        assert_eq!(shell.env, ["HOME", "WORK"]);
+        assert_eq!(shell.files.len(), 2);
+        assert_eq!(
+            shell.variables,
+            toml::from_str("font = { family = 'mono', size = 12 }").unwrap()
+        );
+
+        // A second condition can't be combined with the first one
+        let mut global: GlobalConfig =
+            toml::from_str("[shell]\nif = \"dotter.os == 'linux'\"\n").unwrap();
+        assert!(apply_includes(&mut global, &includes, &mut Origins::default()).is_err());
+    }
+
+    #[test]
+    fn add_file_to_package_of_include() {
+        let dir = tempfile::tempdir().unwrap();
+        let global = dir.path().join("global.toml");
+        let include = dir.path().join("include.toml");
+        fs::write(&global, "[shell.files]\nbashrc = '~/.bashrc'\n").unwrap();
+        fs::write(&include, "# Only on this machine\n[work]\n").unwrap();
+        let includes = [include.clone()];
+
+        let (path, document) = add_file(
+            &global,
+            &includes,
+            "work",
+            "vpn".into(),
+            FileTarget::Automatic("~/.vpn".into()),
+        )
+        .unwrap();
+        assert_eq!(path, include);
+        assert!(document.to_string().starts_with("# Only on this machine\n"));
+        assert!(document["work"]["files"].get("vpn").is_some());
+
+        add_file(
+            &global,
+            &includes,
+            "shell",
+            "bashrc".into(),
+            FileTarget::Automatic("~/.bashrc".into()),
+        )
+        .unwrap_err();
+        add_file(
+            &global,
+            &includes,
+            "missing",
+            "a".into(),
+            FileTarget::Automatic("~/a".into()),
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn dummy_config_keeps_existing_config_and_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let global = dir.path().join("global.toml");
+        let local = dir.path().join("local.toml");
+        fs::write(
+            &global,
+            "# Shared between machines\n[default.files]\nbashrc = '~/.bashrc' # login shell\n",
+        )
+        .unwrap();
+        fs::write(&local, "# This machine\npackages = ['work']\n").unwrap();
+
+        save_dummy_config(vec!["bashrc".into(), "vimrc".into()], &local, &global).unwrap();
+
+        let global = fs::read_to_string(&global).unwrap();
+        assert!(
+            global.starts_with("# Shared between machines\n"),
+            "{global}"
+        );
+        assert!(
+            global.contains("bashrc = '~/.bashrc' # login shell\n"),
+            "{global}"
+        );
+        assert!(global.contains("vimrc = \"\"\n"), "{global}");
+        let local = fs::read_to_string(&local).unwrap();
+        assert!(local.starts_with("# This machine\n"), "{local}");
+        let document = local.parse::<toml_edit::DocumentMut>().unwrap();
+        let packages = document["packages"].as_array().unwrap();
+        assert_eq!(
+            packages
+                .iter()
+                .filter_map(|p| p.as_str())
+                .collect::<Vec<_>>(),
+            ["work", "default"]
+        );
+    }
+
+    #[test]
+    fn parse_file_modes() {
+        assert_eq!(
+            FileMode::try_from("600".to_string()).unwrap(),
+            FileMode(0o600)
+        );
+        assert_eq!(
+            FileMode::try_from("0o755".to_string()).unwrap(),
+            FileMode(0o755)
+        );
+        assert_eq!(FileMode(0o600).to_string(), "0600");
+        FileMode::try_from("0o888".to_string()).unwrap_err();
+        FileMode::try_from("17777".to_string()).unwrap_err();
+    }
+
     #[test]
     fn settting_default_target_type_symbolic() {
         let global: GlobalConfig = toml::from_str(
@@ -827,4 +1685,137 @@ mod test {
             &FileTarget::Symbolic(PathBuf::from("~/.SliverBodacious").into())
         );
     }
+
+    #[test]
+    fn origins_of_overridden_and_disabled_files() {
+        let global: GlobalConfig = toml::from_str(
+            r#"
+                [cat.files]
+                cat = '~/.QuarticCat'
+
+                [derby.files]
+                derby = '~/.DerbyLantern'
+            "#,
+        )
+        .unwrap();
+
+        let local: LocalConfig = toml::from_str(
+            r#"
+                packages = ['cat']
+
+                [files]
+                cat = ''
+            "#,
+        )
+        .unwrap();
+
+        let config = merge_configuration_files(global, local, None).unwrap();
+
+        assert_eq!(
+            config.origins.files[Path::new("cat")],
+            vec![
+                "defined in package \"cat\" of the global config",
+                "set in the local config",
+                "dropped because its target is empty",
+            ]
+        );
+        assert_eq!(
+            config.origins.files[Path::new("derby")],
+            vec![
+                "defined in package \"derby\" of the global config",
+                "ignored because package \"derby\" isn't enabled",
+            ]
+        );
+    }
+
+    #[test]
+    fn recommended_and_conflicting_packages() {
+        let global: GlobalConfig = toml::from_str(
+            r#"
+                [sway]
+                recommends = ['waybar', 'zsh']
+                conflicts = ['i3']
+
+                [i3]
+                [waybar]
+                [zsh]
+                conflicts = ['bash']
+
+                [bash]
+            "#,
+        )
+        .unwrap();
+        let selected =
+            |packages: &[&str]| packages.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            enabled_packages(&global, &selected(&["sway", "!zsh"])).unwrap(),
+            BTreeSet::from(["sway".to_string(), "waybar".to_string()])
+        );
+        assert!(enabled_packages(&global, &selected(&["zsh", "!zsh"])).is_err());
+
+        let enabled = enabled_packages(&global, &selected(&["sway", "bash"])).unwrap();
+        assert!(check_conflicts(&global.packages, &enabled).is_err());
+        let enabled = enabled_packages(&global, &selected(&["sway", "!zsh", "bash"])).unwrap();
+        check_conflicts(&global.packages, &enabled).unwrap();
+        let enabled = enabled_packages(&global, &selected(&["i3", "sway"])).unwrap();
+        assert!(check_conflicts(&global.packages, &enabled).is_err());
+    }
+
+    #[test]
+    fn conditional_packages() {
+        let global: GlobalConfig = toml::from_str(
+            r#"
+                [base.variables]
+                laptop = true
+
+                [battery]
+                if = 'laptop'
+                depends = ['power']
+                [battery.variables]
+                battery_widget = true
+
+                [power]
+                [server]
+                if = '(not laptop)'
+
+                [this_os]
+                if = '(or (eq dotter.os "unix") (eq dotter.os "windows"))'
+            "#,
+        )
+        .unwrap();
+        let local: LocalConfig = toml::from_str(
+            r#"
+                packages = ['base', 'battery', 'server', 'this_os']
+            "#,
+        )
+        .unwrap();
+
+        let config = merge_configuration_files(global, local, None).unwrap();
+
+        assert_eq!(
+            config.packages,
+            BTreeMap::from([
+                ("base".to_string(), true),
+                ("battery".to_string(), true),
+                ("power".to_string(), true),
+                ("server".to_string(), false),
+                ("this_os".to_string(), true),
+            ])
+        );
+        assert_eq!(config.variables["battery_widget"].as_bool(), Some(true));
+
+        // Depending on a package doesn't enable it if its condition doesn't hold
+        let global: GlobalConfig = toml::from_str(
+            r#"
+                [desktop]
+                depends = ['server']
+                [server]
+                if = 'false'
+            "#,
+        )
+        .unwrap();
+        assert!(enabled_packages(&global, &["desktop".to_string()]).is_err());
+        assert!(enabled_packages(&global, &["server".to_string(), "desktop".to_string()]).is_err());
+    }
 }
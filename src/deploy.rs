@@ -5,32 +5,41 @@ use std::collections::BTreeSet;
 use std::io::{self, Read};
 use std::path::PathBuf;
 
-use crate::actions::{self, ActionRunner, RealActionRunner};
-use crate::args::Options;
-use crate::config::{self, Cache, FileTarget, SymbolicTarget, TemplateTarget};
+use crate::actions::{self, ActionRunner, Prerendered, RealActionRunner};
+use crate::args::{Options, OutputFormat};
+use crate::backup::Backups;
+use crate::cache;
+#[cfg(feature = "scripting")]
+use crate::config::Helpers;
+use crate::config::{
+    self, Cache, CachedCopy, CopyTarget, FileTarget, Files, Package, SymbolicTarget,
+    TemplateHashes, TemplateTarget, Variables,
+};
 use crate::display_error;
-use crate::filesystem::{self, load_file, Filesystem};
-use crate::handlebars_helpers::create_new_handlebars;
+use crate::filesystem::{self, Filesystem};
+use crate::handlebars_helpers::{self, create_new_handlebars};
 use crate::hooks;
+use crate::journal::{Journal, JournalingFilesystem};
+use crate::plan::{self, Plan};
+use crate::report::{Action, ActionKind, Comparison, Report};
+use crate::secrets::{self, Secrets};
 
 /// Returns true if an error was printed
 pub fn deploy(opt: &Options) -> Result<bool> {
+    deploy_planned(opt, None)
+}
+
+/// Deploys, failing before anything is changed if the deploy wouldn't do exactly what was
+/// `planned`. The plan is checked against the same renders and comparisons that get deployed.
+/// Returns true if an error was printed
+pub(crate) fn deploy_planned(opt: &Options, planned: Option<&Plan>) -> Result<bool> {
     // === Load configuration ===
-    let mut patch = None;
-    if opt.patch {
-        debug!("Reading manual patch from stdin...");
-        let mut patch_str = String::new();
-        io::stdin()
-            .read_to_string(&mut patch_str)
-            .context("read patch from stdin")?;
-        patch = Some(toml::from_str(&patch_str).context("parse patch into package")?);
-    }
-    trace!("Manual patch: {:#?}", patch);
+    let patch = load_patch(opt)?;
 
     let mut config = config::load_configuration(&opt.local_config, &opt.global_config, patch)
         .context("get a configuration")?;
 
-    let mut cache = if let Some(cache) = load_file(&opt.cache_file)? {
+    let mut cache = if let Some(cache) = cache::load(&opt.cache_file)? {
         cache
     } else {
         warn!("Cache file not found. Assuming cache is empty.");
@@ -39,8 +48,15 @@ pub fn deploy(opt: &Options) -> Result<bool> {
 
     // === Pre-deploy ===
 
+    let secrets = secrets::decrypt_variables(&mut config).context("decrypt variables")?;
     let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
 
+    let mut journal = if opt.dry_run {
+        None
+    } else {
+        Some(Journal::begin(opt).context("start journal")?)
+    };
+
     debug!("Running pre-deploy hook");
     if !opt.dry_run {
         hooks::run_hook(
@@ -52,10 +68,11 @@ pub fn deploy(opt: &Options) -> Result<bool> {
         .context("run pre-deploy hook")?;
     }
 
-    let (mut real_fs, mut dry_run_fs);
-    let fs: &mut dyn Filesystem = if !opt.dry_run {
+    let (mut real_fs, mut dry_run_fs, mut journaling_fs);
+    let fs: &mut dyn Filesystem = if let Some(journal) = &mut journal {
         real_fs = crate::filesystem::RealFilesystem::new(opt.noconfirm);
-        &mut real_fs
+        journaling_fs = JournalingFilesystem::new(&mut real_fs, journal);
+        &mut journaling_fs
     } else {
         dry_run_fs = crate::filesystem::DryRunFilesystem::new();
         &mut dry_run_fs
@@ -63,84 +80,111 @@ pub fn deploy(opt: &Options) -> Result<bool> {
 
     // === Re-structure configuration ===
 
-    // On Windows, you need developer mode to create symlinks.
-    let symlinks_enabled = if filesystem::symlinks_enabled(&PathBuf::from("DOTTER_SYMLINK_TEST"))
-        .context("check whether symlinks are enabled")?
-    {
-        true
-    } else {
-        warn!(
-            "No permission to create symbolic links.\n
-On Windows, in order to create symbolic links you need to enable Developer Mode.\n
-Proceeding by copying instead of symlinking."
-        );
-        false
-    };
+    let desired =
+        desired_files(config.files).context("sort files into symlinks, templates and copies")?;
 
-    let mut desired_symlinks = BTreeMap::<PathBuf, SymbolicTarget>::new();
-    let mut desired_templates = BTreeMap::<PathBuf, TemplateTarget>::new();
+    // === Perform deployment ===
 
-    for (source, target) in config.files {
-        if symlinks_enabled {
-            match target {
-                FileTarget::Automatic(target) => {
-                    if filesystem::is_template(&source)
-                        .context(format!("check whether {source:?} is a template"))?
-                    {
-                        desired_templates.insert(source, target.into());
-                    } else {
-                        desired_symlinks.insert(source, target.into());
-                    }
-                }
-                FileTarget::Symbolic(target) => {
-                    desired_symlinks.insert(source, target);
-                }
-                FileTarget::ComplexTemplate(target) => {
-                    desired_templates.insert(source, target);
-                }
-            }
-        } else {
-            match target {
-                FileTarget::Automatic(target) => {
-                    desired_templates.insert(source, target.into());
-                }
-                FileTarget::Symbolic(target) => {
-                    desired_templates.insert(source, target.into_template());
-                }
-                FileTarget::ComplexTemplate(target) => {
-                    desired_templates.insert(source, target);
-                }
-            }
-        }
+    let inputs = template_inputs(
+        &desired.templates,
+        &config.variables,
+        &secrets,
+        #[cfg(feature = "scripting")]
+        &config.helpers,
+    );
+    let mut prerendered = if opt.full {
+        Prerendered::new()
+    } else {
+        unchanged_renders(&inputs, &cache, opt)
+    };
+    debug!(
+        "Reusing the renders of {} unchanged templates",
+        prerendered.len()
+    );
+    let changed_templates = desired
+        .templates
+        .iter()
+        .filter(|(source, _)| !prerendered.contains_key(*source))
+        .map(|(source, target)| (source.clone(), target.clone()))
+        .collect();
+    prerendered.extend(actions::prerender_templates(
+        &changed_templates,
+        fs,
+        &handlebars,
+        &config.variables,
+    ));
+    let comparisons = actions::compare_templates(&desired.templates, &opt.cache_directory);
+
+    if let Some(planned) = planned {
+        let current = plan::plan_actions(
+            fs,
+            &desired,
+            &cache,
+            &plan::Rendering {
+                handlebars: &handlebars,
+                variables: &config.variables,
+                secrets: &secrets,
+                prerendered: &prerendered,
+                #[cfg(feature = "scripting")]
+                helpers: &config.helpers,
+            },
+            &comparisons,
+            opt,
+        )
+        .context("check plan")?;
+        plan::check(planned, &current)?;
     }
 
-    // === Perform deployment ===
-
+    let mut backups = Backups::new(&opt.backup_directory);
     let mut runner = RealActionRunner::new(
         fs,
+        &mut backups,
         &handlebars,
         &config.variables,
+        &secrets,
+        &prerendered,
+        comparisons,
         opt.force,
+        opt.interactive,
+        opt.merge,
+        opt.conflict_markers,
         opt.diff_context_lines,
     );
 
-    let (suggest_force, mut error_occurred) = run_deploy(
+    let report = run_deploy(
         &mut runner,
-        &desired_symlinks,
-        &desired_templates,
+        &desired.symlinks,
+        &desired.templates,
+        &desired.copies,
         &mut cache,
         opt,
     );
+    report.finish(opt.dry_run);
 
     // === Post-deploy ===
 
-    if suggest_force {
+    let mut error_occurred = report.error_occurred;
+    if report.suggest_force {
         error!("Some files were skipped. To ignore errors and overwrite unexpected target files, use the --force flag.");
         error_occurred = true;
     }
 
-    if !opt.dry_run {
-        filesystem::save_file(&opt.cache_file, cache).context("save cache")?;
+    if opt.atomic && error_occurred {
+        if let Some(journal) = journal {
+            error!("Rolling back the deploy because of --atomic.");
+            journal
+                .rollback(&mut crate::filesystem::RealFilesystem::new(opt.noconfirm))
+                .context("roll back deploy")?;
+        }
+        return Ok(true);
+    }
+
+    if let Some(mut journal) = journal {
+        record_template_hashes(&mut cache, inputs, &prerendered, opt);
+        journal.record(&opt.cache_file).context("journal cache")?;
+        cache::save(&opt.cache_file, &cache).context("save cache")?;
+        journal.finish(error_occurred).context("finish journal")?;
+        backups.save().context("save backups")?;
     }
 
     debug!("Running post-deploy hook");
@@ -162,9 +206,11 @@ pub fn undeploy(opt: &Options) -> Result<bool> {
     let mut config = config::load_configuration(&opt.local_config, &opt.global_config, None)
         .context("get a configuration")?;
 
-    let mut cache: config::Cache = filesystem::load_file(&opt.cache_file)?
-        .context("load cache: Cannot undeploy without a cache.")?;
+    let mut cache: config::Cache =
+        cache::load(&opt.cache_file)?.context("load cache: Cannot undeploy without a cache.")?;
 
+    // No templates are rendered when undeploying, so encrypted variables are left as they are
+    // (undeploy hooks see them encrypted) instead of running `decrypt_command` for nothing
     let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
 
     // === Pre-undeploy ===
@@ -180,8 +226,7 @@ pub fn undeploy(opt: &Options) -> Result<bool> {
         .context("run pre-undeploy hook")?;
     }
 
-    let mut suggest_force = false;
-    let mut error_occurred = false;
+    let mut report = Report::new(opt.output, opt.force);
 
     let (mut real_fs, mut dry_run_fs);
     let fs: &mut dyn Filesystem = if !opt.dry_run {
@@ -194,35 +239,82 @@ pub fn undeploy(opt: &Options) -> Result<bool> {
 
     // === Perform undeployment ===
 
+    let mut backups = Backups::new(&opt.backup_directory);
+
     for (deleted_symlink, target) in cache.symlinks.clone() {
+        let action = Action {
+            kind: ActionKind::DeleteSymlink,
+            source: &deleted_symlink,
+            target: &target,
+            comparison: inspect(opt, || {
+                fs.compare_symlink(&deleted_symlink, &target)
+                    .map(Comparison::Symlink)
+            }),
+        };
         execute_action(
-            actions::delete_symlink(&deleted_symlink, &target, fs, opt.force),
+            actions::delete_symlink(&deleted_symlink, &target, fs, &mut backups, opt.force),
             || cache.symlinks.remove(&deleted_symlink),
-            || format!("delete symlink {deleted_symlink:?} -> {target:?}"),
-            &mut suggest_force,
-            &mut error_occurred,
+            &action,
+            &mut report,
         );
     }
 
     for (deleted_template, target) in cache.templates.clone() {
+        let cache_file = opt.cache_directory.join(&deleted_template);
+        let action = Action {
+            kind: ActionKind::DeleteTemplate,
+            source: &deleted_template,
+            target: &target,
+            comparison: inspect(opt, || {
+                fs.compare_template(&target, &cache_file)
+                    .map(Comparison::Template)
+            }),
+        };
         execute_action(
             actions::delete_template(
                 &deleted_template,
-                &opt.cache_directory.join(&deleted_template),
+                &cache_file,
                 &target,
                 fs,
+                &mut backups,
                 opt.force,
             ),
             || cache.templates.remove(&deleted_template),
-            || format!("delete template {deleted_template:?} -> {target:?}"),
-            &mut suggest_force,
-            &mut error_occurred,
+            &action,
+            &mut report,
+        );
+    }
+
+    for (deleted_copy, copy) in cache.copies.clone() {
+        let action = Action {
+            kind: ActionKind::DeleteCopy,
+            source: &deleted_copy,
+            target: &copy.target,
+            comparison: inspect(opt, || {
+                fs.compare_copy(&copy.target, &copy.hash)
+                    .map(Comparison::Copy)
+            }),
+        };
+        execute_action(
+            actions::delete_copy(
+                &deleted_copy,
+                &copy.target,
+                &copy.hash,
+                fs,
+                &mut backups,
+                opt.force,
+            ),
+            || cache.copies.remove(&deleted_copy),
+            &action,
+            &mut report,
         );
     }
+    report.finish(opt.dry_run);
 
     // === Post-undeploy ===
 
-    if suggest_force {
+    let mut error_occurred = report.error_occurred;
+    if report.suggest_force {
         error!("Some files were skipped. To ignore errors and overwrite unexpected target files, use the --force flag.");
         error_occurred = true;
     }
@@ -230,7 +322,11 @@ pub fn undeploy(opt: &Options) -> Result<bool> {
     if !opt.dry_run {
         // Should be empty if everything went well, but if some things were skipped this contains
         // them.
-        filesystem::save_file(&opt.cache_file, cache).context("save cache")?;
+        cache
+            .template_hashes
+            .retain(|source, _| cache.templates.contains_key(source));
+        cache::save(&opt.cache_file, &cache).context("save cache")?;
+        backups.save().context("save backups")?;
     }
 
     debug!("Running post-undeploy hook");
@@ -247,16 +343,217 @@ pub fn undeploy(opt: &Options) -> Result<bool> {
     Ok(error_occurred)
 }
 
-fn run_deploy<A: ActionRunner>(
-    runner: &mut A,
-    desired_symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
-    desired_templates: &BTreeMap<PathBuf, TemplateTarget>,
+/// Reads the manual patch from stdin if `--patch` was given
+pub(crate) fn load_patch(opt: &Options) -> Result<Option<Package>> {
+    let mut patch = None;
+    if opt.patch {
+        debug!("Reading manual patch from stdin...");
+        let mut patch_str = String::new();
+        io::stdin()
+            .read_to_string(&mut patch_str)
+            .context("read patch from stdin")?;
+        patch = Some(toml::from_str(&patch_str).context("parse patch into package")?);
+    }
+    trace!("Manual patch: {:#?}", patch);
+    Ok(patch)
+}
+
+pub(crate) struct DesiredFiles {
+    pub symlinks: BTreeMap<PathBuf, SymbolicTarget>,
+    pub templates: BTreeMap<PathBuf, TemplateTarget>,
+    pub copies: BTreeMap<PathBuf, CopyTarget>,
+}
+
+/// Decides which files will be symlinked, which will be templated and which will be copied
+pub(crate) fn desired_files(files: Files) -> Result<DesiredFiles> {
+    // On Windows, you need developer mode to create symlinks.
+    let symlinks_enabled = if filesystem::symlinks_enabled(&PathBuf::from("DOTTER_SYMLINK_TEST"))
+        .context("check whether symlinks are enabled")?
+    {
+        true
+    } else {
+        warn!(
+            "No permission to create symbolic links.\n
+On Windows, in order to create symbolic links you need to enable Developer Mode.\n
+Proceeding by copying instead of symlinking."
+        );
+        false
+    };
+
+    let mut desired_symlinks = BTreeMap::<PathBuf, SymbolicTarget>::new();
+    let mut desired_templates = BTreeMap::<PathBuf, TemplateTarget>::new();
+    let mut desired_copies = BTreeMap::<PathBuf, CopyTarget>::new();
+
+    for (source, target) in files {
+        if symlinks_enabled {
+            match target {
+                FileTarget::Automatic(target) => {
+                    if filesystem::is_template(&source)
+                        .context(format!("check whether {source:?} is a template"))?
+                    {
+                        desired_templates.insert(source, target.into());
+                    } else {
+                        desired_symlinks.insert(source, target.into());
+                    }
+                }
+                FileTarget::Symbolic(target) => {
+                    desired_symlinks.insert(source, target);
+                }
+                FileTarget::ComplexTemplate(target) => {
+                    desired_templates.insert(source, target);
+                }
+                FileTarget::Copy(target) => {
+                    desired_copies.insert(source, target);
+                }
+            }
+        } else {
+            match target {
+                // Templated rather than copied, so that the caches of earlier versions stay valid
+                FileTarget::Automatic(target) => {
+                    desired_templates.insert(source, target.into());
+                }
+                FileTarget::Symbolic(target) => {
+                    desired_templates.insert(source, target.into_template());
+                }
+                FileTarget::ComplexTemplate(target) => {
+                    desired_templates.insert(source, target);
+                }
+                FileTarget::Copy(target) => {
+                    desired_copies.insert(source, target);
+                }
+            }
+        }
+    }
+
+    Ok(DesiredFiles {
+        symlinks: desired_symlinks,
+        templates: desired_templates,
+        copies: desired_copies,
+    })
+}
+
+/// Whether the render of a template could contain decrypted secrets, because its source is
+/// encrypted or it uses encrypted variables. `used` are the variables it uses, if they're known.
+/// Hashes of such renders are never written anywhere, since short secrets could be brute-forced
+/// from them.
+pub(crate) fn renders_secrets(
+    target: &TemplateTarget,
+    used: Option<&BTreeSet<String>>,
+    variables: &Variables,
+    secrets: &Secrets,
+) -> bool {
+    if target.is_encrypted() {
+        return true;
+    }
+    if secrets.is_empty() {
+        return false;
+    }
+    used.map_or(true, |used| {
+        used.iter()
+            .filter_map(|name| variables.get(name))
+            .any(|value| secrets.is_secret(value))
+    })
+}
+
+/// Hashes of the source and of the used variables of every template whose render only depends on
+/// them. Templates that use helpers like `command_output` or could contain secrets are left out.
+fn template_inputs(
+    templates: &BTreeMap<PathBuf, TemplateTarget>,
+    variables: &Variables,
+    secrets: &Secrets,
+    #[cfg(feature = "scripting")] helpers: &Helpers,
+) -> BTreeMap<PathBuf, (String, String)> {
+    templates
+        .iter()
+        .filter(|(_, target)| !target.is_encrypted())
+        .filter_map(|(source, target)| {
+            let source_contents = target.apply_actions(std::fs::read_to_string(source).ok()?);
+            let used = handlebars_helpers::template_variables(
+                &source_contents,
+                #[cfg(feature = "scripting")]
+                helpers,
+            )?;
+            if renders_secrets(target, Some(&used), variables, secrets) {
+                return None;
+            }
+            let used = used
+                .iter()
+                .map(|name| (name, variables.get(name)))
+                .collect::<BTreeMap<_, _>>();
+            let used = serde_json::to_string(&used).ok()?;
+            Some((
+                source.clone(),
+                (
+                    filesystem::hash(source_contents.as_bytes()),
+                    filesystem::hash(used.as_bytes()),
+                ),
+            ))
+        })
+        .collect()
+}
+
+/// Reads the previous renders of the templates whose inputs are the same as when they were cached
+fn unchanged_renders(
+    inputs: &BTreeMap<PathBuf, (String, String)>,
+    cache: &Cache,
+    opt: &Options,
+) -> Prerendered {
+    inputs
+        .iter()
+        .filter_map(|(source, (source_hash, variables_hash))| {
+            let cached = cache.template_hashes.get(source)?;
+            if &cached.source != source_hash || &cached.variables != variables_hash {
+                return None;
+            }
+            let rendered = std::fs::read_to_string(opt.cache_directory.join(source)).ok()?;
+            (filesystem::hash(rendered.as_bytes()) == cached.rendered)
+                .then(|| (source.clone(), Ok(rendered)))
+        })
+        .collect()
+}
+
+/// Remembers the inputs of the templates whose cached render is the one from this deploy. Ones
+/// that were skipped still have an older render in the cache, so they're left out.
+fn record_template_hashes(
     cache: &mut Cache,
+    inputs: BTreeMap<PathBuf, (String, String)>,
+    prerendered: &Prerendered,
     opt: &Options,
-) -> (bool, bool) {
-    let mut suggest_force = false;
-    let mut error_occurred = false;
+) {
+    cache.template_hashes = inputs
+        .into_iter()
+        .filter(|(source, _)| cache.templates.contains_key(source))
+        .filter_map(|(source, (source_hash, variables_hash))| {
+            let rendered = filesystem::hash(prerendered.get(&source)?.as_ref().ok()?.as_bytes());
+            let cached = std::fs::read(opt.cache_directory.join(&source)).ok()?;
+            (filesystem::hash(&cached) == rendered).then_some((
+                source,
+                TemplateHashes {
+                    source: source_hash,
+                    variables: variables_hash,
+                    rendered,
+                },
+            ))
+        })
+        .collect();
+}
+
+/// What happens to a single file during a deploy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Step {
+    pub kind: ActionKind,
+    pub source: PathBuf,
+    pub target: PathBuf,
+}
 
+/// Decides what to do with every file by comparing the desired files with the cache, without
+/// looking at the filesystem. Deletions come first, then creations, then updates.
+pub(crate) fn plan_steps(
+    desired_symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
+    desired_templates: &BTreeMap<PathBuf, TemplateTarget>,
+    desired_copies: &BTreeMap<PathBuf, CopyTarget>,
+    cache: &Cache,
+) -> Vec<Step> {
     // Index by both source and target location
     let existing_symlinks: BTreeSet<(PathBuf, PathBuf)> = cache
         .symlinks
@@ -268,147 +565,306 @@ fn run_deploy<A: ActionRunner>(
         .iter()
         .map(|(k, v)| (k.clone(), v.clone()))
         .collect();
+    let existing_copies: BTreeSet<(PathBuf, PathBuf)> = cache
+        .copies
+        .iter()
+        .map(|(k, v)| (k.clone(), v.target.clone()))
+        .collect();
 
-    let desired_symlinks: BTreeMap<(PathBuf, PathBuf), _> = desired_symlinks
+    let desired_symlinks: BTreeSet<(PathBuf, PathBuf)> = desired_symlinks
+        .iter()
+        .map(|(k, v)| (k.clone(), v.target.clone()))
+        .collect();
+    let desired_templates: BTreeSet<(PathBuf, PathBuf)> = desired_templates
         .iter()
-        .map(|(k, v)| ((k.clone(), v.target.clone()), v))
+        .map(|(k, v)| (k.clone(), v.target.clone()))
         .collect();
-    let desired_templates: BTreeMap<(PathBuf, PathBuf), _> = desired_templates
+    let desired_copies: BTreeSet<(PathBuf, PathBuf)> = desired_copies
         .iter()
-        .map(|(k, v)| ((k.clone(), v.target.clone()), v))
+        .map(|(k, v)| (k.clone(), v.target.clone()))
         .collect();
 
-    // Avoid modifying cache while iterating over it
-    let mut resulting_cache = cache.clone();
+    let mut steps = Vec::new();
+    let mut add = |kind, files: Vec<&(PathBuf, PathBuf)>| {
+        steps.extend(files.into_iter().map(|(source, target)| Step {
+            kind,
+            source: source.clone(),
+            target: target.clone(),
+        }));
+    };
 
-    for (source, target) in
-        existing_symlinks.difference(&desired_symlinks.keys().cloned().collect())
-    {
-        execute_action(
-            runner.delete_symlink(source, target),
-            || resulting_cache.symlinks.remove(source),
-            || format!("delete symlink {source:?} -> {target:?}"),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
-    }
+    use ActionKind::*;
+    add(
+        DeleteSymlink,
+        existing_symlinks.difference(&desired_symlinks).collect(),
+    );
+    add(
+        DeleteTemplate,
+        existing_templates.difference(&desired_templates).collect(),
+    );
+    add(
+        DeleteCopy,
+        existing_copies.difference(&desired_copies).collect(),
+    );
+    add(
+        CreateSymlink,
+        desired_symlinks.difference(&existing_symlinks).collect(),
+    );
+    add(
+        CreateTemplate,
+        desired_templates.difference(&existing_templates).collect(),
+    );
+    add(
+        CreateCopy,
+        desired_copies.difference(&existing_copies).collect(),
+    );
+    add(
+        UpdateSymlink,
+        existing_symlinks.intersection(&desired_symlinks).collect(),
+    );
+    add(
+        UpdateTemplate,
+        existing_templates
+            .intersection(&desired_templates)
+            .collect(),
+    );
+    add(
+        UpdateCopy,
+        existing_copies.intersection(&desired_copies).collect(),
+    );
 
-    for (source, target) in
-        existing_templates.difference(&desired_templates.keys().cloned().collect())
-    {
-        execute_action(
-            runner.delete_template(source, &opt.cache_directory.join(source), target),
-            || resulting_cache.templates.remove(source),
-            || format!("delete template {source:?} -> {target:?}"),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
-    }
+    steps
+}
 
-    for (source, target_path) in desired_symlinks
-        .keys()
-        .cloned()
-        .collect::<BTreeSet<_>>()
-        .difference(&existing_symlinks)
-    {
-        let target = desired_symlinks
-            .get(&(source.into(), target_path.into()))
-            .unwrap();
-        execute_action(
-            runner.create_symlink(source, target),
-            || {
-                resulting_cache
-                    .symlinks
-                    .insert(source.clone(), target_path.clone())
-            },
-            || format!("create symlink {source:?} -> {target_path:?}"),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
-    }
+fn run_deploy<A: ActionRunner>(
+    runner: &mut A,
+    desired_symlinks: &BTreeMap<PathBuf, SymbolicTarget>,
+    desired_templates: &BTreeMap<PathBuf, TemplateTarget>,
+    desired_copies: &BTreeMap<PathBuf, CopyTarget>,
+    cache: &mut Cache,
+    opt: &Options,
+) -> Report {
+    let mut report = Report::new(opt.output, opt.force);
 
-    for (source, target_path) in desired_templates
-        .keys()
-        .cloned()
-        .collect::<BTreeSet<_>>()
-        .difference(&existing_templates)
-    {
-        let target = desired_templates
-            .get(&(source.into(), target_path.into()))
-            .unwrap();
-        execute_action(
-            runner.create_template(source, &opt.cache_directory.join(source), target),
-            || {
-                resulting_cache
-                    .templates
-                    .insert(source.clone(), target_path.clone())
-            },
-            || format!("create template {source:?} -> {target_path:?}"),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
-    }
+    // Avoid modifying cache while iterating over it
+    let mut resulting_cache = cache.clone();
 
-    for (source, target_path) in
-        existing_symlinks.intersection(&desired_symlinks.keys().cloned().collect())
+    let steps = plan_steps(desired_symlinks, desired_templates, desired_copies, cache);
+    for Step {
+        kind,
+        source,
+        target: target_path,
+    } in &steps
     {
-        let target = desired_symlinks
-            .get(&(source.into(), target_path.into()))
-            .unwrap();
-        execute_action(
-            runner.update_symlink(source, target),
-            || (),
-            || format!("update symlink {source:?} -> {target_path:?}"),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
-    }
+        let action = |comparison| Action {
+            kind: *kind,
+            source,
+            target: target_path,
+            comparison,
+        };
+        match kind {
+            ActionKind::DeleteSymlink => {
+                let action = action(inspect(opt, || {
+                    runner
+                        .compare_symlink(source, target_path)
+                        .map(Comparison::Symlink)
+                }));
+                execute_action(
+                    runner.delete_symlink(source, target_path),
+                    || resulting_cache.symlinks.remove(source),
+                    &action,
+                    &mut report,
+                );
+            }
+            ActionKind::DeleteTemplate => {
+                let cache_file = opt.cache_directory.join(source);
+                let action = action(inspect(opt, || {
+                    runner
+                        .compare_template(target_path, &cache_file)
+                        .map(Comparison::Template)
+                }));
+                execute_action(
+                    runner.delete_template(source, &cache_file, target_path),
+                    || resulting_cache.templates.remove(source),
+                    &action,
+                    &mut report,
+                );
+            }
+            ActionKind::DeleteCopy => {
+                let hash = &cache.copies[source].hash;
+                let action = action(inspect(opt, || {
+                    runner.compare_copy(target_path, hash).map(Comparison::Copy)
+                }));
+                execute_action(
+                    runner.delete_copy(source, target_path, hash),
+                    || resulting_cache.copies.remove(source),
+                    &action,
+                    &mut report,
+                );
+            }
+            ActionKind::CreateSymlink => {
+                let target = &desired_symlinks[source];
+                let action = action(inspect(opt, || {
+                    runner
+                        .compare_symlink(source, target_path)
+                        .map(Comparison::Symlink)
+                }));
+                execute_action(
+                    runner.create_symlink(source, target),
+                    || {
+                        resulting_cache
+                            .symlinks
+                            .insert(source.clone(), target_path.clone())
+                    },
+                    &action,
+                    &mut report,
+                );
+            }
+            ActionKind::CreateTemplate => {
+                let target = &desired_templates[source];
+                let cache_file = opt.cache_directory.join(source);
+                let action = action(inspect(opt, || {
+                    runner
+                        .compare_template(target_path, &cache_file)
+                        .map(Comparison::Template)
+                }));
+                execute_action(
+                    runner.create_template(source, &cache_file, target),
+                    || {
+                        resulting_cache
+                            .templates
+                            .insert(source.clone(), target_path.clone())
+                    },
+                    &action,
+                    &mut report,
+                );
+            }
+            ActionKind::CreateCopy => {
+                let target = &desired_copies[source];
+                // The cache records the hash of what was copied
+                let hash = runner.hash_file(source).context("hash source file");
+                let action = action(hash.as_ref().ok().and_then(|hash| {
+                    inspect(opt, || {
+                        runner.compare_copy(target_path, hash).map(Comparison::Copy)
+                    })
+                }));
+                let (result, hash) = match hash {
+                    Ok(hash) => (runner.create_copy(source, target, &hash), hash),
+                    Err(e) => (Err(e), String::new()),
+                };
+                execute_action(
+                    result,
+                    || {
+                        resulting_cache.copies.insert(
+                            source.clone(),
+                            CachedCopy {
+                                target: target_path.clone(),
+                                hash,
+                            },
+                        )
+                    },
+                    &action,
+                    &mut report,
+                );
+            }
+            ActionKind::UpdateSymlink => {
+                let target = &desired_symlinks[source];
+                let action = action(inspect(opt, || {
+                    runner
+                        .compare_symlink(source, target_path)
+                        .map(Comparison::Symlink)
+                }));
+                execute_action(
+                    runner.update_symlink(source, target),
+                    || (),
+                    &action,
+                    &mut report,
+                );
+            }
+            ActionKind::UpdateTemplate => {
+                let target = &desired_templates[source];
+                let cache_file = opt.cache_directory.join(source);
+                let action = action(inspect(opt, || {
+                    runner
+                        .compare_template(target_path, &cache_file)
+                        .map(Comparison::Template)
+                }));
+                execute_action(
+                    runner.update_template(source, &cache_file, target),
+                    || (),
+                    &action,
+                    &mut report,
+                );
+            }
+            ActionKind::UpdateCopy => {
+                let target = &desired_copies[source];
+                let cached_hash = &cache.copies[source].hash;
+                let action = action(inspect(opt, || {
+                    runner
+                        .compare_copy(target_path, cached_hash)
+                        .map(Comparison::Copy)
+                }));
+                let (result, hash) = match runner.hash_file(source).context("hash source file") {
+                    Ok(hash) => (runner.update_copy(source, target, cached_hash, &hash), hash),
+                    Err(e) => (Err(e), String::new()),
+                };
+                execute_action(
+                    result,
+                    || {
+                        if let Some(copy) = resulting_cache.copies.get_mut(source) {
+                            copy.hash = hash;
+                        }
+                    },
+                    &action,
+                    &mut report,
+                );
+            }
+        }
 
-    for (source, target_path) in
-        existing_templates.intersection(&desired_templates.keys().cloned().collect())
-    {
-        let target = desired_templates
-            .get(&(source.into(), target_path.into()))
-            .unwrap();
-        execute_action(
-            runner.update_template(source, &opt.cache_directory.join(source), target),
-            || (),
-            || format!("update template {source:?} -> {target_path:?}"),
-            &mut suggest_force,
-            &mut error_occurred,
-        );
+        if opt.atomic && (report.error_occurred || report.suggest_force) {
+            // The rest would be rolled back anyway
+            break;
+        }
     }
 
     *cache = resulting_cache;
 
-    (suggest_force, error_occurred)
+    report
+}
+
+/// Inspects the state of a target before acting on it, but only if the output format reports it
+fn inspect(opt: &Options, compare: impl FnOnce() -> Result<Comparison>) -> Option<Comparison> {
+    if opt.output == OutputFormat::Json {
+        compare().ok()
+    } else {
+        None
+    }
 }
 
 /// Used to remove duplication
-fn execute_action<T, S: FnOnce() -> T, E: FnOnce() -> String>(
+fn execute_action<T, S: FnOnce() -> T>(
     result: Result<bool>,
     success: S,
-    context: E,
-    suggest_force: &mut bool,
-    error_occurred: &mut bool,
+    action: &Action<'_>,
+    report: &mut Report,
 ) {
+    report.record(action, &result);
     match result {
         Ok(true) => {
             success();
         }
-        Ok(false) => {
-            *suggest_force = true;
-        }
+        Ok(false) => {}
         Err(e) => {
-            display_error(e.context(context()));
-            *error_occurred = true;
+            display_error(e.context(action.to_string()));
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::filesystem::{SymlinkComparison, TemplateComparison};
+    use crate::config::FileMode;
+    use crate::filesystem::{CopyComparison, SymlinkComparison, TemplateComparison};
+    use crate::secrets::Secrets;
 
     use std::path::{Path, PathBuf};
 
@@ -456,10 +912,11 @@ mod test {
             .in_sequence(&mut seq)
             .returning(|_, _, _| Ok(true));
 
-        let (suggest_force, error_occurred) = run_deploy(
+        let report = run_deploy(
             &mut runner,
             &desired_symlinks,
             &desired_templates,
+            &BTreeMap::new(),
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -468,8 +925,8 @@ mod test {
             },
         );
 
-        assert!(!suggest_force);
-        assert!(!error_occurred);
+        assert!(!report.suggest_force);
+        assert!(!report.error_occurred);
 
         assert!(cache.symlinks.contains_key(&PathBuf::from("a_in")));
         assert!(cache.templates.contains_key(&PathBuf::from("b_in")));
@@ -513,10 +970,11 @@ mod test {
             .returning(|_, _, _| Ok(false));
 
         // Reality
-        let (suggest_force, error_occurred) = run_deploy(
+        let report = run_deploy(
             &mut runner,
             &desired_symlinks,
             &desired_templates,
+            &BTreeMap::new(),
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -525,8 +983,8 @@ mod test {
             },
         );
 
-        assert!(suggest_force);
-        assert!(error_occurred);
+        assert!(report.suggest_force);
+        assert!(report.error_occurred);
 
         assert_eq!(cache.symlinks.len(), 0);
         assert_eq!(cache.templates.len(), 0);
@@ -548,6 +1006,8 @@ mod test {
                 PathBuf::from("a_in") => "a_out_old".into()
             },
             templates: BTreeMap::new(),
+            copies: BTreeMap::new(),
+            template_hashes: BTreeMap::new(),
         };
 
         // Expectation
@@ -565,10 +1025,11 @@ mod test {
             .returning(|_, _| Ok(true));
 
         // Reality
-        let (suggest_force, error_occurred) = run_deploy(
+        let report = run_deploy(
             &mut runner,
             &desired_symlinks,
             &BTreeMap::new(),
+            &BTreeMap::new(),
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -577,8 +1038,8 @@ mod test {
             },
         );
 
-        assert!(!suggest_force);
-        assert!(!error_occurred);
+        assert!(!report.suggest_force);
+        assert!(!report.error_occurred);
 
         assert_eq!(cache.symlinks.len(), 1);
         assert_eq!(cache.templates.len(), 0);
@@ -600,6 +1061,8 @@ mod test {
             templates: maplit::btreemap! {
                 PathBuf::from("a_in") => "a_out_old".into()
             },
+            copies: BTreeMap::new(),
+            template_hashes: BTreeMap::new(),
         };
 
         // Expectation
@@ -621,10 +1084,11 @@ mod test {
             .returning(|_, _| Ok(true));
 
         // Reality
-        let (suggest_force, error_occurred) = run_deploy(
+        let report = run_deploy(
             &mut runner,
             &desired_symlinks,
             &BTreeMap::new(),
+            &BTreeMap::new(),
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -633,8 +1097,8 @@ mod test {
             },
         );
 
-        assert!(!suggest_force);
-        assert!(!error_occurred);
+        assert!(!report.suggest_force);
+        assert!(!report.error_occurred);
 
         assert_eq!(cache.symlinks.len(), 1);
         assert_eq!(cache.templates.len(), 0);
@@ -655,6 +1119,8 @@ mod test {
             templates: maplit::btreemap! {
                 PathBuf::from("a_in") => "a_out_old".into()
             },
+            copies: BTreeMap::new(),
+            template_hashes: BTreeMap::new(),
         };
 
         // Expectation
@@ -670,10 +1136,11 @@ mod test {
             .returning(|_, _, _| Ok(false));
 
         // Reality
-        let (suggest_force, error_occurred) = run_deploy(
+        let report = run_deploy(
             &mut runner,
             &desired_symlinks,
             &BTreeMap::new(),
+            &BTreeMap::new(),
             &mut cache,
             &Options {
                 cache_directory: "cache".into(),
@@ -682,8 +1149,8 @@ mod test {
             },
         );
 
-        assert!(!suggest_force);
-        assert!(!error_occurred);
+        assert!(!report.suggest_force);
+        assert!(!report.error_occurred);
 
         assert_eq!(cache.symlinks.len(), 1);
         assert_eq!(cache.templates.len(), 0);
@@ -698,6 +1165,8 @@ mod test {
         let opt = Options::default();
         let handlebars = handlebars::Handlebars::new();
         let variables = BTreeMap::new();
+        let secrets = Secrets::default();
+        let prerendered = actions::Prerendered::new();
 
         // Expectation:
         // create_symlink
@@ -708,18 +1177,19 @@ mod test {
             .returning(|_, _| Ok(SymlinkComparison::OnlySourceExists));
         fs.expect_create_dir_all()
             .times(1)
-            .with(function(path_eq("")), eq(None)) // parent of a_out
+            .with(function(path_eq("")), eq(None), eq(None)) // parent of a_out
             .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _, _| Ok(()));
         fs.expect_make_symlink()
             .times(1)
             .with(
                 function(path_eq("a_out")),
                 function(path_eq("a_in")),
                 eq(None),
+                eq(None),
             )
             .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(()));
 
         // create_template
         fs.expect_compare_template()
@@ -732,9 +1202,9 @@ mod test {
             .returning(|_, _| Ok(TemplateComparison::BothMissing));
         fs.expect_create_dir_all()
             .times(1)
-            .with(function(path_eq("")), eq(None)) // parent of b_out
+            .with(function(path_eq("")), eq(None), eq(None)) // parent of b_out
             .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _, _| Ok(()));
         fs.expect_read_to_string()
             .times(1)
             .with(function(path_eq("b_in")))
@@ -742,15 +1212,12 @@ mod test {
             .returning(|_| Ok("Hello!".into()));
         fs.expect_create_dir_all()
             .times(1)
-            .with(function(path_eq("cache")), eq(None))
+            .with(function(path_eq("cache")), eq(None), eq(None))
             .in_sequence(&mut seq)
-            .returning(|_, _| Ok(()));
-        fs.expect_write()
+            .returning(|_, _, _| Ok(()));
+        fs.expect_write_private()
             .times(1)
-            .with(
-                function(path_eq("cache/b_cache")),
-                eq(String::from("Hello!")),
-            )
+            .with(function(path_eq("cache/b_cache")), eq(Vec::from("Hello!")))
             .in_sequence(&mut seq)
             .returning(|_, _| Ok(()));
         fs.expect_copy_file()
@@ -759,9 +1226,10 @@ mod test {
                 function(path_eq("cache/b_cache")),
                 function(path_eq("b_out")),
                 eq(None),
+                eq(None),
             )
             .in_sequence(&mut seq)
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(()));
         fs.expect_copy_permissions()
             .times(1)
             .with(
@@ -773,11 +1241,19 @@ mod test {
             .returning(|_, _, _| Ok(()));
 
         // Reality
+        let mut backups = Backups::new(&opt.backup_directory);
         let mut runner = actions::RealActionRunner::new(
             &mut fs,
+            &mut backups,
             &handlebars,
             &variables,
+            &secrets,
+            &prerendered,
+            actions::Comparisons::new(),
             opt.force,
+            opt.interactive,
+            opt.merge,
+            opt.conflict_markers,
             opt.diff_context_lines,
         );
         assert!(runner
@@ -792,6 +1268,292 @@ mod test {
             .unwrap());
     }
 
+    #[test]
+    fn modes_are_enforced_on_create_and_update() {
+        // Setup
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        let opt = Options::default();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = BTreeMap::new();
+        let secrets = Secrets::default();
+        let prerendered = actions::Prerendered::new();
+        let target = CopyTarget {
+            mode: Some(FileMode(0o600)),
+            dir_mode: Some(FileMode(0o700)),
+            ..CopyTarget::from(PathBuf::from("dir/sub/c_out"))
+        };
+
+        // Expectation:
+        // create_copy, into directories that don't exist yet
+        fs.expect_mode()
+            .times(1)
+            .with(function(path_eq("dir/sub")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(None));
+        fs.expect_mode()
+            .times(1)
+            .with(function(path_eq("dir")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(None));
+        fs.expect_compare_copy()
+            .times(1)
+            .with(function(path_eq("dir/sub/c_out")), eq("hash"))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(CopyComparison::TargetMissing));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("dir/sub")), eq(None), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_copy_file()
+            .times(1)
+            .with(
+                function(path_eq("c_in")),
+                function(path_eq("dir/sub/c_out")),
+                eq(None),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Ok(()));
+        fs.expect_copy_permissions()
+            .times(1)
+            .with(
+                function(path_eq("c_in")),
+                function(path_eq("dir/sub/c_out")),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(function(path_eq("dir")), eq(FileMode(0o700)))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(function(path_eq("dir/sub")), eq(FileMode(0o700)))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(function(path_eq("dir/sub/c_out")), eq(FileMode(0o600)))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+
+        // update_copy, which resets modes that were changed since
+        fs.expect_mode()
+            .times(1)
+            .with(function(path_eq("dir/sub")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(Some(FileMode(0o755))));
+        fs.expect_compare_copy()
+            .times(1)
+            .with(function(path_eq("dir/sub/c_out")), eq("hash"))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(CopyComparison::Identical));
+        fs.expect_set_owner()
+            .times(1)
+            .with(function(path_eq("dir/sub/c_out")), eq(None), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(function(path_eq("dir/sub")), eq(FileMode(0o700)))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(function(path_eq("dir/sub/c_out")), eq(FileMode(0o600)))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+
+        // Reality
+        let mut backups = Backups::new(&opt.backup_directory);
+        let mut runner = actions::RealActionRunner::new(
+            &mut fs,
+            &mut backups,
+            &handlebars,
+            &variables,
+            &secrets,
+            &prerendered,
+            actions::Comparisons::new(),
+            opt.force,
+            opt.interactive,
+            opt.merge,
+            opt.conflict_markers,
+            opt.diff_context_lines,
+        );
+        assert!(runner
+            .create_copy(&PathBuf::from("c_in"), &target, "hash")
+            .unwrap());
+        assert!(runner
+            .update_copy(&PathBuf::from("c_in"), &target, "hash", "hash")
+            .unwrap());
+    }
+
+    #[test]
+    fn symlink_modes_are_set_on_source() {
+        // Setup
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        let opt = Options::default();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = BTreeMap::new();
+        let secrets = Secrets::default();
+        let prerendered = actions::Prerendered::new();
+        let target = SymbolicTarget {
+            mode: Some(FileMode(0o600)),
+            ..SymbolicTarget::from(PathBuf::from("a_out"))
+        };
+
+        // Expectation:
+        // create_symlink
+        fs.expect_compare_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), function(path_eq("a_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(SymlinkComparison::OnlySourceExists));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("")), eq(None), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_make_symlink()
+            .times(1)
+            .with(
+                function(path_eq("a_out")),
+                function(path_eq("a_in")),
+                eq(None),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Ok(()));
+        fs.expect_set_mode()
+            .times(1)
+            .with(function(path_eq("a_in")), eq(FileMode(0o600)))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+
+        // update_symlink, which resets the mode if it was changed since
+        fs.expect_compare_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), function(path_eq("a_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(SymlinkComparison::Identical));
+        fs.expect_set_mode()
+            .times(1)
+            .with(function(path_eq("a_in")), eq(FileMode(0o600)))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+
+        // Reality
+        let mut backups = Backups::new(&opt.backup_directory);
+        let mut runner = actions::RealActionRunner::new(
+            &mut fs,
+            &mut backups,
+            &handlebars,
+            &variables,
+            &secrets,
+            &prerendered,
+            actions::Comparisons::new(),
+            opt.force,
+            opt.interactive,
+            opt.merge,
+            opt.conflict_markers,
+            opt.diff_context_lines,
+        );
+        assert!(runner
+            .create_symlink(&PathBuf::from("a_in"), &target)
+            .unwrap());
+        assert!(runner
+            .update_symlink(&PathBuf::from("a_in"), &target)
+            .unwrap());
+    }
+
+    #[test]
+    fn comparisons_done_ahead_are_forgotten_after_changes() {
+        // Setup
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        let opt = Options::default();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = BTreeMap::new();
+        let secrets = Secrets::default();
+        let prerendered = actions::Prerendered::new();
+        let comparisons = actions::Comparisons::from([
+            (
+                PathBuf::from("b_out"),
+                (
+                    PathBuf::from("cache/b_cache"),
+                    TemplateComparison::TargetNotRegularFile,
+                ),
+            ),
+            (
+                PathBuf::from("c_out"),
+                (
+                    PathBuf::from("cache/c_cache"),
+                    TemplateComparison::TargetNotRegularFile,
+                ),
+            ),
+        ]);
+
+        // Expectation:
+        // update_template of b uses the comparison done ahead of time
+        // delete_symlink into c's target
+        fs.expect_compare_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), function(path_eq("c_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(SymlinkComparison::BothMissing));
+        // update_template of c has to compare again
+        fs.expect_compare_template()
+            .times(1)
+            .with(
+                function(path_eq("c_out")),
+                function(path_eq("cache/c_cache")),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(TemplateComparison::TargetNotRegularFile));
+
+        // Reality
+        let mut backups = Backups::new(&opt.backup_directory);
+        let mut runner = actions::RealActionRunner::new(
+            &mut fs,
+            &mut backups,
+            &handlebars,
+            &variables,
+            &secrets,
+            &prerendered,
+            comparisons,
+            opt.force,
+            opt.interactive,
+            opt.merge,
+            opt.conflict_markers,
+            opt.diff_context_lines,
+        );
+        assert!(!runner
+            .update_template(
+                &PathBuf::from("b_in"),
+                &PathBuf::from("cache/b_cache"),
+                &PathBuf::from("b_out").into(),
+            )
+            .unwrap());
+        assert!(runner
+            .delete_symlink(&PathBuf::from("a_in"), &PathBuf::from("c_out"))
+            .unwrap());
+        assert!(!runner
+            .update_template(
+                &PathBuf::from("c_in"),
+                &PathBuf::from("cache/c_cache"),
+                &PathBuf::from("c_out").into(),
+            )
+            .unwrap());
+    }
+
     #[test]
     fn low_level_skip() {
         // Setup
@@ -801,6 +1563,8 @@ mod test {
         let opt = Options::default();
         let handlebars = handlebars::Handlebars::new();
         let variables = BTreeMap::new();
+        let secrets = Secrets::default();
+        let prerendered = actions::Prerendered::new();
 
         // Expectation:
         // create_symlink
@@ -821,11 +1585,19 @@ mod test {
             .returning(|_, _| Ok(TemplateComparison::Changed));
 
         // Reality
+        let mut backups = Backups::new(&opt.backup_directory);
         let mut runner = actions::RealActionRunner::new(
             &mut fs,
+            &mut backups,
             &handlebars,
             &variables,
+            &secrets,
+            &prerendered,
+            actions::Comparisons::new(),
             opt.force,
+            opt.interactive,
+            opt.merge,
+            opt.conflict_markers,
             opt.diff_context_lines,
         );
 
@@ -841,4 +1613,319 @@ mod test {
             )
             .unwrap());
     }
+
+    #[test]
+    fn low_level_force_backs_up() {
+        // Setup
+        let mut fs = crate::filesystem::MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        let opt = Options {
+            force: true,
+            backup_directory: "backups".into(),
+            ..Options::default()
+        };
+        let handlebars = handlebars::Handlebars::new();
+        let variables = BTreeMap::new();
+        let secrets = Secrets::default();
+        let prerendered = actions::Prerendered::new();
+
+        // Expectation:
+        // update_symlink
+        fs.expect_compare_symlink()
+            .times(1)
+            .with(function(path_eq("a_in")), function(path_eq("a_out")))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(SymlinkComparison::TargetNotSymlink));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(always(), eq(None), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        fs.expect_move_file()
+            .times(1)
+            .withf(|source, target| source == Path::new("a_out") && target.ends_with("a_out"))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_make_symlink()
+            .times(1)
+            .with(
+                function(path_eq("a_out")),
+                function(path_eq("a_in")),
+                eq(None),
+                eq(None),
+            )
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Ok(()));
+
+        // Reality
+        let mut backups = Backups::new(&opt.backup_directory);
+        let mut runner = actions::RealActionRunner::new(
+            &mut fs,
+            &mut backups,
+            &handlebars,
+            &variables,
+            &secrets,
+            &prerendered,
+            actions::Comparisons::new(),
+            opt.force,
+            opt.interactive,
+            opt.merge,
+            opt.conflict_markers,
+            opt.diff_context_lines,
+        );
+        assert!(runner
+            .update_symlink(&PathBuf::from("a_in"), &PathBuf::from("a_out").into())
+            .unwrap());
+    }
+
+    #[test]
+    fn high_level_copies_track_hashes() {
+        // Setup
+        let a_out: CopyTarget = "a_out".into();
+        let b_out: CopyTarget = "b_out".into();
+
+        let desired_copies = maplit::btreemap! {
+            PathBuf::from("a_in") => a_out.clone(),
+            PathBuf::from("b_in") => b_out.clone(),
+        };
+
+        let mut runner = actions::MockActionRunner::new();
+        let mut cache = Cache {
+            symlinks: BTreeMap::new(),
+            templates: BTreeMap::new(),
+            copies: maplit::btreemap! {
+                PathBuf::from("b_in") => CachedCopy {
+                    target: "b_out".into(),
+                    hash: "old".into(),
+                },
+            },
+            template_hashes: BTreeMap::new(),
+        };
+
+        // Expectation
+        runner
+            .expect_hash_file()
+            .with(function(path_eq("a_in")))
+            .returning(|_| Ok("a".into()));
+        runner
+            .expect_hash_file()
+            .with(function(path_eq("b_in")))
+            .returning(|_| Ok("new".into()));
+        runner
+            .expect_create_copy()
+            .times(1)
+            .with(function(path_eq("a_in")), eq(a_out), eq("a"))
+            .returning(|_, _, _| Ok(true));
+        runner
+            .expect_update_copy()
+            .times(1)
+            .with(function(path_eq("b_in")), eq(b_out), eq("old"), eq("new"))
+            .returning(|_, _, _, _| Ok(true));
+
+        // Reality
+        let report = run_deploy(
+            &mut runner,
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            &desired_copies,
+            &mut cache,
+            &Options::default(),
+        );
+
+        assert!(!report.suggest_force);
+        assert!(!report.error_occurred);
+        assert_eq!(cache.copies[Path::new("a_in")].hash, "a");
+        assert_eq!(cache.copies[Path::new("b_in")].hash, "new");
+    }
+
+    #[test]
+    fn plan_steps_without_filesystem() {
+        let cache = Cache {
+            symlinks: maplit::btreemap! {
+                PathBuf::from("kept") => PathBuf::from("kept_out"),
+                PathBuf::from("moved") => PathBuf::from("old_out"),
+            },
+            templates: maplit::btreemap! {
+                PathBuf::from("removed") => PathBuf::from("removed_out"),
+            },
+            copies: BTreeMap::new(),
+            template_hashes: BTreeMap::new(),
+        };
+        let desired_symlinks = maplit::btreemap! {
+            PathBuf::from("kept") => SymbolicTarget::from("kept_out"),
+            PathBuf::from("moved") => SymbolicTarget::from("new_out"),
+        };
+        let desired_templates = maplit::btreemap! {
+            PathBuf::from("new") => TemplateTarget::from("new_out"),
+        };
+
+        let steps = plan_steps(
+            &desired_symlinks,
+            &desired_templates,
+            &BTreeMap::new(),
+            &cache,
+        )
+        .into_iter()
+        .map(|s| (s.kind, s.source, s.target))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            vec![
+                (ActionKind::DeleteSymlink, "moved".into(), "old_out".into()),
+                (
+                    ActionKind::DeleteTemplate,
+                    "removed".into(),
+                    "removed_out".into()
+                ),
+                (ActionKind::CreateSymlink, "moved".into(), "new_out".into()),
+                (ActionKind::CreateTemplate, "new".into(), "new_out".into()),
+                (ActionKind::UpdateSymlink, "kept".into(), "kept_out".into()),
+            ]
+        );
+    }
+    #[test]
+    fn template_inputs_track_source_and_used_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a_in");
+        std::fs::write(&source, "{{name}}").unwrap();
+        let templates = maplit::btreemap! { source.clone() => TemplateTarget::from("a_out") };
+        let inputs = |variables: &Variables| {
+            template_inputs(
+                &templates,
+                variables,
+                &Secrets::default(),
+                #[cfg(feature = "scripting")]
+                &Helpers::new(),
+            )[&source]
+                .clone()
+        };
+        let mut variables = Variables::new();
+        variables.insert("name".into(), "a".into());
+        variables.insert("unused".into(), "a".into());
+
+        let (source_hash, variables_hash) = inputs(&variables);
+
+        variables.insert("unused".into(), "b".into());
+        assert_eq!(
+            inputs(&variables),
+            (source_hash.clone(), variables_hash.clone())
+        );
+
+        variables.insert("name".into(), "b".into());
+        let (changed_source_hash, changed_variables_hash) = inputs(&variables);
+        assert_eq!(changed_source_hash, source_hash);
+        assert_ne!(changed_variables_hash, variables_hash);
+
+        std::fs::write(&source, "{{name}}!").unwrap();
+        assert_ne!(inputs(&variables).0, source_hash);
+    }
+
+    #[test]
+    fn templates_using_secrets_have_no_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain");
+        let secret = dir.path().join("secret");
+        std::fs::write(&plain, "{{name}}").unwrap();
+        std::fs::write(&secret, "{{#if token}}{{name}}{{/if}}").unwrap();
+        let templates = maplit::btreemap! {
+            plain.clone() => TemplateTarget::from("plain_out"),
+            secret => TemplateTarget::from("secret_out"),
+        };
+        let variables: Variables =
+            toml::from_str("name = 'a'\n[token]\nvalue = 'hunter2'").unwrap();
+
+        let inputs = template_inputs(
+            &templates,
+            &variables,
+            &Secrets::from_values(vec!["hunter2".into()]),
+            #[cfg(feature = "scripting")]
+            &Helpers::new(),
+        );
+
+        assert_eq!(inputs.keys().collect::<Vec<_>>(), [&plain]);
+    }
+
+    #[test]
+    fn only_unchanged_renders_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let opt = Options {
+            cache_directory: dir.path().into(),
+            ..Options::default()
+        };
+        for source in ["a_in", "b_in", "c_in"] {
+            std::fs::write(dir.path().join(source), source).unwrap();
+        }
+        let inputs = maplit::btreemap! {
+            PathBuf::from("a_in") => ("a".to_string(), "a".to_string()),
+            PathBuf::from("b_in") => ("b".to_string(), "b".to_string()),
+            PathBuf::from("c_in") => ("c".to_string(), "c".to_string()),
+        };
+        let mut cache = Cache {
+            templates: inputs
+                .keys()
+                .map(|source| (source.clone(), PathBuf::from("target")))
+                .collect(),
+            ..Cache::default()
+        };
+        let prerendered = inputs
+            .keys()
+            .map(|source| (source.clone(), Ok(source.to_str().unwrap().to_string())))
+            .collect();
+        record_template_hashes(&mut cache, inputs.clone(), &prerendered, &opt);
+        assert_eq!(cache.template_hashes.len(), 3);
+
+        assert_eq!(unchanged_renders(&inputs, &cache, &opt), prerendered);
+
+        // a_in's variables and b_in's source changed, c_in's cache was changed by hand
+        let changed_inputs = maplit::btreemap! {
+            PathBuf::from("a_in") => ("a".to_string(), "changed".to_string()),
+            PathBuf::from("b_in") => ("changed".to_string(), "b".to_string()),
+            PathBuf::from("c_in") => ("c".to_string(), "c".to_string()),
+        };
+        std::fs::write(dir.path().join("c_in"), "changed").unwrap();
+        assert!(unchanged_renders(&changed_inputs, &cache, &opt).is_empty());
+    }
+
+    #[test]
+    fn only_deployed_renders_are_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let opt = Options {
+            cache_directory: dir.path().into(),
+            ..Options::default()
+        };
+        // b_in's target was changed, so its cache still holds the previous render
+        std::fs::write(dir.path().join("a_in"), "a").unwrap();
+        std::fs::write(dir.path().join("b_in"), "previous").unwrap();
+        let inputs = ["a_in", "b_in", "c_in", "d_in"]
+            .into_iter()
+            .map(|source| (PathBuf::from(source), (source.into(), source.into())))
+            .collect();
+        // c_in was skipped, so it's not in the cache, and d_in failed to render
+        let mut cache = Cache {
+            templates: maplit::btreemap! {
+                PathBuf::from("a_in") => PathBuf::from("a_out"),
+                PathBuf::from("b_in") => PathBuf::from("b_out"),
+                PathBuf::from("d_in") => PathBuf::from("d_out"),
+            },
+            ..Cache::default()
+        };
+        let prerendered = maplit::btreemap! {
+            PathBuf::from("a_in") => Ok("a".to_string()),
+            PathBuf::from("b_in") => Ok("b".to_string()),
+            PathBuf::from("c_in") => Ok("c".to_string()),
+            PathBuf::from("d_in") => Err("error".to_string()),
+        };
+
+        record_template_hashes(&mut cache, inputs, &prerendered, &opt);
+
+        assert_eq!(
+            cache.template_hashes.keys().collect::<Vec<_>>(),
+            [Path::new("a_in")]
+        );
+        assert_eq!(
+            cache.template_hashes[Path::new("a_in")].rendered,
+            filesystem::hash(b"a")
+        );
+    }
 }
@@ -6,7 +6,9 @@ use std::cmp::{max, min};
 use std::fs;
 use std::path::Path;
 
+use crate::actions::Prerendered;
 use crate::config::{TemplateTarget, Variables};
+use crate::secrets::Secrets;
 
 pub type Diff = Vec<diff::Result<String>>;
 pub type HunkDiff = Vec<(usize, usize, Diff)>;
@@ -16,10 +18,20 @@ pub fn print_template_diff(
     target: &TemplateTarget,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
     diff_context_lines: usize,
 ) {
     if log_enabled!(log::Level::Info) {
-        match generate_template_diff(source, target, handlebars, variables, true) {
+        match generate_template_diff(
+            source,
+            target,
+            handlebars,
+            variables,
+            secrets,
+            prerendered,
+            true,
+        ) {
             Ok(diff) => {
                 if diff_nonempty(&diff) {
                     info!(
@@ -46,24 +58,94 @@ pub fn generate_template_diff(
     target: &TemplateTarget,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
+    secrets: &Secrets,
+    prerendered: &Prerendered,
     source_to_target: bool,
 ) -> Result<Diff> {
-    let file_contents = fs::read_to_string(source).context("read template source file")?;
-    let file_contents = target.apply_actions(file_contents);
-    let rendered = handlebars
-        .render_template(&file_contents, variables)
-        .context("render template")?;
+    let rendered = if let Some(rendered) = prerendered.get(source) {
+        rendered
+            .clone()
+            .map_err(anyhow::Error::msg)
+            .context("render template")?
+    } else {
+        let file_contents = if target.is_encrypted() {
+            secrets.decrypt_file(source)?
+        } else {
+            fs::read_to_string(source).context("read template source file")?
+        };
+        let file_contents = target.apply_actions(file_contents);
+        handlebars
+            .render_template(&file_contents, variables)
+            .context("render template")?
+    };
 
     let target_contents =
         fs::read_to_string(&target.target).context("read template target file")?;
 
-    let diff_result = if source_to_target {
-        diff::lines(&target_contents, &rendered)
+    let diff = if source_to_target {
+        diff_strings(&target_contents, &rendered)
     } else {
-        diff::lines(&rendered, &target_contents)
+        diff_strings(&rendered, &target_contents)
     };
+    Ok(secrets.mask_diff(diff, target.is_encrypted()))
+}
 
-    Ok(diff_result.into_iter().map(to_owned_diff_result).collect())
+pub fn diff_strings(left: &str, right: &str) -> Diff {
+    diff::lines(left, right)
+        .into_iter()
+        .map(to_owned_diff_result)
+        .collect()
+}
+
+/// Combines two files into one, surrounding the lines where they differ with git-style conflict
+/// markers so they can be resolved by hand
+pub fn conflict_markers(left: &str, right: &str, left_name: &str, right_name: &str) -> String {
+    let mut output = Vec::new();
+    let mut left_lines = Vec::new();
+    let mut right_lines = Vec::new();
+
+    for line in diff::lines(left, right) {
+        match line {
+            diff::Result::Left(l) => left_lines.push(l),
+            diff::Result::Right(r) => right_lines.push(r),
+            diff::Result::Both(l, _) => {
+                push_conflict(
+                    &mut output,
+                    &mut left_lines,
+                    &mut right_lines,
+                    left_name,
+                    right_name,
+                );
+                output.push(l.to_string());
+            }
+        }
+    }
+    push_conflict(
+        &mut output,
+        &mut left_lines,
+        &mut right_lines,
+        left_name,
+        right_name,
+    );
+
+    output.join("\n")
+}
+
+fn push_conflict(
+    output: &mut Vec<String>,
+    left_lines: &mut Vec<&str>,
+    right_lines: &mut Vec<&str>,
+    left_name: &str,
+    right_name: &str,
+) {
+    if left_lines.is_empty() && right_lines.is_empty() {
+        return;
+    }
+    output.push(format!("<<<<<<< {left_name}"));
+    output.extend(left_lines.drain(..).map(String::from));
+    output.push("=======".into());
+    output.extend(right_lines.drain(..).map(String::from));
+    output.push(format!(">>>>>>> {right_name}"));
 }
 
 fn to_owned_diff_result(from: diff::Result<&str>) -> diff::Result<String> {
@@ -205,3 +287,23 @@ pub fn print_diff(diff: &[diff::Result<String>], extra_lines: usize) {
 
     print_hunk(last_hunk.0, last_hunk.1, last_hunk.2, max_possible_digits);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conflict_markers_around_changes() {
+        let merged = conflict_markers("a\nb\nc\n", "a\nB\nc\nd\n", "source", "target");
+        assert_eq!(
+            merged,
+            "a\n<<<<<<< source\nb\n=======\nB\n>>>>>>> target\nc\n\
+             <<<<<<< source\n=======\nd\n>>>>>>> target\n"
+        );
+    }
+
+    #[test]
+    fn conflict_markers_identical() {
+        assert_eq!(conflict_markers("a\nb\n", "a\nb\n", "l", "r"), "a\nb\n");
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::args::Options;
+use crate::config::{self, Files};
+use crate::deploy::{desired_files, load_patch, DesiredFiles};
+use crate::handlebars_helpers::create_new_handlebars;
+
+/// Prints the steps that led to a file being configured the way it is, and where the variables
+/// its template uses come from
+pub fn explain(opt: &Options, path: &Path) -> Result<()> {
+    let patch = load_patch(opt)?;
+    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, patch)
+        .context("get a configuration")?;
+
+    // Files that are filtered out by their condition can still be looked up by target
+    let configured = config.files.clone();
+    create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let origins = std::mem::take(&mut config.origins);
+
+    let sources = matching_sources(&configured, &origins.files, path)?;
+    if sources.is_empty() {
+        anyhow::bail!(
+            "{:?} isn't a source or a target of any file in the configuration",
+            path
+        );
+    }
+
+    let DesiredFiles {
+        symlinks: desired_symlinks,
+        templates: desired_templates,
+        copies: desired_copies,
+    } = desired_files(config.files).context("sort files into symlinks, templates and copies")?;
+
+    for (i, source) in sources.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        match configured.get(source) {
+            Some(target) => println!("{} -> {:?}", format!("{source:?}").bold(), target.path()),
+            None => println!("{}", format!("{source:?}").bold()),
+        }
+        print_steps(&origins.files[source], 1);
+
+        if let Some(target) = desired_symlinks.get(source) {
+            println!("Deployed as a {} to {:?}", "symlink".green(), target.target);
+        } else if let Some(target) = desired_templates.get(source) {
+            println!(
+                "Deployed as a {} to {:?}",
+                "template".green(),
+                target.target
+            );
+
+            let contents = std::fs::read_to_string(source).unwrap_or_default();
+            let used: Vec<_> = origins
+                .variables
+                .iter()
+                .filter(|(name, _)| contents.contains(name.as_str()))
+                .collect();
+            if !used.is_empty() {
+                println!("Variables it might use:");
+                for (name, steps) in used {
+                    println!("    {name}");
+                    print_steps(steps, 2);
+                }
+            }
+        } else if let Some(target) = desired_copies.get(source) {
+            println!("Deployed as a {} to {:?}", "copy".green(), target.target);
+        } else {
+            println!("{}", "Not deployed".dark_grey());
+        }
+    }
+
+    Ok(())
+}
+
+/// Sources that are named `path`, or whose configured target is `path`
+fn matching_sources(
+    configured: &Files,
+    origins: &BTreeMap<PathBuf, Vec<String>>,
+    path: &Path,
+) -> Result<Vec<PathBuf>> {
+    if origins.contains_key(path) {
+        return Ok(vec![path.into()]);
+    }
+
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .context("get current directory")?
+            .join(path)
+    };
+    Ok(configured
+        .iter()
+        .filter(|(_, target)| target.path() == absolute)
+        .map(|(source, _)| source.clone())
+        .collect())
+}
+
+fn print_steps(steps: &[String], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for (i, step) in steps.iter().enumerate() {
+        println!("{indent}{}. {step}", i + 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::FileTarget;
+
+    #[test]
+    fn match_by_source_or_target() {
+        let configured = Files::from([
+            (
+                PathBuf::from("bashrc"),
+                FileTarget::Automatic("/home/user/.bashrc".into()),
+            ),
+            (
+                PathBuf::from("bashrc.work"),
+                FileTarget::Automatic("/home/user/.bashrc".into()),
+            ),
+        ]);
+        let origins = BTreeMap::from([
+            (PathBuf::from("bashrc"), vec![]),
+            (PathBuf::from("bashrc.work"), vec![]),
+            (PathBuf::from("vimrc"), vec![]),
+        ]);
+
+        assert_eq!(
+            matching_sources(&configured, &origins, Path::new("vimrc")).unwrap(),
+            vec![PathBuf::from("vimrc")]
+        );
+        assert_eq!(
+            matching_sources(&configured, &origins, Path::new("/home/user/.bashrc")).unwrap(),
+            vec![PathBuf::from("bashrc"), PathBuf::from("bashrc.work")]
+        );
+        assert!(
+            matching_sources(&configured, &origins, Path::new("/etc/hosts"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -10,10 +10,42 @@ use std::path::{Path, PathBuf};
 #[cfg(unix)]
 use std::process::Command;
 
-use crate::config::UnixUser;
+use crate::config::{FileMode, UnixGroup, UnixUser};
 
 // === Serialize/deserialize files ===
 
+/// Formats that files can be written in, detected by their extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// Files with other extensions are assumed to be TOML
+    pub fn of(filename: &Path) -> Format {
+        match filename.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+}
+
+/// If `filename` doesn't exist, looks for a file with the same name in one of the other formats.
+/// Returns `filename` if none of them exist either.
+pub fn find_in_any_format(filename: &Path) -> PathBuf {
+    if filename.exists() {
+        return filename.into();
+    }
+    ["toml", "yaml", "yml", "json"]
+        .iter()
+        .map(|extension| filename.with_extension(extension))
+        .find(|f| f.exists())
+        .unwrap_or_else(|| filename.into())
+}
+
 /// Returns Ok(None) if file was not found, otherwise Ok(Some(data)) or Err
 pub fn load_file<T>(filename: &Path) -> Result<Option<T>>
 where
@@ -27,7 +59,11 @@ where
     }
     .context("open file")?;
     f.read_to_string(&mut buf).context("read file")?;
-    let data = toml::from_str::<T>(&buf).context("deserialize file contents")?;
+    let data = match Format::of(filename) {
+        Format::Toml => toml::from_str::<T>(&buf).context("deserialize file contents")?,
+        Format::Yaml => serde_yaml::from_str::<T>(&buf).context("deserialize file contents")?,
+        Format::Json => serde_json::from_str::<T>(&buf).context("deserialize file contents")?,
+    };
     Ok(Some(data))
 }
 
@@ -35,8 +71,60 @@ pub fn save_file<T>(filename: &Path, data: T) -> Result<()>
 where
     T: Serialize,
 {
-    let data = toml::to_string(&data).context("serialize data")?;
-    fs::write(filename, data).context("write to file")
+    fs::write(filename, serialize(filename, data)?).context("write to file")
+}
+
+/// Like `save_file`, but only the current user can read the file
+pub fn save_private_file<T>(filename: &Path, data: T) -> Result<()>
+where
+    T: Serialize,
+{
+    let data = serialize(filename, data)?;
+    write_atomically(filename, true, |file| {
+        io::Write::write_all(file, data.as_bytes())
+    })
+    .context("write to private file")
+}
+
+fn serialize<T>(filename: &Path, data: T) -> Result<String>
+where
+    T: Serialize,
+{
+    Ok(match Format::of(filename) {
+        Format::Toml => toml::to_string(&data).context("serialize data")?,
+        Format::Yaml => serde_yaml::to_string(&data).context("serialize data")?,
+        Format::Json => serde_json::to_string_pretty(&data).context("serialize data")? + "\n",
+    })
+}
+
+/// Like `load_file`, but keeps comments and formatting so the file can be edited and saved back.
+/// Files in other formats are converted to TOML, so they lose their comments when they're saved.
+pub fn load_document(filename: &Path) -> Result<Option<toml_edit::DocumentMut>> {
+    let contents = if Format::of(filename) == Format::Toml {
+        match fs::read_to_string(filename) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("read file"),
+        }
+    } else {
+        match load_file::<toml::Value>(filename)? {
+            Some(value) => toml::to_string(&value).context("convert file contents to toml")?,
+            None => return Ok(None),
+        }
+    };
+    let document = contents
+        .parse::<toml_edit::DocumentMut>()
+        .context("parse file contents")?;
+    Ok(Some(document))
+}
+
+pub fn save_document(filename: &Path, document: &toml_edit::DocumentMut) -> Result<()> {
+    if Format::of(filename) == Format::Toml {
+        return fs::write(filename, document.to_string()).context("write to file");
+    }
+    let value: toml::Value =
+        toml::from_str(&document.to_string()).context("convert document from toml")?;
+    save_file(filename, value)
 }
 
 // === Mockable filesystem ===
@@ -49,32 +137,64 @@ pub trait Filesystem {
     /// Check state of expected symbolic link on disk
     fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison>;
 
+    /// Check whether a copied file still has the contents with the given hash
+    fn compare_copy(&mut self, target: &Path, hash: &str) -> Result<CopyComparison>;
+
+    /// Hash the contents of a file, to detect later changes to copies of it
+    fn hash_file(&mut self, path: &Path) -> Result<String>;
+
     /// Removes a file or folder, elevating privileges if needed
     fn remove_file(&mut self, path: &Path) -> Result<()>;
 
     /// Read contents of file into a string
     fn read_to_string(&mut self, path: &Path) -> Result<String>;
 
-    /// Write string to file, without elevating privileges
-    fn write(&mut self, path: &Path, content: String) -> Result<()>;
+    /// Write contents to file, without elevating privileges
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()>;
+
+    /// Write contents to file that only the current user can read, without elevating privileges
+    fn write_private(&mut self, path: &Path, content: Vec<u8>) -> Result<()>;
 
     /// Delete parents of target file if they're empty
     fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()>;
 
-    /// Makes a symlink owned by the selected user, elevating privileges as needed
-    fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    /// Makes a symlink owned by the selected user and group, elevating privileges as needed
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()>;
 
     /// Create directory (and its parents) owned by the selected user,
-    /// elevating privileges as needed
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    /// elevating privileges as needed. The last directory gets the selected group.
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()>;
 
     /// Copy readable file to target existing location.
-    /// Target file will be owned by the selected user. Privileges elevated as needed.
-    fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    /// Target file will be owned by the selected user and group. Privileges elevated as needed.
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()>;
 
     /// If `owner.is_some`, elevates privileges and sets file to that owner
     /// If `owner.is_none`, ensures file is owned by the current user (elevating privileges if needed)
-    fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()>;
+    /// If `group.is_some`, also elevates privileges and sets file to that group
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()>;
 
     /// Copy file mode, elevating privileges as needed. (Does not change owner)
     fn copy_permissions(
@@ -83,6 +203,17 @@ pub trait Filesystem {
         target: &Path,
         owner: &Option<UnixUser>,
     ) -> Result<()>;
+
+    /// Move a file or folder to an existing directory, elevating privileges as needed.
+    /// Works across filesystems.
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()>;
+
+    /// Set the permission bits of a file, following symlinks. Elevates privileges as needed.
+    fn set_mode(&mut self, path: &Path, mode: FileMode) -> Result<()>;
+
+    /// Permission bits of a file, following symlinks.
+    /// None if the file doesn't exist or the platform doesn't have them.
+    fn mode(&mut self, path: &Path) -> Result<Option<FileMode>>;
 }
 
 // == Windows Filesystem ==
@@ -111,12 +242,15 @@ impl Filesystem for RealFilesystem {
     }
 
     fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
-        let target_state = get_file_state(target).context("get state of target")?;
-        trace!("Target state: {:#?}", target_state);
-        let cache_state = get_file_state(cache).context("get state of cache")?;
-        trace!("Cache state: {:#?}", cache_state);
+        compare_template_files(target, cache)
+    }
 
-        Ok(compare_template(target_state, cache_state))
+    fn compare_copy(&mut self, target: &Path, hash: &str) -> Result<CopyComparison> {
+        compare_copy(target, hash)
+    }
+
+    fn hash_file(&mut self, path: &Path) -> Result<String> {
+        hash_file(path)
     }
 
     fn remove_file(&mut self, path: &Path) -> Result<()> {
@@ -132,8 +266,14 @@ impl Filesystem for RealFilesystem {
         fs::read_to_string(path).context("read from file")
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
-        fs::write(path, content).context("write to file")
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        write_atomically(path, false, |file| io::Write::write_all(file, &content))
+            .context("write to file")
+    }
+
+    fn write_private(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        write_atomically(path, true, |file| io::Write::write_all(file, &content))
+            .context("write to private file")
     }
 
     fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()> {
@@ -158,7 +298,13 @@ impl Filesystem for RealFilesystem {
         Ok(())
     }
 
-    fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         use std::os::windows::fs;
 
         if let Some(owner) = owner {
@@ -167,6 +313,12 @@ impl Filesystem for RealFilesystem {
                 owner, link, target
             );
         }
+        if let Some(group) = group {
+            warn!(
+                "Ignoring `group`={:?} when creating symlink {:?} -> {:?}",
+                group, link, target
+            );
+        }
         let real_source_path = real_path(target).context("get real path of source file")?;
         if real_source_path.is_dir() {
             fs::symlink_dir(real_source_path, link)
@@ -176,31 +328,64 @@ impl Filesystem for RealFilesystem {
         .context("create symlink")
     }
 
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         if let Some(owner) = owner {
             warn!(
                 "Ignoring `owner`={:?} when creating directory {:?}",
                 owner, path
             );
         }
+        if let Some(group) = group {
+            warn!(
+                "Ignoring `group`={:?} when creating directory {:?}",
+                group, path
+            );
+        }
         std::fs::create_dir_all(path).context("create directories")
     }
 
-    fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         if let Some(owner) = owner {
             warn!(
                 "Ignoring `owner`={:?} when copying {:?} -> {:?}",
                 owner, source, target
             );
         }
-        std::fs::copy(source, target).context("copy file")?;
-        Ok(())
+        if let Some(group) = group {
+            warn!(
+                "Ignoring `group`={:?} when copying {:?} -> {:?}",
+                group, source, target
+            );
+        }
+        write_atomically(target, false, |file| {
+            io::copy(&mut File::open(source)?, file).map(|_| ())
+        })
+        .context("copy file")
     }
 
-    fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         if owner.is_some() {
             warn!("ignoring `owner` field on file {:?}", file);
         }
+        if group.is_some() {
+            warn!("ignoring `group` field on file {:?}", file);
+        }
         Ok(())
     }
 
@@ -225,6 +410,25 @@ impl Filesystem for RealFilesystem {
         )
         .context("set target permissions")
     }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        if std::fs::rename(source, target).is_ok() {
+            return Ok(());
+        }
+        // Probably on another drive
+        std::fs::copy(source, target).context("copy file to new location")?;
+        self.remove_file(source)
+            .context("remove file from old location")
+    }
+
+    fn set_mode(&mut self, path: &Path, mode: FileMode) -> Result<()> {
+        debug!("Ignoring mode {} of {:?} on Windows", mode, path);
+        Ok(())
+    }
+
+    fn mode(&mut self, path: &Path) -> Result<Option<FileMode>> {
+        get_mode(path)
+    }
 }
 
 // == Unix Filesystem ==
@@ -257,6 +461,23 @@ impl RealFilesystem {
         Command::new("sudo")
     }
 
+    fn chgrp(&mut self, path: &Path, group: &UnixGroup) -> Result<()> {
+        let success = self
+            .sudo(format!("setting group of {path:?} to \"{group}\""))
+            .arg("chgrp")
+            .arg("-h") // no-dereference
+            .arg(group.to_string())
+            .arg(path)
+            .spawn()
+            .context("spawn sudo chgrp command")?
+            .wait()
+            .context("wait for sudo chgrp command")?
+            .success();
+
+        anyhow::ensure!(success, "sudo chgrp command failed");
+        Ok(())
+    }
+
     fn is_owned_by_user(path: &Path) -> Result<bool> {
         use std::os::unix::fs::MetadataExt;
         let file_uid = path.metadata().context("get file metadata")?.uid();
@@ -275,10 +496,15 @@ impl Filesystem for RealFilesystem {
     }
 
     fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
-        let target_state = get_file_state(target).context("get state of target")?;
-        let cache_state = get_file_state(cache).context("get state of cache")?;
+        compare_template_files(target, cache)
+    }
 
-        Ok(compare_template(target_state, cache_state))
+    fn compare_copy(&mut self, target: &Path, hash: &str) -> Result<CopyComparison> {
+        compare_copy(target, hash)
+    }
+
+    fn hash_file(&mut self, path: &Path) -> Result<String> {
+        hash_file(path)
     }
 
     fn remove_file(&mut self, path: &Path) -> Result<()> {
@@ -313,8 +539,14 @@ impl Filesystem for RealFilesystem {
         fs::read_to_string(path).context("read from file")
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
-        fs::write(path, content).context("write to file")
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        write_atomically(path, false, |file| io::Write::write_all(file, &content))
+            .context("write to file")
+    }
+
+    fn write_private(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        write_atomically(path, true, |file| io::Write::write_all(file, &content))
+            .context("write to private file")
     }
 
     fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()> {
@@ -356,7 +588,13 @@ impl Filesystem for RealFilesystem {
         Ok(())
     }
 
-    fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         use std::os::unix::fs;
 
         if let Some(owner) = owner {
@@ -388,10 +626,19 @@ impl Filesystem for RealFilesystem {
             )
             .context("create symlink")?;
         }
+
+        if let Some(group) = group {
+            self.chgrp(link, group).context("set group of symlink")?;
+        }
         Ok(())
     }
 
-    fn create_dir_all(&mut self, path: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         if let Some(owner) = owner {
             let success = self
                 .sudo(format!(
@@ -413,15 +660,26 @@ impl Filesystem for RealFilesystem {
             debug!("Creating directory {:?} as current user...", path);
             std::fs::create_dir_all(path).context("create directories")?;
         }
+
+        if let Some(group) = group {
+            self.chgrp(path, group).context("set group of directory")?;
+        }
         Ok(())
     }
 
-    fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         use std::io::Write;
 
         if let Some(owner) = owner {
-            let contents = std::fs::read_to_string(source)
-                .context("read source file contents as current user")?;
+            // Unlike copies as the current user, this isn't atomic
+            let contents =
+                std::fs::read(source).context("read source file contents as current user")?;
             let mut child = self
                 .sudo(format!(
                     "Copying {source:?} -> {target:?} as user {owner:?}"
@@ -441,7 +699,7 @@ impl Filesystem for RealFilesystem {
                 .stdin
                 .as_ref()
                 .expect("has stdin")
-                .write_all(contents.as_bytes())
+                .write_all(&contents)
                 .context("give input to tee")?;
 
             let success = child.wait().context("wait for sudo tee")?.success();
@@ -449,15 +707,27 @@ impl Filesystem for RealFilesystem {
             anyhow::ensure!(success, "sudo tee failed");
         } else {
             debug!("Copying {:?} -> {:?} as current user", source, target);
-            std::fs::copy(source, target).context("copy file")?;
+            write_atomically(target, false, |file| {
+                io::copy(&mut File::open(source)?, file).map(|_| ())
+            })
+            .context("copy file")?;
         }
 
+        if let Some(group) = group {
+            self.chgrp(target, group).context("set group of target")?;
+        }
         Ok(())
     }
 
-    fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()> {
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
         if Self::is_owned_by_user(file).context("detect if file is owned by the current user")?
             && owner.is_none()
+            && group.is_none()
         {
             // Nothing to do, no need to elevate
             return Ok(());
@@ -466,11 +736,18 @@ impl Filesystem for RealFilesystem {
         let owner = owner.clone().unwrap_or(UnixUser::Name(
             std::env::var("USER").context("get USER env var")?,
         ));
+        let (ownership, chown_arg) = match group {
+            Some(group) => (
+                format!("user \"{owner}\" and group \"{group}\""),
+                format!("{}:{group}", owner.as_chown_arg()),
+            ),
+            None => (format!("user \"{owner}\""), owner.as_chown_arg()),
+        };
 
         let success = self
-            .sudo(format!("setting owner of {file:?} to user \"{owner}\""))
+            .sudo(format!("setting owner of {file:?} to {ownership}"))
             .arg("chown")
-            .arg(owner.as_chown_arg())
+            .arg(chown_arg)
             .arg("-h") // no-dereference
             .arg(file)
             .spawn()
@@ -521,17 +798,77 @@ impl Filesystem for RealFilesystem {
         }
         Ok(())
     }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        let error = match std::fs::rename(source, target) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        // `mv` takes care of moving across filesystems
+        let mut command = if error.kind() == std::io::ErrorKind::PermissionDenied {
+            let mut command = self.sudo(format!("moving {source:?} -> {target:?}"));
+            command.arg("mv");
+            command
+        } else {
+            Command::new("mv")
+        };
+        let success = command
+            .arg(source)
+            .arg(target)
+            .spawn()
+            .context("spawn mv command")?
+            .wait()
+            .context("wait for mv command")?
+            .success();
+
+        anyhow::ensure!(success, "mv command failed");
+        Ok(())
+    }
+
+    fn set_mode(&mut self, path: &Path, mode: FileMode) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if self.mode(path)? == Some(mode) {
+            return Ok(());
+        }
+
+        debug!("Setting mode of {:?} to {}", path, mode);
+        match std::fs::set_permissions(path, fs::Permissions::from_mode(mode.0)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                let success = self
+                    .sudo(format!("setting mode of {path:?} to {mode}"))
+                    .arg("chmod")
+                    .arg(mode.to_string())
+                    .arg(path)
+                    .spawn()
+                    .context("spawn sudo chmod command")?
+                    .wait()
+                    .context("wait for sudo chmod command")?
+                    .success();
+
+                anyhow::ensure!(success, "sudo chmod command failed");
+                Ok(())
+            }
+            Err(e) => Err(e).context("set permissions"),
+        }
+    }
+
+    fn mode(&mut self, path: &Path) -> Result<Option<FileMode>> {
+        get_mode(path)
+    }
 }
 
 // == Dry run Filesystem ==
 pub struct DryRunFilesystem {
     file_states: BTreeMap<PathBuf, FileState>,
+    modes: BTreeMap<PathBuf, FileMode>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 enum FileState {
-    /// None if file is invalid UTF-8
-    File(Option<String>),
+    File(Vec<u8>),
     SymbolicLink(PathBuf),
     Directory,
     Missing,
@@ -541,6 +878,7 @@ impl DryRunFilesystem {
     pub fn new() -> DryRunFilesystem {
         DryRunFilesystem {
             file_states: BTreeMap::new(),
+            modes: BTreeMap::new(),
         }
     }
 
@@ -552,6 +890,23 @@ impl DryRunFilesystem {
     }
 }
 
+/// Describes ownership as `user:group`, leaving out the parts that aren't configured
+fn ownership(owner: &Option<UnixUser>, group: &Option<UnixGroup>) -> Option<String> {
+    match (owner, group) {
+        (None, None) => None,
+        (Some(owner), None) => Some(owner.to_string()),
+        (None, Some(group)) => Some(format!(":{group}")),
+        (Some(owner), Some(group)) => Some(format!("{owner}:{group}")),
+    }
+}
+
+fn report_ownership(path: &Path, owner: &Option<UnixUser>, group: &Option<UnixGroup>) {
+    match ownership(owner, group) {
+        Some(ownership) => info!("Would set ownership of {:?} to {}", path, ownership),
+        None => debug!("{:?} would be owned by the current user", path),
+    }
+}
+
 impl Filesystem for DryRunFilesystem {
     fn compare_symlink(&mut self, source: &Path, link: &Path) -> Result<SymlinkComparison> {
         let source_state = if let Some(state) = self.file_states.get(source) {
@@ -595,6 +950,32 @@ impl Filesystem for DryRunFilesystem {
         Ok(compare_template(target_state, cache_state))
     }
 
+    fn compare_copy(&mut self, target: &Path, expected: &str) -> Result<CopyComparison> {
+        Ok(match self.file_states.get(target) {
+            Some(FileState::File(contents)) => {
+                debug!("Cached (probably not actual) target contents");
+                if hash(contents) == expected {
+                    CopyComparison::Identical
+                } else {
+                    CopyComparison::Changed
+                }
+            }
+            Some(FileState::Missing) => CopyComparison::TargetMissing,
+            Some(FileState::SymbolicLink(_) | FileState::Directory) => {
+                CopyComparison::TargetNotRegularFile
+            }
+            None => compare_copy(target, expected)?,
+        })
+    }
+
+    fn hash_file(&mut self, path: &Path) -> Result<String> {
+        match self.file_states.get(path) {
+            Some(FileState::File(contents)) => Ok(hash(contents)),
+            None => hash_file(path),
+            Some(s) => anyhow::bail!("file is not regular file but is a {:?}", s),
+        }
+    }
+
     fn remove_file(&mut self, path: &Path) -> Result<()> {
         debug!("Removing file {:?}", path);
         self.file_states.insert(path.into(), FileState::Missing);
@@ -604,15 +985,24 @@ impl Filesystem for DryRunFilesystem {
     fn read_to_string(&mut self, path: &Path) -> Result<String> {
         debug!("Reading contents of file {:?}", path);
         match self.get_state(path).context("get file state")? {
-            FileState::File(s) => Ok(s.context("invalid utf-8 in template source")?),
+            FileState::File(contents) => {
+                String::from_utf8(contents).context("invalid utf-8 in template source")
+            }
             _ => anyhow::bail!("writing to non-file"),
         }
     }
 
-    fn write(&mut self, path: &Path, content: String) -> Result<()> {
-        debug!("Writing contents {:?} to file {:?}", content, path);
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        debug!("Writing {} bytes to file {:?}", content.len(), path);
+        self.file_states
+            .insert(path.into(), FileState::File(content));
+        Ok(())
+    }
+
+    fn write_private(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        debug!("Writing {} bytes to private file {:?}", content.len(), path);
         self.file_states
-            .insert(path.into(), FileState::File(Some(content)));
+            .insert(path.into(), FileState::File(content));
         Ok(())
     }
 
@@ -624,18 +1014,28 @@ impl Filesystem for DryRunFilesystem {
         Ok(())
     }
 
-    fn make_symlink(&mut self, link: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        debug!(
-            "Making symlink {:?} -> {:?} (owned by {:?})",
-            link, target, owner
-        );
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!("Making symlink {:?} -> {:?}", link, target);
+        report_ownership(link, owner, group);
         self.file_states
             .insert(link.into(), FileState::SymbolicLink(target.into()));
         Ok(())
     }
 
-    fn create_dir_all(&mut self, mut path: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        debug!("Creating directory {:?} (owned by {:?})", path, owner);
+    fn create_dir_all(
+        &mut self,
+        mut path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!("Creating directory {:?}", path);
+        report_ownership(path, owner, group);
         self.file_states.insert(path.into(), FileState::Directory);
         while path.parent().is_some() {
             path = path.parent().unwrap();
@@ -644,11 +1044,15 @@ impl Filesystem for DryRunFilesystem {
         Ok(())
     }
 
-    fn copy_file(&mut self, source: &Path, target: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        debug!(
-            "Copying file {:?} -> {:?} (target owned by {:?})",
-            source, target, owner
-        );
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        debug!("Copying file {:?} -> {:?}", source, target);
+        report_ownership(target, owner, group);
         match self.get_state(source).context("get state of source file")? {
             FileState::File(content) => {
                 if self
@@ -669,8 +1073,13 @@ impl Filesystem for DryRunFilesystem {
         }
     }
 
-    fn set_owner(&mut self, file: &Path, owner: &Option<UnixUser>) -> Result<()> {
-        debug!("Setting owner of file {:?} to {:?}", file, owner);
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        report_ownership(file, owner, group);
         Ok(())
     }
 
@@ -686,6 +1095,28 @@ impl Filesystem for DryRunFilesystem {
         );
         Ok(())
     }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        debug!("Moving file {:?} -> {:?}", source, target);
+        let state = self.get_state(source).context("get state of source file")?;
+        anyhow::ensure!(state != FileState::Missing, "source file is missing");
+        self.file_states.insert(target.into(), state);
+        self.file_states.insert(source.into(), FileState::Missing);
+        Ok(())
+    }
+
+    fn set_mode(&mut self, path: &Path, mode: FileMode) -> Result<()> {
+        debug!("Setting mode of {:?} to {}", path, mode);
+        self.modes.insert(path.into(), mode);
+        Ok(())
+    }
+
+    fn mode(&mut self, path: &Path) -> Result<Option<FileMode>> {
+        if let Some(mode) = self.modes.get(path) {
+            return Ok(Some(*mode));
+        }
+        get_mode(path)
+    }
 }
 
 // === Comparisons ===
@@ -699,15 +1130,15 @@ fn get_file_state(path: &Path) -> Result<FileState> {
         return Ok(FileState::Directory);
     }
 
-    match fs::read_to_string(path) {
-        Ok(f) => Ok(FileState::File(Some(f))),
-        Err(e) if e.kind() == ErrorKind::InvalidData => Ok(FileState::File(None)),
+    match fs::read(path) {
+        Ok(f) => Ok(FileState::File(f)),
         Err(e) if e.kind() == ErrorKind::NotFound => Ok(FileState::Missing),
         Err(e) => Err(e).context("read contents of file that isn't symbolic or directory")?,
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SymlinkComparison {
     Identical,
     OnlySourceExists,
@@ -752,7 +1183,8 @@ fn compare_symlink(
     })
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TemplateComparison {
     Identical,
     OnlyCacheExists,
@@ -777,6 +1209,16 @@ impl std::fmt::Display for TemplateComparison {
     }
 }
 
+/// Compares a template's target with its cache on disk
+pub(crate) fn compare_template_files(target: &Path, cache: &Path) -> Result<TemplateComparison> {
+    let target_state = get_file_state(target).context("get state of target")?;
+    trace!("Target state: {:#?}", target_state);
+    let cache_state = get_file_state(cache).context("get state of cache")?;
+    trace!("Cache state: {:#?}", cache_state);
+
+    Ok(compare_template(target_state, cache_state))
+}
+
 fn compare_template(target_state: FileState, cache_state: FileState) -> TemplateComparison {
     match (target_state, cache_state) {
         (FileState::File(t), FileState::File(c)) => {
@@ -793,6 +1235,52 @@ fn compare_template(target_state: FileState, cache_state: FileState) -> Template
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyComparison {
+    Identical,
+    Changed,
+    TargetMissing,
+    TargetNotRegularFile,
+}
+
+impl std::fmt::Display for CopyComparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        use self::CopyComparison::*;
+        match self {
+            Identical => "target has the copied contents",
+            Changed => "target contents were changed",
+            TargetMissing => "target doesn't exist",
+            TargetNotRegularFile => "target already exists and isn't a regular file",
+        }
+        .fmt(f)
+    }
+}
+
+fn compare_copy(target: &Path, expected: &str) -> Result<CopyComparison> {
+    match target.symlink_metadata() {
+        Ok(metadata) if !metadata.is_file() => return Ok(CopyComparison::TargetNotRegularFile),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CopyComparison::TargetMissing),
+        Err(e) => return Err(e).context("get metadata of target"),
+    }
+    Ok(if hash_file(target)? == expected {
+        CopyComparison::Identical
+    } else {
+        CopyComparison::Changed
+    })
+}
+
+/// Hex-encoded SHA-256 of the contents
+pub fn hash(contents: &[u8]) -> String {
+    use sha2::Digest;
+    format!("{:x}", sha2::Sha256::digest(contents))
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    Ok(hash(&fs::read(path).context("read file contents")?))
+}
+
 // === Utility functions ===
 pub fn real_path(path: &Path) -> Result<PathBuf, io::Error> {
     let path = std::fs::canonicalize(path)?;
@@ -816,6 +1304,50 @@ pub fn ask_boolean(prompt: &str) -> bool {
     buf.to_lowercase().starts_with('y')
 }
 
+/// Asks until one of `choices` is typed, returning None if stdin was closed
+pub fn ask_choice(prompt: &str, choices: &[char]) -> Option<char> {
+    loop {
+        eprintln!("{prompt}");
+        let mut buf = String::new();
+        let read = io::stdin()
+            .read_line(&mut buf)
+            .expect("Failed to read line from stdin");
+        if read == 0 {
+            return None;
+        }
+        if let Some(choice) = buf.trim().to_lowercase().chars().next() {
+            if choices.contains(&choice) {
+                return Some(choice);
+            }
+        }
+    }
+}
+
+/// Opens the file in the user's `$VISUAL` or `$EDITOR` and waits for it to close
+pub fn open_editor(path: &Path) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "notepad".into()
+            } else {
+                "vi".into()
+            }
+        });
+    // Allow for editors that need arguments, like `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().context("editor command is empty")?;
+
+    let success = std::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .with_context(|| format!("spawn editor {editor:?}"))?
+        .success();
+    anyhow::ensure!(success, "editor {:?} returned error", editor);
+    Ok(())
+}
+
 pub fn is_template(source: &Path) -> Result<bool> {
     if fs::metadata(source)?.is_dir() {
         return Ok(false);
@@ -862,6 +1394,135 @@ pub fn symlinks_enabled(_test_file_path: &Path) -> Result<bool> {
     Ok(true)
 }
 
+/// Writes through an exclusively created temporary file next to `path` that's renamed over it,
+/// so that a crash never leaves a half-written file behind. `write` is called with the file to
+/// write into. The temporary file is only readable by the current user until it gets the mode and
+/// owner of the file it replaces. New files get the usual permissions, unless they're `private`.
+///
+/// Writes in place instead when renaming would change the file in other ways: when it has other
+/// hard links, when its directory isn't writable, or when its owner can't be kept.
+fn write_atomically(
+    path: &Path,
+    private: bool,
+    write: impl Fn(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    // Replacing a symlink would break it, so write to the file it points to instead
+    let path = if path.is_symlink() {
+        fs::canonicalize(path).unwrap_or_else(|_| path.into())
+    } else {
+        path.into()
+    };
+    let existing = fs::metadata(&path).ok();
+    if existing.as_ref().is_some_and(has_other_links) {
+        return write_in_place(&path, private, write);
+    }
+
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".dotter-");
+    #[cfg(unix)]
+    if existing.is_none() && !private {
+        use std::os::unix::fs::PermissionsExt;
+        // Like any new file, the umask applies
+        builder.permissions(fs::Permissions::from_mode(0o666));
+    }
+    let mut temporary = match builder.tempfile_in(directory) {
+        Ok(temporary) => temporary,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            return write_in_place(&path, private, write);
+        }
+        Err(e) => return Err(e),
+    };
+
+    write(temporary.as_file_mut())?;
+    if let Some(existing) = &existing {
+        if !keep_owner(temporary.as_file(), existing) {
+            drop(temporary);
+            return write_in_place(&path, private, write);
+        }
+        if !private {
+            temporary
+                .as_file()
+                .set_permissions(existing.permissions())?;
+        }
+    }
+    temporary.persist(&path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn write_in_place(
+    path: &Path,
+    private: bool,
+    write: impl Fn(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+        // The file might have existed with a wider mode
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    write(&mut file)
+}
+
+#[cfg(unix)]
+fn has_other_links(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() > 1
+}
+
+#[cfg(windows)]
+fn has_other_links(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Gives `file` the owner and group of `existing`. False if that isn't allowed.
+#[cfg(unix)]
+fn keep_owner(file: &File, existing: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let Ok(current) = file.metadata() else {
+        return false;
+    };
+    if current.uid() == existing.uid() && current.gid() == existing.gid() {
+        return true;
+    }
+    // SAFETY: the file descriptor stays open for the duration of the call
+    unsafe { libc::fchown(file.as_raw_fd(), existing.uid(), existing.gid()) == 0 }
+}
+
+#[cfg(windows)]
+fn keep_owner(_file: &File, _existing: &fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(unix)]
+pub(crate) fn get_mode(path: &Path) -> Result<Option<FileMode>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match path.metadata() {
+        Ok(metadata) => Ok(Some(FileMode(metadata.permissions().mode() & 0o7777))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("get metadata"),
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn get_mode(_path: &Path) -> Result<Option<FileMode>> {
+    Ok(None)
+}
+
 #[cfg(windows)]
 pub fn platform_dunce(path: &Path) -> PathBuf {
     dunce::simplified(path).into()
@@ -897,10 +1558,31 @@ mod test {
         );
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn atomic_write_keeps_mode_and_leaves_nothing_behind() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("existing");
+        std::fs::write(&existing, "old").unwrap();
+        std::fs::set_permissions(&existing, fs::Permissions::from_mode(0o640)).unwrap();
+        let private = dir.path().join("private");
+
+        let mut fs = RealFilesystem::new(true);
+        fs.write(&existing, "new".into()).unwrap();
+        write_atomically(&private, true, |file| io::Write::write_all(file, b"secret")).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "new");
+        assert_eq!(get_mode(&existing).unwrap(), Some(FileMode(0o640)));
+        assert_eq!(get_mode(&private).unwrap(), Some(FileMode(0o600)));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
     #[test]
     fn simple_create_dir_all() {
         let mut fs = DryRunFilesystem::new();
-        fs.create_dir_all(&PathBuf::from("/home/user/.config"), &None)
+        fs.create_dir_all(&PathBuf::from("/home/user/.config"), &None, &None)
             .unwrap();
         assert_eq!(
             fs.get_state(&PathBuf::from("/home")).unwrap(),
@@ -933,7 +1615,7 @@ mod test {
             TemplateComparison::BothMissing
         );
 
-        fs.create_dir_all(&PathBuf::from("target_dir"), &None)
+        fs.create_dir_all(&PathBuf::from("target_dir"), &None, &None)
             .unwrap();
 
         // perform_template_deploy
@@ -944,9 +1626,9 @@ mod test {
         let rendered = String::from("John");
 
         // cache
-        fs.create_dir_all(&PathBuf::from("cache_dir"), &None)
+        fs.create_dir_all(&PathBuf::from("cache_dir"), &None, &None)
             .unwrap();
-        fs.write(&PathBuf::from("cache_dir/cache"), rendered)
+        fs.write(&PathBuf::from("cache_dir/cache"), rendered.into())
             .unwrap();
 
         // target
@@ -954,6 +1636,7 @@ mod test {
             &PathBuf::from("cache_dir/cache"),
             &PathBuf::from("target_dir/target"),
             &None,
+            &None,
         )
         .unwrap();
         fs.copy_permissions(
@@ -966,7 +1649,7 @@ mod test {
         // Verify all actions
         assert_eq!(
             fs.file_states.get(&PathBuf::from("source")),
-            Some(&FileState::File(Some("{{name}}".into())))
+            Some(&FileState::File("{{name}}".into()))
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("cache_dir")),
@@ -974,7 +1657,7 @@ mod test {
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("cache_dir/cache")),
-            Some(&FileState::File(Some("John".into())))
+            Some(&FileState::File("John".into()))
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("target_dir")),
@@ -982,7 +1665,54 @@ mod test {
         );
         assert_eq!(
             fs.file_states.get(&PathBuf::from("target_dir/target")),
-            Some(&FileState::File(Some("John".into())))
+            Some(&FileState::File("John".into()))
+        );
+    }
+
+    #[test]
+    fn describe_ownership() {
+        let root = Some(UnixUser::Name("root".into()));
+        let wheel = Some(UnixGroup::Name("wheel".into()));
+        assert_eq!(ownership(&None, &None), None);
+        assert_eq!(ownership(&root, &None).unwrap(), "root");
+        assert_eq!(ownership(&None, &wheel).unwrap(), ":wheel");
+        assert_eq!(
+            ownership(&root, &Some(UnixGroup::Gid(10))).unwrap(),
+            "root:10"
+        );
+    }
+
+    #[test]
+    fn binary_contents_are_compared() {
+        let mut fs = DryRunFilesystem::new();
+        fs.write(&PathBuf::from("target"), vec![0xff, 0x00, 0x01])
+            .unwrap();
+        fs.write(&PathBuf::from("cache"), vec![0xff, 0x00, 0x02])
+            .unwrap();
+        assert_eq!(
+            fs.compare_template(&PathBuf::from("target"), &PathBuf::from("cache"))
+                .unwrap(),
+            TemplateComparison::Changed
+        );
+
+        let hash = fs.hash_file(&PathBuf::from("cache")).unwrap();
+        fs.create_dir_all(&PathBuf::from("target_dir"), &None, &None)
+            .unwrap();
+        fs.copy_file(
+            &PathBuf::from("cache"),
+            &PathBuf::from("target_dir/copy"),
+            &None,
+            &None,
+        )
+        .unwrap();
+        assert_eq!(
+            fs.compare_copy(&PathBuf::from("target_dir/copy"), &hash)
+                .unwrap(),
+            CopyComparison::Identical
+        );
+        assert_eq!(
+            fs.compare_copy(&PathBuf::from("target"), &hash).unwrap(),
+            CopyComparison::Changed
         );
     }
 
@@ -997,13 +1727,24 @@ mod test {
             &PathBuf::from("source"),
             &PathBuf::from("some_dir/target"),
             &None,
+            &None,
         )
         .unwrap_err();
 
         // Source isn't a file
-        fs.make_symlink(&PathBuf::from("link"), &PathBuf::from("target"), &None)
-            .unwrap();
-        fs.copy_file(&PathBuf::from("link"), &PathBuf::from("link2"), &None)
-            .unwrap_err();
+        fs.make_symlink(
+            &PathBuf::from("link"),
+            &PathBuf::from("target"),
+            &None,
+            &None,
+        )
+        .unwrap();
+        fs.copy_file(
+            &PathBuf::from("link"),
+            &PathBuf::from("link2"),
+            &None,
+            &None,
+        )
+        .unwrap_err();
     }
 }
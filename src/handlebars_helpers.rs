@@ -1,19 +1,42 @@
 use anyhow::{Context as AnyhowContext, Result};
 
+use handlebars::template::{Parameter, TemplateElement};
 use handlebars::{
     Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason,
 };
 use toml::value::{Table, Value};
 
 use std::collections::{BTreeMap, BTreeSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
 #[cfg(feature = "scripting")]
 use crate::config::Helpers;
-use crate::config::{Configuration, Files, Variables};
+use crate::config::{Configuration, Files, Origins, Variables};
 
 pub fn create_new_handlebars<'b>(config: &mut Configuration) -> Result<Handlebars<'b>> {
+    let handlebars = new_handlebars(
+        #[cfg(feature = "scripting")]
+        &config.helpers,
+    );
+
+    add_dotter_variable(&mut config.variables, &config.files, &config.packages);
+    config.origins.variable("dotter", "built into Dotter");
+    filter_files_condition(
+        &handlebars,
+        &config.variables,
+        &mut config.files,
+        &mut config.origins,
+    )
+    .context("filter files based on `if` field")?;
+    trace!("Handlebars instance: {:#?}", handlebars);
+    Ok(handlebars)
+}
+
+/// A Handlebars instance with all the helpers registered, but without the `dotter` variable
+pub(crate) fn new_handlebars<'b>(
+    #[cfg(feature = "scripting")] helpers: &Helpers,
+) -> Handlebars<'b> {
     debug!("Creating Handlebars instance...");
     let mut handlebars = Handlebars::new();
     handlebars.register_escape_fn(str::to_string); // Disable html-escaping
@@ -21,26 +44,28 @@ pub fn create_new_handlebars<'b>(config: &mut Configuration) -> Result<Handlebar
     register_rust_helpers(&mut handlebars);
 
     #[cfg(feature = "scripting")]
-    register_script_helpers(&mut handlebars, &config.helpers);
+    register_script_helpers(&mut handlebars, helpers);
 
-    add_dotter_variable(&mut config.variables, &config.files, &config.packages);
-    filter_files_condition(&handlebars, &config.variables, &mut config.files)
-        .context("filter files based on `if` field")?;
-    trace!("Handlebars instance: {:#?}", handlebars);
-    Ok(handlebars)
+    handlebars
 }
 
 fn filter_files_condition(
     handlebars: &Handlebars<'_>,
     variables: &Variables,
     files: &mut Files,
+    origins: &mut Origins,
 ) -> Result<()> {
     let filtered = std::mem::take(files)
         .into_iter()
         .map(|(source, target)| -> Result<Option<_>> {
             let condition = target.condition();
             Ok(if let Some(condition) = condition {
-                if eval_condition(handlebars, variables, condition).context("")? {
+                let result = eval_condition(handlebars, variables, condition).context("")?;
+                origins.file(
+                    &source,
+                    format!("condition {condition:?} evaluated to {result}"),
+                );
+                if result {
                     Some((source, target))
                 } else {
                     None
@@ -57,7 +82,7 @@ fn filter_files_condition(
     Ok(())
 }
 
-fn eval_condition(
+pub(crate) fn eval_condition(
     handlebars: &Handlebars<'_>,
     variables: &Variables,
     condition: &str,
@@ -70,6 +95,108 @@ fn eval_condition(
     Ok(rendered == "true")
 }
 
+/// Helpers whose output depends on more than their parameters
+const VOLATILE_HELPERS: &[&str] = &[
+    "include_template",
+    "is_executable",
+    "command_success",
+    "command_output",
+];
+
+/// Finds the top-level variables that a template uses. Returns `None` if its render can depend on
+/// anything else, like the output of a command, or if it can't tell.
+pub(crate) fn template_variables(
+    template: &str,
+    #[cfg(feature = "scripting")] helpers: &Helpers,
+) -> Option<BTreeSet<String>> {
+    let is_volatile = |name: &str| {
+        #[cfg(feature = "scripting")]
+        if helpers.contains_key(name) {
+            return true;
+        }
+        VOLATILE_HELPERS.contains(&name)
+    };
+    let template = handlebars::Template::compile(template).ok()?;
+    let mut variables = BTreeSet::new();
+    collect_variables(&template.elements, &is_volatile, &mut variables)?;
+    Some(variables)
+}
+
+fn collect_variables(
+    elements: &[TemplateElement],
+    is_volatile: &dyn Fn(&str) -> bool,
+    variables: &mut BTreeSet<String>,
+) -> Option<()> {
+    for element in elements {
+        collect_element_variables(element, is_volatile, variables)?;
+    }
+    Some(())
+}
+
+fn collect_element_variables(
+    element: &TemplateElement,
+    is_volatile: &dyn Fn(&str) -> bool,
+    variables: &mut BTreeSet<String>,
+) -> Option<()> {
+    let helper = match element {
+        TemplateElement::RawString(_) | TemplateElement::Comment(_) => return Some(()),
+        TemplateElement::Expression(helper)
+        | TemplateElement::HtmlExpression(helper)
+        | TemplateElement::HelperBlock(helper) => helper,
+        // Partials and decorators can pull in anything
+        _ => return None,
+    };
+
+    if let Parameter::Name(name) = &helper.name {
+        if is_volatile(name) {
+            return None;
+        }
+    }
+    for parameter in std::iter::once(&helper.name)
+        .chain(&helper.params)
+        .chain(helper.hash.values())
+    {
+        match parameter {
+            // Either a helper or a variable
+            Parameter::Name(name) => {
+                variables.insert(name.clone());
+            }
+            Parameter::Path(handlebars::Path::Relative((_, raw))) => {
+                variables.insert(root_variable(raw)?.to_string());
+            }
+            Parameter::Path(handlebars::Path::Local(_)) | Parameter::Literal(_) => {}
+            Parameter::Subexpression(subexpression) => {
+                collect_element_variables(&subexpression.element, is_volatile, variables)?;
+            }
+            _ => return None,
+        }
+    }
+    for template in helper.template.iter().chain(&helper.inverse) {
+        collect_variables(&template.elements, is_volatile, variables)?;
+    }
+    Some(())
+}
+
+/// The first segment of a path like `../foo.bar`, or `None` if it's the whole context
+fn root_variable(mut path: &str) -> Option<&str> {
+    while let Some(rest) = path
+        .strip_prefix("../")
+        .or_else(|| path.strip_prefix("./"))
+        .or_else(|| path.strip_prefix("@root."))
+        .or_else(|| path.strip_prefix("@root/"))
+        .or_else(|| path.strip_prefix("this."))
+        .or_else(|| path.strip_prefix("this/"))
+    {
+        path = rest;
+    }
+    let root = path.split(['.', '/']).next().unwrap_or_default();
+    if root.is_empty() || root == "this" || root.starts_with('@') {
+        None
+    } else {
+        Some(root.trim_start_matches('[').trim_end_matches(']'))
+    }
+}
+
 fn math_helper(
     h: &Helper<'_>,
     _: &Handlebars<'_>,
@@ -326,6 +453,15 @@ fn add_dotter_variable(
         ),
     );
     dotter.insert("files".into(), files_as_toml(files));
+    dotter.extend(host_variables());
+
+    variables.insert("dotter".into(), dotter.into());
+}
+
+/// The parts of the `dotter` variable that only depend on the machine, which are
+/// known before the packages are
+pub(crate) fn host_variables() -> Table {
+    let mut dotter = Table::new();
     dotter.insert(
         "os".into(),
         (if cfg!(windows) { "windows" } else { "unix" }).into(),
@@ -348,7 +484,82 @@ fn add_dotter_variable(
         warn!("Failed to get hostname, skipping dotter.hostname variable");
     }
 
-    variables.insert("dotter".into(), dotter.into());
+    // More specific than `os`: linux, macos, freebsd, windows...
+    dotter.insert("os_family".into(), std::env::consts::OS.into());
+    dotter.insert("arch".into(), std::env::consts::ARCH.into());
+    let os_release = ["/etc/os-release", "/usr/lib/os-release"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok());
+    if let Some(os_release) = os_release {
+        let (distro, version) = parse_os_release(&os_release);
+        if let Some(distro) = distro {
+            dotter.insert("distro".into(), distro.into());
+        }
+        if let Some(version) = version {
+            dotter.insert("distro_version".into(), version.into());
+        }
+    }
+
+    let username = ["USER", "LOGNAME", "USERNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok());
+    #[cfg(unix)]
+    let username = username.or_else(passwd_username);
+    if let Some(username) = username {
+        dotter.insert("username".into(), username.into());
+    }
+    let home = shellexpand::tilde("~");
+    if home != "~" {
+        dotter.insert("home".into(), home.to_string().into());
+    }
+    #[cfg(unix)]
+    dotter.insert(
+        "uid".into(),
+        Value::Integer(unsafe { libc::geteuid() }.into()),
+    );
+    if let Ok(shell) = std::env::var("SHELL") {
+        dotter.insert("shell".into(), shell.into());
+    }
+
+    let is_wsl = cfg!(target_os = "linux")
+        && (std::env::var_os("WSL_DISTRO_NAME").is_some()
+            || std::fs::read_to_string("/proc/sys/kernel/osrelease")
+                .map(|release| release.to_lowercase().contains("microsoft"))
+                .unwrap_or(false));
+    dotter.insert("is_wsl".into(), is_wsl.into());
+    let is_container = std::env::var_os("container").is_some()
+        || Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists();
+    dotter.insert("is_container".into(), is_container.into());
+
+    dotter
+}
+
+/// Looks up the name of the current user, for when it's not in the environment
+#[cfg(unix)]
+fn passwd_username() -> Option<String> {
+    // SAFETY: the returned entry is only read before any other passwd function is called
+    unsafe {
+        let passwd = libc::getpwuid(libc::geteuid());
+        if passwd.is_null() {
+            return None;
+        }
+        std::ffi::CStr::from_ptr((*passwd).pw_name)
+            .to_str()
+            .ok()
+            .map(str::to_string)
+    }
+}
+
+/// Gets the `ID` and `VERSION_ID` fields of an os-release file
+fn parse_os_release(contents: &str) -> (Option<String>, Option<String>) {
+    let field = |name: &str| {
+        contents.lines().find_map(|line| {
+            let value = line.trim().strip_prefix(name)?.strip_prefix('=')?;
+            Some(value.trim_matches(|c| c == '"' || c == '\'').to_string())
+        })
+    };
+    (field("ID"), field("VERSION_ID"))
 }
 
 #[cfg(test)]
@@ -367,6 +578,7 @@ mod test {
             packages: maplit::btreemap! { "default".into() => true, "disabled".into() => false },
             recurse: true,
             settings: Settings::default(),
+            origins: Origins::default(),
         };
         let handlebars = create_new_handlebars(&mut config).unwrap();
 
@@ -394,6 +606,7 @@ mod test {
             packages: BTreeMap::new(),
             recurse: true,
             settings: Settings::default(),
+            origins: Origins::default(),
         };
         let handlebars = create_new_handlebars(&mut config).unwrap();
 
@@ -407,4 +620,44 @@ mod test {
             eval_condition(&handlebars, &config.variables, "(eq (math \"5+5\") \"10\")").unwrap()
         );
     }
+
+    #[test]
+    fn parse_distro_from_os_release() {
+        let os_release = r#"
+NAME="Ubuntu"
+VERSION_ID="22.04"
+ID=ubuntu
+ID_LIKE=debian
+"#;
+        assert_eq!(
+            parse_os_release(os_release),
+            (Some("ubuntu".into()), Some("22.04".into()))
+        );
+        assert_eq!(parse_os_release("NAME=Arch\n"), (None, None));
+    }
+
+    #[test]
+    fn variables_used_by_template() {
+        let variables = |template| {
+            template_variables(
+                template,
+                #[cfg(feature = "scripting")]
+                &Helpers::new(),
+            )
+        };
+
+        assert_eq!(
+            variables(
+                "{{name}} {{#if (eq dotter.os \"unix\")}}{{#each fonts}}{{this.size}} {{../theme.bg}}{{/each}}{{else}}{{math size \"+\" 1}}{{/if}}"
+            ),
+            Some(
+                ["dotter", "eq", "fonts", "if", "math", "name", "size", "theme", "each"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+        assert_eq!(variables("{{command_output \"date\"}}"), None);
+        assert_eq!(variables("{{#if ok}}{{this}}{{/if}}"), None);
+    }
 }
@@ -5,7 +5,9 @@ use std::path::Path;
 use std::process::Child;
 use std::process::Command;
 
+use crate::actions::Prerendered;
 use crate::filesystem::{Filesystem, RealFilesystem};
+use crate::secrets::Secrets;
 
 pub(crate) fn run_hook(
     location: &Path,
@@ -32,6 +34,9 @@ pub(crate) fn run_hook(
         &mut fs,
         handlebars,
         variables,
+        // Scripts can't be encrypted, and their variables were decrypted already
+        &Secrets::default(),
+        &Prerendered::new(),
     )
     .context("deploy script")?;
     fs.copy_permissions(location, &script_file, &None)
@@ -3,16 +3,17 @@ use anyhow::{Context, Result};
 use std::collections::BTreeMap;
 
 use crate::args::Options;
+use crate::cache;
 use crate::config;
-use crate::filesystem::save_file;
+use crate::filesystem::find_in_any_format;
 
 pub fn init(opt: Options) -> Result<()> {
     info!("Looking for existing configuration...");
-    if opt.global_config.exists() {
+    if find_in_any_format(&opt.global_config).exists() {
         if opt.force {
-            warn!("Configuration already exists. Overwriting because of --force");
+            warn!("Configuration already exists. Adding the files to it because of --force");
         } else {
-            anyhow::bail!("Configuration already exists. Use --force to overwrite.");
+            anyhow::bail!("Configuration already exists. Use --force to add the files to it.");
         }
     } else {
         info!("No existing configuration.");
@@ -38,11 +39,13 @@ pub fn init(opt: Options) -> Result<()> {
         .context("save dummy config")?;
 
     debug!("Emptying cache...");
-    save_file(
+    cache::save(
         &opt.cache_file,
-        config::Cache {
+        &config::Cache {
             symlinks: BTreeMap::default(),
             templates: BTreeMap::default(),
+            copies: BTreeMap::default(),
+            template_hashes: BTreeMap::default(),
         },
     )
     .context("save empty cache file")?;
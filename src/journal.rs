@@ -0,0 +1,595 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::args::Options;
+use crate::config::{FileMode, UnixGroup, UnixUser};
+use crate::display_error;
+use crate::filesystem::{self, CopyComparison, Filesystem, RealFilesystem};
+use crate::filesystem::{SymlinkComparison, TemplateComparison};
+
+/// Previous state of every file a deploy changed, in the order they were first changed
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+struct JournalFile {
+    /// Whether the deploy got to the end. Only failed deploys keep their journal
+    complete: bool,
+    #[serde(default)]
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    path: PathBuf,
+    prior: Prior,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+enum Prior {
+    /// Nothing was there
+    Missing,
+    /// A file, whose contents were copied into the journal directory
+    File {
+        stored: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<FileMode>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<u32>,
+    },
+    /// A file that the current user can't read, so its contents can't be restored
+    Unreadable,
+    Symlink {
+        points_to: PathBuf,
+    },
+    /// A directory. Only its mode is restored, not its contents
+    Directory {
+        mode: Option<FileMode>,
+    },
+    /// The file was moved away, for example into a backup
+    MovedTo {
+        moved: PathBuf,
+    },
+}
+
+/// Records the state of files right before a deploy changes them for the first time, and saves
+/// it after every change so that an interrupted deploy can be rolled back too.
+#[derive(Debug)]
+pub struct Journal {
+    directory: PathBuf,
+    entries: Vec<Entry>,
+    journaled: BTreeSet<PathBuf>,
+}
+
+impl Journal {
+    /// Starts the journal of a new deploy, discarding the one of the previous deploy.
+    /// Fails if the previous deploy was interrupted, unless --force is used.
+    pub fn begin(opt: &Options) -> Result<Journal> {
+        let directory = opt.journal_directory.clone();
+        if let Some(previous) = filesystem::load_file::<JournalFile>(&journal_file(&directory))
+            .context("load journal of previous deploy")?
+        {
+            if !previous.complete {
+                if !opt.force {
+                    anyhow::bail!(
+                        "the previous deploy was interrupted before it finished. Use `dotter rollback` to undo it, or --force to keep its changes"
+                    );
+                }
+                warn!("Keeping the changes of the interrupted previous deploy because of --force.");
+            }
+            remove_directory(&directory).context("remove journal of previous deploy")?;
+        }
+
+        Ok(Journal {
+            directory,
+            entries: Vec::new(),
+            journaled: BTreeSet::new(),
+        })
+    }
+
+    /// Records the current state of `path` unless it was already changed during this deploy
+    pub fn record(&mut self, path: &Path) -> Result<()> {
+        if self.journaled.contains(path) {
+            return Ok(());
+        }
+
+        let prior = match path.symlink_metadata() {
+            Err(e) if e.kind() == ErrorKind::NotFound => Prior::Missing,
+            Err(e) => return Err(e).with_context(|| format!("get metadata of {path:?}")),
+            Ok(metadata) if metadata.is_symlink() => {
+                // Writes to a symlink go through to the file it points to
+                if let Ok(destination) = std::fs::canonicalize(path) {
+                    if destination.is_file() {
+                        self.record(&destination)?;
+                    }
+                }
+                Prior::Symlink {
+                    points_to: std::fs::read_link(path).context("read symlink")?,
+                }
+            }
+            Ok(metadata) if metadata.is_dir() => Prior::Directory {
+                mode: filesystem::get_mode(path)?,
+            },
+            Ok(metadata) => {
+                let files = self.directory.join("files");
+                create_private_directory(&files).context("create journal directory")?;
+                let stored = files.join(self.entries.len().to_string());
+                match copy_privately(path, &stored) {
+                    Ok(()) => {
+                        let (owner, group) = ownership(&metadata);
+                        Prior::File {
+                            stored,
+                            mode: filesystem::get_mode(path)?,
+                            owner,
+                            group,
+                        }
+                    }
+                    // Targets owned by other users are written with sudo
+                    Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                        warn!(
+                            "Can't read {:?} as the current user, so rolling back won't restore its contents.",
+                            path
+                        );
+                        Prior::Unreadable
+                    }
+                    Err(e) => {
+                        return Err(e).with_context(|| format!("copy {path:?} into the journal"))
+                    }
+                }
+            }
+        };
+        self.push(path, prior)
+    }
+
+    fn push(&mut self, path: &Path, prior: Prior) -> Result<()> {
+        self.journaled.insert(path.into());
+        self.entries.push(Entry {
+            path: path.into(),
+            prior,
+        });
+        self.save(false)
+    }
+
+    fn save(&self, complete: bool) -> Result<()> {
+        create_private_directory(&self.directory).context("create journal directory")?;
+        let journal = JournalFile {
+            complete,
+            entries: self.entries.clone(),
+        };
+        filesystem::save_file(&journal_file(&self.directory), journal).context("save journal")
+    }
+
+    /// Keeps the journal around for `dotter rollback` if the deploy failed, otherwise deletes it
+    pub fn finish(self, failed: bool) -> Result<()> {
+        if failed && !self.entries.is_empty() {
+            self.save(true)?;
+            info!("To undo this deploy, use `dotter rollback`.");
+            Ok(())
+        } else {
+            remove_directory(&self.directory).context("remove journal")
+        }
+    }
+
+    /// Puts every file back the way it was before the deploy, newest change first.
+    /// Returns true if some files couldn't be restored, which are then kept in the journal.
+    pub fn rollback(mut self, fs: &mut dyn Filesystem) -> Result<bool> {
+        let mut failed = Vec::new();
+        while let Some(entry) = self.entries.pop() {
+            if let Err(e) = restore(fs, &entry) {
+                display_error(e.context(format!("restore {:?}", entry.path)));
+                failed.push(entry);
+            }
+        }
+
+        if failed.is_empty() {
+            remove_directory(&self.directory).context("remove journal")?;
+            return Ok(false);
+        }
+        failed.reverse();
+        self.entries = failed;
+        // Files that couldn't be restored shouldn't be forgotten by the next deploy
+        self.save(false)?;
+        Ok(true)
+    }
+}
+
+fn journal_file(directory: &Path) -> PathBuf {
+    directory.join("journal.toml")
+}
+
+/// The journal holds copies of targets, which can contain decrypted secrets
+fn create_private_directory(directory: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        builder.mode(0o700).create(directory)?;
+        std::fs::set_permissions(directory, std::fs::Permissions::from_mode(0o700))
+    }
+    #[cfg(windows)]
+    builder.create(directory)
+}
+
+fn copy_privately(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut source = std::fs::File::open(from)?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::copy(&mut source, &mut options.open(to)?)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn ownership(metadata: &std::fs::Metadata) -> (Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(metadata.uid()), Some(metadata.gid()))
+}
+
+#[cfg(windows)]
+fn ownership(_metadata: &std::fs::Metadata) -> (Option<u32>, Option<u32>) {
+    (None, None)
+}
+
+/// Gives `path` back its owner and group, elevating privileges only if they changed
+fn restore_ownership(
+    fs: &mut dyn Filesystem,
+    path: &Path,
+    owner: Option<u32>,
+    group: Option<u32>,
+) -> Result<()> {
+    let (Some(owner), Some(group)) = (owner, group) else {
+        return Ok(());
+    };
+    let metadata = path.symlink_metadata().context("get metadata")?;
+    if ownership(&metadata) == (Some(owner), Some(group)) {
+        return Ok(());
+    }
+    fs.set_owner(
+        path,
+        &Some(UnixUser::Uid(owner as i32)),
+        &Some(UnixGroup::Gid(group as i32)),
+    )
+}
+
+fn remove_directory(directory: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(directory) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context("remove directory"),
+    }
+}
+
+fn restore(fs: &mut dyn Filesystem, entry: &Entry) -> Result<()> {
+    let path = &entry.path;
+    let exists = path.symlink_metadata().is_ok();
+    match &entry.prior {
+        Prior::Missing => {
+            if !exists {
+                return Ok(());
+            }
+            if path.is_dir() && !path.is_symlink() && path.read_dir()?.next().is_some() {
+                warn!(
+                    "Not removing directory {:?} that was created by the deploy because it isn't empty.",
+                    path
+                );
+                return Ok(());
+            }
+            debug!("Removing {:?}", path);
+            fs.remove_file(path)
+                .context("remove file created by deploy")
+        }
+        Prior::File {
+            stored,
+            mode,
+            owner,
+            group,
+        } => {
+            debug!("Restoring contents of {:?}", path);
+            if exists {
+                fs.remove_file(path)
+                    .context("remove file changed by deploy")?;
+            }
+            fs.move_file(stored, path)
+                .context("move previous contents back from journal")?;
+            restore_ownership(fs, path, *owner, *group).context("restore owner")?;
+            if let Some(mode) = mode {
+                fs.set_mode(path, *mode).context("restore mode")?;
+            }
+            Ok(())
+        }
+        Prior::Unreadable => {
+            warn!(
+                "Not restoring the contents of {:?} because they couldn't be read before the deploy changed them.",
+                path
+            );
+            Ok(())
+        }
+        Prior::Symlink { points_to } => {
+            debug!("Restoring symlink {:?} -> {:?}", path, points_to);
+            if exists {
+                fs.remove_file(path)
+                    .context("remove file changed by deploy")?;
+            }
+            let parent = path.parent().context("get parent of symlink")?;
+            fs.make_symlink(path, &parent.join(points_to), &None, &None)
+                .context("recreate symlink")
+        }
+        Prior::Directory { mode } => {
+            if !exists {
+                debug!("Recreating directory {:?}", path);
+                fs.create_dir_all(path, &None, &None)
+                    .context("recreate directory")?;
+            }
+            if let Some(mode) = mode {
+                fs.set_mode(path, *mode)
+                    .context("restore mode of directory")?;
+            }
+            Ok(())
+        }
+        Prior::MovedTo { moved } => {
+            if moved.symlink_metadata().is_err() {
+                // The move never happened
+                return Ok(());
+            }
+            debug!("Moving {:?} back to {:?}", moved, path);
+            if exists {
+                fs.remove_file(path)
+                    .context("remove file created by deploy")?;
+            }
+            fs.move_file(moved, path).context("move file back")
+        }
+    }
+}
+
+/// Undoes the last deploy if it left a journal behind.
+/// Returns true if some files couldn't be restored.
+pub fn rollback(opt: &Options) -> Result<bool> {
+    let Some(journal) = filesystem::load_file::<JournalFile>(&journal_file(&opt.journal_directory))
+        .context("load journal")?
+    else {
+        info!("Nothing to roll back: the last deploy finished without errors.");
+        return Ok(false);
+    };
+
+    if opt.dry_run {
+        for entry in journal.entries.iter().rev() {
+            info!("Would restore {:?} to {:?}", entry.path, entry.prior);
+        }
+        return Ok(false);
+    }
+
+    if !journal.complete {
+        info!("Rolling back the interrupted deploy...");
+    }
+    let journal = Journal {
+        directory: opt.journal_directory.clone(),
+        journaled: journal.entries.iter().map(|e| e.path.clone()).collect(),
+        entries: journal.entries,
+    };
+    let failed = journal
+        .rollback(&mut RealFilesystem::new(opt.noconfirm))
+        .context("restore files")?;
+    if !failed {
+        info!("Rolled back the last deploy.");
+    }
+    Ok(failed)
+}
+
+/// Records every file in the journal before passing changes on to the wrapped filesystem
+pub struct JournalingFilesystem<'a> {
+    inner: &'a mut dyn Filesystem,
+    journal: &'a mut Journal,
+}
+
+impl<'a> JournalingFilesystem<'a> {
+    pub fn new(inner: &'a mut dyn Filesystem, journal: &'a mut Journal) -> Self {
+        JournalingFilesystem { inner, journal }
+    }
+}
+
+impl Filesystem for JournalingFilesystem<'_> {
+    fn compare_symlink(&mut self, source: &Path, link: &Path) -> Result<SymlinkComparison> {
+        self.inner.compare_symlink(source, link)
+    }
+
+    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
+        self.inner.compare_template(target, cache)
+    }
+
+    fn compare_copy(&mut self, target: &Path, hash: &str) -> Result<CopyComparison> {
+        self.inner.compare_copy(target, hash)
+    }
+
+    fn hash_file(&mut self, path: &Path) -> Result<String> {
+        self.inner.hash_file(path)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.journal.record(path)?;
+        self.inner.remove_file(path)
+    }
+
+    fn read_to_string(&mut self, path: &Path) -> Result<String> {
+        self.inner.read_to_string(path)
+    }
+
+    fn write(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        self.journal.record(path)?;
+        self.inner.write(path, content)
+    }
+
+    fn write_private(&mut self, path: &Path, content: Vec<u8>) -> Result<()> {
+        self.journal.record(path)?;
+        self.inner.write_private(path, content)
+    }
+
+    fn delete_parents(&mut self, path: &Path, no_ask: bool) -> Result<()> {
+        // Same walk as the real deletion: up until the first directory with something else in it
+        let mut child = path;
+        for directory in path.ancestors().skip(1) {
+            if !directory.is_dir() {
+                break;
+            }
+            let mut entries = directory.read_dir().context("read directory")?;
+            if entries.any(|e| e.map_or(true, |e| e.path() != child)) {
+                break;
+            }
+            self.journal.record(directory)?;
+            child = directory;
+        }
+        self.inner.delete_parents(path, no_ask)
+    }
+
+    fn make_symlink(
+        &mut self,
+        link: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        self.journal.record(link)?;
+        self.inner.make_symlink(link, target, owner, group)
+    }
+
+    fn create_dir_all(
+        &mut self,
+        path: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        let missing: Vec<_> = path
+            .ancestors()
+            .take_while(|a| !a.as_os_str().is_empty() && a.symlink_metadata().is_err())
+            .collect();
+        // Outermost first, so the rollback removes the innermost first
+        for directory in missing.into_iter().rev() {
+            self.journal.record(directory)?;
+        }
+        self.inner.create_dir_all(path, owner, group)
+    }
+
+    fn copy_file(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        self.journal.record(target)?;
+        self.inner.copy_file(source, target, owner, group)
+    }
+
+    fn set_owner(
+        &mut self,
+        file: &Path,
+        owner: &Option<UnixUser>,
+        group: &Option<UnixGroup>,
+    ) -> Result<()> {
+        self.journal.record(file)?;
+        self.inner.set_owner(file, owner, group)
+    }
+
+    fn copy_permissions(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        owner: &Option<UnixUser>,
+    ) -> Result<()> {
+        self.journal.record(target)?;
+        self.inner.copy_permissions(source, target, owner)
+    }
+
+    fn move_file(&mut self, source: &Path, target: &Path) -> Result<()> {
+        self.journal.record(target)?;
+        if !self.journal.journaled.contains(source) {
+            self.journal.push(
+                source,
+                Prior::MovedTo {
+                    moved: target.into(),
+                },
+            )?;
+        }
+        self.inner.move_file(source, target)
+    }
+
+    fn set_mode(&mut self, path: &Path, mode: FileMode) -> Result<()> {
+        self.journal.record(path)?;
+        self.inner.set_mode(path, mode)
+    }
+
+    fn mode(&mut self, path: &Path) -> Result<Option<FileMode>> {
+        self.inner.mode(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn rollback_restores_previous_state() {
+        let temporary = tempfile::tempdir().unwrap();
+        let dir = temporary.path();
+        let changed = dir.join("changed");
+        let removed = dir.join("removed");
+        let created = dir.join("new/nested/created");
+        std::fs::write(&changed, "old").unwrap();
+        std::fs::write(&removed, "removed").unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(&changed, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut journal = Journal {
+            directory: dir.join("journal"),
+            entries: Vec::new(),
+            journaled: BTreeSet::new(),
+        };
+        let mut real_fs = RealFilesystem::new(true);
+        let mut fs = JournalingFilesystem::new(&mut real_fs, &mut journal);
+        fs.write(&changed, b"new".to_vec()).unwrap();
+        fs.set_mode(&changed, FileMode(0o600)).unwrap();
+        fs.write(&changed, b"newer".to_vec()).unwrap();
+        fs.remove_file(&removed).unwrap();
+        fs.create_dir_all(created.parent().unwrap(), &None, &None)
+            .unwrap();
+        fs.write(&created, b"created".to_vec()).unwrap();
+
+        // Saved after every change, in case the deploy gets interrupted
+        let saved: JournalFile = filesystem::load_file(&journal_file(&dir.join("journal")))
+            .unwrap()
+            .unwrap();
+        assert!(!saved.complete);
+        assert_eq!(saved.entries, journal.entries);
+        #[cfg(unix)]
+        assert_eq!(
+            filesystem::get_mode(&dir.join("journal")).unwrap(),
+            Some(FileMode(0o700))
+        );
+        assert!(!journal.rollback(&mut RealFilesystem::new(true)).unwrap());
+
+        assert_eq!(std::fs::read_to_string(&changed).unwrap(), "old");
+        #[cfg(unix)]
+        assert_eq!(
+            filesystem::get_mode(&changed).unwrap(),
+            Some(FileMode(0o640))
+        );
+        assert_eq!(std::fs::read_to_string(&removed).unwrap(), "removed");
+        assert!(!dir.join("new").exists());
+        assert!(!dir.join("journal").exists());
+    }
+}
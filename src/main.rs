@@ -2,14 +2,26 @@
 extern crate log;
 
 mod actions;
+mod adopt;
 mod args;
+mod backup;
+mod cache;
 mod config;
 mod deploy;
 mod difference;
+mod explain;
 mod filesystem;
 mod handlebars_helpers;
 mod hooks;
 mod init;
+mod journal;
+mod merge;
+mod packages;
+mod plan;
+mod pull;
+mod report;
+mod secrets;
+mod status;
 #[cfg(feature = "watch")]
 mod watch;
 
@@ -71,7 +83,12 @@ fn run() -> Result<bool> {
             .set_level_padding(simplelog::LevelPadding::Left)
             .add_filter_allow("dotter".into())
             .build(),
-        simplelog::TerminalMode::Mixed,
+        if opt.output == args::OutputFormat::Json {
+            // Keep stdout clean for the JSON records
+            simplelog::TerminalMode::Stderr
+        } else {
+            simplelog::TerminalMode::Mixed
+        },
         simplelog::ColorChoice::Auto,
     )
     .unwrap();
@@ -82,7 +99,8 @@ fn run() -> Result<bool> {
     {
         use std::os::unix::prelude::MetadataExt;
         if std::env::var("USER").unwrap_or_default() == "root"
-            && !std::fs::metadata(&opt.global_config).is_ok_and(|m| m.uid() == 0)
+            && !std::fs::metadata(filesystem::find_in_any_format(&opt.global_config))
+                .is_ok_and(|m| m.uid() == 0)
         {
             warn!("It is not recommended to run Dotter as root, since the cache files and all files not marked with an `owner` field will default to being owned by root.
 If you're truly logged in as root, it is safe to ignore this message.
@@ -105,6 +123,70 @@ Otherwise, run `dotter undeploy` as root, remove cache.toml and cache/ folders,
                 return Ok(false);
             }
         }
+        args::Action::Status => {
+            debug!("Checking status...");
+            if status::status(&opt).context("check status")? {
+                // Something isn't deployed as expected
+                return Ok(false);
+            }
+        }
+        args::Action::Restore { backup, files } => {
+            debug!("Restoring...");
+            if backup::restore(&opt, backup.as_deref(), &files).context("restore backup")? {
+                // Some files weren't restored
+                return Ok(false);
+            }
+        }
+        args::Action::Rollback => {
+            debug!("Rolling back...");
+            if journal::rollback(&opt).context("roll back last deploy")? {
+                // Some files weren't restored
+                return Ok(false);
+            }
+        }
+        args::Action::Adopt {
+            target,
+            package,
+            template,
+            source,
+        } => {
+            debug!("Adopting...");
+            adopt::adopt(&opt, &target, &package, template, source.as_deref())
+                .context("adopt file")?;
+        }
+        args::Action::Pull { files } => {
+            debug!("Pulling...");
+            if pull::pull(&opt, &files).context("pull changes from templates")? {
+                // Some changes have to be ported by hand
+                return Ok(false);
+            }
+        }
+        args::Action::Explain { path } => {
+            explain::explain(&opt, &path).context("explain file")?;
+        }
+        args::Action::Plan { out } => {
+            debug!("Planning...");
+            plan::plan(&opt, out.as_deref()).context("make a plan")?;
+        }
+        args::Action::Apply { plan } => {
+            debug!("Applying plan...");
+            if plan::apply(&opt, &plan).context("apply plan")? {
+                // An error occurred
+                return Ok(false);
+            }
+        }
+        args::Action::Cache { action } => match action {
+            args::CacheAction::Repair => {
+                debug!("Repairing cache...");
+                if cache::repair(&opt).context("repair cache")? {
+                    // Some templates couldn't be repaired
+                    return Ok(false);
+                }
+            }
+        },
+        args::Action::Packages { action } => {
+            packages::packages(&opt, &action).context("manage packages")?;
+        }
         args::Action::Init => {
             debug!("Initializing repo...");
             init::init(opt).context("initalize directory")?;
@@ -0,0 +1,193 @@
+use std::cmp::max;
+use std::ops::Range;
+
+/// A change to a contiguous range of lines of the base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk<'a> {
+    /// Lines of the base that are replaced
+    pub base: Range<usize>,
+    /// Lines that replace them
+    pub lines: Vec<&'a str>,
+}
+
+/// Result of a three-way merge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merge {
+    pub text: String,
+    /// Amount of places where both sides changed the same lines differently.
+    /// Each of them is surrounded with conflict markers in `text`.
+    pub conflicts: usize,
+}
+
+/// Splits a file into lines in a way that joining them with "\n" gives back the same file
+pub fn split_lines(text: &str) -> Vec<&str> {
+    text.split('\n').collect()
+}
+
+/// Lists the changes needed to turn `base` into `other`
+pub fn hunks<'a>(base: &[&'a str], other: &[&'a str]) -> Vec<Hunk<'a>> {
+    let mut hunks = Vec::new();
+    let mut current: Option<Hunk<'a>> = None;
+    let mut position = 0;
+
+    for line in diff::slice(base, other) {
+        match line {
+            diff::Result::Left(_) => {
+                current
+                    .get_or_insert_with(|| Hunk {
+                        base: position..position,
+                        lines: Vec::new(),
+                    })
+                    .base
+                    .end += 1;
+                position += 1;
+            }
+            diff::Result::Right(r) => {
+                current
+                    .get_or_insert_with(|| Hunk {
+                        base: position..position,
+                        lines: Vec::new(),
+                    })
+                    .lines
+                    .push(r);
+            }
+            diff::Result::Both(..) => {
+                hunks.extend(current.take());
+                position += 1;
+            }
+        }
+    }
+    hunks.extend(current);
+
+    hunks
+}
+
+/// Merges the changes that `ours` and `theirs` made to `base`.
+/// Changes that touch the same lines of the base are a conflict, unless both sides made the
+/// exact same change.
+pub fn merge(base: &str, ours: &str, theirs: &str, ours_name: &str, theirs_name: &str) -> Merge {
+    let base = split_lines(base);
+    let ours = hunks(&base, &split_lines(ours));
+    let theirs = hunks(&base, &split_lines(theirs));
+    let mut ours = ours.iter().peekable();
+    let mut theirs = theirs.iter().peekable();
+
+    let mut output = Vec::new();
+    let mut conflicts = 0;
+    let mut position = 0;
+
+    loop {
+        let start = match (ours.peek(), theirs.peek()) {
+            (None, None) => break,
+            (Some(o), None) => o.base.start,
+            (None, Some(t)) => t.base.start,
+            (Some(o), Some(t)) => o.base.start.min(t.base.start),
+        };
+
+        // Collect every hunk that overlaps or touches the region changed so far
+        let mut end = start;
+        let mut ours_region = Vec::new();
+        let mut theirs_region = Vec::new();
+        loop {
+            if let Some(hunk) = ours.next_if(|h| h.base.start <= end) {
+                end = max(end, hunk.base.end);
+                ours_region.push(hunk);
+            } else if let Some(hunk) = theirs.next_if(|h| h.base.start <= end) {
+                end = max(end, hunk.base.end);
+                theirs_region.push(hunk);
+            } else {
+                break;
+            }
+        }
+
+        output.extend(base[position..start].iter().map(|l| l.to_string()));
+        let ours_lines = apply(&base, start..end, &ours_region);
+        let theirs_lines = apply(&base, start..end, &theirs_region);
+        if theirs_region.is_empty() || ours_lines == theirs_lines {
+            output.extend(ours_lines.into_iter().map(String::from));
+        } else if ours_region.is_empty() {
+            output.extend(theirs_lines.into_iter().map(String::from));
+        } else {
+            conflicts += 1;
+            output.push(format!("<<<<<<< {ours_name}"));
+            output.extend(ours_lines.into_iter().map(String::from));
+            output.push("=======".into());
+            output.extend(theirs_lines.into_iter().map(String::from));
+            output.push(format!(">>>>>>> {theirs_name}"));
+        }
+        position = end;
+    }
+    output.extend(base[position..].iter().map(|l| l.to_string()));
+
+    Merge {
+        text: output.join("\n"),
+        conflicts,
+    }
+}
+
+/// Applies the hunks to a region of the base, returning the new contents of that region
+pub fn apply<'a>(base: &[&'a str], region: Range<usize>, hunks: &[&Hunk<'a>]) -> Vec<&'a str> {
+    let mut lines = Vec::new();
+    let mut position = region.start;
+    for hunk in hunks {
+        lines.extend(&base[position..hunk.base.start]);
+        lines.extend(&hunk.lines);
+        position = hunk.base.end;
+    }
+    lines.extend(&base[position..region.end]);
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn separate_changes_merge_cleanly() {
+        let merged = merge(
+            "a\nb\nc\nd\ne\n",
+            "a\nB\nc\nd\ne\nf\n",
+            "a\nb\nc\nD\ne\n",
+            "ours",
+            "theirs",
+        );
+        assert_eq!(merged.text, "a\nB\nc\nD\ne\nf\n");
+        assert_eq!(merged.conflicts, 0);
+    }
+
+    #[test]
+    fn same_change_is_not_a_conflict() {
+        let merged = merge("a\nb\nc\n", "a\nB\nc\n", "a\nB\nc\n", "ours", "theirs");
+        assert_eq!(merged.text, "a\nB\nc\n");
+        assert_eq!(merged.conflicts, 0);
+    }
+
+    #[test]
+    fn overlapping_changes_conflict() {
+        let merged = merge("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\nd\n", "o", "t");
+        assert_eq!(
+            merged.text,
+            "a\n<<<<<<< o\nours\n=======\ntheirs\n>>>>>>> t\nc\nd\n"
+        );
+        assert_eq!(merged.conflicts, 1);
+    }
+
+    #[test]
+    fn hunks_of_insertions_and_deletions() {
+        let base = split_lines("a\nb\nc");
+        let other = split_lines("x\na\nc");
+        assert_eq!(
+            hunks(&base, &other),
+            vec![
+                Hunk {
+                    base: 0..0,
+                    lines: vec!["x"]
+                },
+                Hunk {
+                    base: 1..2,
+                    lines: vec![]
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::args::{Options, PackagesAction};
+use crate::config::{self, FileTarget, GlobalConfig, Package};
+use crate::filesystem;
+
+pub fn packages(opt: &Options, action: &PackagesAction) -> Result<()> {
+    let mut global = config::load_global_config(&opt.global_config)?;
+    let local = config::load_local_config(&opt.local_config)?;
+    config::apply_includes(&mut global, &local.includes, &mut Default::default())
+        .context("apply includes")?;
+    let packages = &global.packages;
+
+    // Typos in local.toml shouldn't get in the way of fixing them
+    let (selected, unknown): (Vec<_>, Vec<_>) = local
+        .packages
+        .iter()
+        .cloned()
+        .partition(|p| packages.contains_key(p.strip_prefix('!').unwrap_or(p)));
+    for name in &unknown {
+        warn!(
+            "Package {:?} is selected in the local config but doesn't exist.",
+            name
+        );
+    }
+
+    match action {
+        PackagesAction::List => {
+            list(&global, &selected).context("list packages")?;
+        }
+        PackagesAction::Enable { name } => {
+            package(packages, name)?;
+            if selected.contains(name) {
+                info!("Package {:?} is already enabled.", name);
+                return Ok(());
+            }
+            let disabled = format!("!{name}");
+            edit_local_packages(opt, |selected| {
+                selected.retain(|p| p.as_str() != Some(disabled.as_str()));
+                selected.push(name.as_str());
+            })
+            .context("add package to local config")?;
+        }
+        PackagesAction::Disable { name } => {
+            package(packages, name)?;
+            let selected: Vec<_> = selected.into_iter().filter(|p| p != name).collect();
+            let enabled = config::enabled_packages(&global, &selected)?;
+            let dependents = dependents(packages, &enabled, name);
+            // Recommended packages are only left out when they're disabled explicitly
+            let recommenders = if dependents.is_empty() {
+                recommenders(packages, &enabled, name)
+            } else {
+                Vec::new()
+            };
+
+            if local.packages.contains(name) || !recommenders.is_empty() {
+                edit_local_packages(opt, |selected| {
+                    selected.retain(|p| p.as_str() != Some(name.as_str()));
+                    if !recommenders.is_empty() {
+                        selected.push(format!("!{name}"));
+                    }
+                })
+                .context("remove package from local config")?;
+            } else {
+                info!("Package {:?} isn't selected in the local config.", name);
+            }
+
+            if !dependents.is_empty() {
+                warn!(
+                    "Package {:?} is still enabled because it's required by {}.",
+                    name,
+                    dependents.join(", ")
+                );
+            } else if !recommenders.is_empty() {
+                info!(
+                    "Package {:?} is recommended by {}, so it's now disabled with \"!{}\".",
+                    name,
+                    recommenders.join(", "),
+                    name
+                );
+            }
+        }
+        PackagesAction::Show { name } => {
+            show(&global, &selected, name).context("show package")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn package<'a>(packages: &'a BTreeMap<String, Package>, name: &str) -> Result<&'a Package> {
+    packages.get(name).with_context(|| {
+        format!(
+            "find package {name:?}, the available packages are: {}",
+            packages.keys().cloned().collect::<Vec<_>>().join(", ")
+        )
+    })
+}
+
+/// Lists the enabled packages that have `name` as a direct dependency
+fn dependents(
+    packages: &BTreeMap<String, Package>,
+    enabled: &BTreeSet<String>,
+    name: &str,
+) -> Vec<String> {
+    enabled
+        .iter()
+        .filter(|p| packages[*p].depends.iter().any(|d| d == name))
+        .cloned()
+        .collect()
+}
+
+/// Lists the enabled packages that recommend `name`, if it's enabled
+fn recommenders(
+    packages: &BTreeMap<String, Package>,
+    enabled: &BTreeSet<String>,
+    name: &str,
+) -> Vec<String> {
+    if !enabled.contains(name) {
+        return Vec::new();
+    }
+    enabled
+        .iter()
+        .filter(|p| packages[*p].recommends.iter().any(|r| r == name))
+        .cloned()
+        .collect()
+}
+
+fn list(global: &GlobalConfig, selected: &[String]) -> Result<()> {
+    let packages = &global.packages;
+    let enabled = config::enabled_packages(global, selected)?;
+    let width = packages
+        .keys()
+        .map(|k| k.chars().count())
+        .max()
+        .unwrap_or(0);
+    for name in packages.keys() {
+        if selected.contains(name) && enabled.contains(name) {
+            println!("{} {}", "enabled ".green(), name);
+            continue;
+        }
+
+        let dependents = dependents(packages, &enabled, name);
+        let recommenders = recommenders(packages, &enabled, name);
+        if !dependents.is_empty() {
+            println!(
+                "{} {:<width$}  {}",
+                "depended".yellow(),
+                name,
+                format!("(required by {})", dependents.join(", ")).dark_grey()
+            );
+        } else if !recommenders.is_empty() {
+            println!(
+                "{} {:<width$}  {}",
+                "depended".yellow(),
+                name,
+                format!("(recommended by {})", recommenders.join(", ")).dark_grey()
+            );
+        } else if selected.contains(name) {
+            println!(
+                "{} {:<width$}  {}",
+                "disabled".dark_grey(),
+                name,
+                "(its condition doesn't hold)".dark_grey()
+            );
+        } else {
+            println!("{} {}", "disabled".dark_grey(), name);
+        }
+    }
+    Ok(())
+}
+
+fn show(global: &GlobalConfig, selected: &[String], name: &str) -> Result<()> {
+    let packages = &global.packages;
+    let package = package(packages, name)?;
+    let enabled = config::enabled_packages(global, selected)?;
+    println!(
+        "Package {} ({})",
+        name.bold(),
+        if selected.iter().any(|p| p == name) && enabled.contains(name) {
+            "enabled".green()
+        } else if selected.iter().any(|p| p == name) {
+            "disabled because its condition doesn't hold".dark_grey()
+        } else if enabled.contains(name) {
+            "enabled as a dependency".yellow()
+        } else if selected.iter().any(|p| p.strip_prefix('!') == Some(name)) {
+            "disabled explicitly".dark_grey()
+        } else {
+            "disabled".dark_grey()
+        }
+    );
+
+    if let Some(condition) = &package.condition {
+        println!("Only enabled if: {condition}");
+    }
+
+    if !package.depends.is_empty() {
+        println!("Depends on:");
+        print_dependencies(packages, name, 1, &mut vec![name]);
+    }
+
+    if !package.recommends.is_empty() {
+        println!("Recommends:");
+        for recommended in &package.recommends {
+            println!("    {recommended}");
+        }
+    }
+
+    if !package.conflicts.is_empty() {
+        println!("Conflicts with:");
+        for conflict in &package.conflicts {
+            let marker = if enabled.contains(conflict) {
+                " (enabled)".red().to_string()
+            } else {
+                String::new()
+            };
+            println!("    {conflict}{marker}");
+        }
+    }
+
+    if !package.files.is_empty() {
+        println!("Files:");
+        for (source, target) in &package.files {
+            let kind = match target {
+                FileTarget::Automatic(_) => "",
+                FileTarget::Symbolic(_) => " (symbolic)",
+                FileTarget::ComplexTemplate(_) => " (template)",
+                FileTarget::Copy(_) => " (copy)",
+            };
+            println!("    {:?} -> {:?}{}", source, target.path(), kind);
+        }
+    }
+
+    if !package.variables.is_empty() {
+        println!("Variables:");
+        let variables = toml::to_string(&package.variables).context("serialize variables")?;
+        for line in variables.lines() {
+            println!("    {line}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the dependency tree, marking dependencies that lead back to a package in `path`
+fn print_dependencies<'a>(
+    packages: &'a BTreeMap<String, Package>,
+    name: &str,
+    depth: usize,
+    path: &mut Vec<&'a str>,
+) {
+    let Some(package) = packages.get(name) else {
+        return;
+    };
+    for dependency in &package.depends {
+        let indent = "    ".repeat(depth);
+        if !packages.contains_key(dependency) {
+            println!("{indent}{} {}", dependency, "(doesn't exist)".red());
+        } else if path.contains(&dependency.as_str()) {
+            println!("{indent}{} {}", dependency, "(cycle)".dark_grey());
+        } else {
+            println!("{indent}{dependency}");
+            path.push(dependency);
+            print_dependencies(packages, dependency, depth + 1, path);
+            path.pop();
+        }
+    }
+}
+
+/// Edits the `packages` list of the local config, keeping its formatting
+fn edit_local_packages(opt: &Options, edit: impl FnOnce(&mut toml_edit::Array)) -> Result<()> {
+    let path = config::local_config_path(&opt.local_config)?;
+    let mut document = filesystem::load_document(&path)
+        .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("file not found")))
+        .with_context(|| format!("load local config {path:?}"))?;
+    let selected = document
+        .entry("packages")
+        .or_insert_with(|| toml_edit::value(toml_edit::Array::new()))
+        .as_array_mut()
+        .context("get packages of local config")?;
+    edit(selected);
+
+    if opt.dry_run {
+        info!("Not saving {:?} during a dry run.", path);
+        return Ok(());
+    }
+    filesystem::save_document(&path, &document).context("save local config")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn direct_dependents_only() {
+        let global: GlobalConfig = toml::from_str(
+            r#"
+                [a]
+                depends = ['b']
+                [b]
+                depends = ['c']
+                [c]
+                [d]
+                depends = ['c']
+            "#,
+        )
+        .unwrap();
+        let enabled = config::enabled_packages(&global, &["a".to_string()]).unwrap();
+        let packages = &global.packages;
+        assert_eq!(dependents(packages, &enabled, "b"), vec!["a"]);
+        assert_eq!(dependents(packages, &enabled, "c"), vec!["b"]);
+        assert!(dependents(packages, &enabled, "a").is_empty());
+    }
+
+    #[test]
+    fn conditions_decide_what_is_enabled() {
+        let global: GlobalConfig = toml::from_str(
+            r#"
+                [a]
+                recommends = ['b']
+                [b]
+                if = '(eq dotter.os "plan9")'
+            "#,
+        )
+        .unwrap();
+        let enabled = config::enabled_packages(&global, &["a".to_string()]).unwrap();
+        assert_eq!(enabled, BTreeSet::from(["a".to_string()]));
+        assert!(recommenders(&global.packages, &enabled, "b").is_empty());
+    }
+}
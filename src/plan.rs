@@ -0,0 +1,336 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use handlebars::Handlebars;
+
+use crate::actions::{self, Comparisons, Prerendered};
+use crate::args::Options;
+use crate::cache;
+#[cfg(feature = "scripting")]
+use crate::config::Helpers;
+use crate::config::{self, Cache, Variables};
+use crate::deploy::{self, desired_files, load_patch, plan_steps, DesiredFiles, Step};
+use crate::filesystem::{self, Filesystem, RealFilesystem};
+use crate::handlebars_helpers::{self, create_new_handlebars};
+use crate::report::{ActionKind, Comparison};
+use crate::secrets::{self, Secrets};
+
+/// Everything a deploy would do, in the order it would do it
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub actions: Vec<PlannedAction>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedAction {
+    pub action: ActionKind,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// State of the target when the plan was made
+    pub state: String,
+    /// Hash of what will be written to the target, for templates and copies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} -> {:?} ({}",
+            self.action, self.source, self.target, self.state
+        )?;
+        if let Some(hash) = &self.hash {
+            write!(f, ", contents {}", &hash[..hash.len().min(12)])?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Writes the plan to `out`, or prints it if it's not given
+pub fn plan(opt: &Options, out: Option<&Path>) -> Result<()> {
+    let plan = make_plan(opt)?;
+    let json = serde_json::to_string_pretty(&plan).context("serialize plan")? + "\n";
+    match out {
+        Some(out) => {
+            std::fs::write(out, json).with_context(|| format!("write plan to {out:?}"))?;
+            info!("Planned {} actions into {:?}.", plan.actions.len(), out);
+        }
+        None => print!("{json}"),
+    }
+    Ok(())
+}
+
+/// Deploys if the plan in `path` is still what the deploy would do.
+/// Returns true if an error was printed during the deploy.
+pub fn apply(opt: &Options, path: &Path) -> Result<bool> {
+    let planned = std::fs::read_to_string(path).with_context(|| format!("read plan {path:?}"))?;
+    let planned: Plan = serde_json::from_str(&planned).context("parse plan")?;
+    deploy::deploy_planned(opt, Some(&planned))
+}
+
+/// Fails if `current` isn't exactly what was `planned`, listing the differences
+pub(crate) fn check(planned: &Plan, current: &Plan) -> Result<()> {
+    if current == planned {
+        info!("Plan is up to date, applying it.");
+        return Ok(());
+    }
+
+    for action in &planned.actions {
+        if !current.actions.contains(action) {
+            warn!("Planned to {}, which no longer applies.", action);
+        }
+    }
+    for action in &current.actions {
+        if !planned.actions.contains(action) {
+            warn!("Would now {}, which wasn't planned.", action);
+        }
+    }
+    anyhow::bail!(
+        "things changed since the plan was made, so nothing was applied. Run `dotter plan` again"
+    )
+}
+
+/// Works out what a deploy would do without changing anything
+fn make_plan(opt: &Options) -> Result<Plan> {
+    let patch = load_patch(opt)?;
+    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, patch)
+        .context("get a configuration")?;
+
+    let cache: Cache = if let Some(cache) = cache::load(&opt.cache_file)? {
+        cache
+    } else {
+        warn!("Cache file not found. Assuming cache is empty.");
+        Cache::default()
+    };
+
+    let secrets = secrets::decrypt_variables(&mut config).context("decrypt variables")?;
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let desired =
+        desired_files(config.files).context("sort files into symlinks, templates and copies")?;
+    // Comparisons only read from the filesystem
+    let mut fs = RealFilesystem::new(true);
+    let prerendered =
+        actions::prerender_templates(&desired.templates, &mut fs, &handlebars, &config.variables);
+    let comparisons = actions::compare_templates(&desired.templates, &opt.cache_directory);
+
+    plan_actions(
+        &mut fs,
+        &desired,
+        &cache,
+        &Rendering {
+            handlebars: &handlebars,
+            variables: &config.variables,
+            secrets: &secrets,
+            prerendered: &prerendered,
+            #[cfg(feature = "scripting")]
+            helpers: &config.helpers,
+        },
+        &comparisons,
+        opt,
+    )
+}
+
+/// What's needed to render templates the way a deploy does
+pub(crate) struct Rendering<'a> {
+    pub handlebars: &'a Handlebars<'a>,
+    pub variables: &'a Variables,
+    pub secrets: &'a Secrets,
+    pub prerendered: &'a Prerendered,
+    #[cfg(feature = "scripting")]
+    pub helpers: &'a Helpers,
+}
+
+/// Works out what a deploy would do from the state of the filesystem, reusing the renders and
+/// comparisons that the deploy does ahead of time, so that a plan checked during a deploy is
+/// bound to what it executes. Templates that could render secrets get no hash, since it would
+/// leak them into the plan.
+pub(crate) fn plan_actions(
+    fs: &mut dyn Filesystem,
+    desired: &DesiredFiles,
+    cache: &Cache,
+    rendering: &Rendering<'_>,
+    comparisons: &Comparisons,
+    opt: &Options,
+) -> Result<Plan> {
+    let steps = plan_steps(
+        &desired.symlinks,
+        &desired.templates,
+        &desired.copies,
+        cache,
+    );
+    let mut actions = Vec::new();
+    for Step {
+        kind,
+        source,
+        target,
+    } in steps
+    {
+        let cache_file = opt.cache_directory.join(&source);
+        let (comparison, hash) = || -> Result<(Comparison, Option<String>)> {
+            use ActionKind::*;
+            Ok(match kind {
+                DeleteSymlink | CreateSymlink | UpdateSymlink => (
+                    Comparison::Symlink(fs.compare_symlink(&source, &target)?),
+                    None,
+                ),
+                DeleteTemplate => (
+                    Comparison::Template(fs.compare_template(&target, &cache_file)?),
+                    None,
+                ),
+                CreateTemplate | UpdateTemplate => {
+                    let template = &desired.templates[&source];
+                    // Which variables are used only matters if some were encrypted
+                    let used = if rendering.secrets.is_empty() || template.is_encrypted() {
+                        None
+                    } else {
+                        fs.read_to_string(&source).ok().and_then(|contents| {
+                            handlebars_helpers::template_variables(
+                                &template.apply_actions(contents),
+                                #[cfg(feature = "scripting")]
+                                rendering.helpers,
+                            )
+                        })
+                    };
+                    let hash = if deploy::renders_secrets(
+                        template,
+                        used.as_ref(),
+                        rendering.variables,
+                        rendering.secrets,
+                    ) {
+                        None
+                    } else {
+                        let rendered = actions::render_template(
+                            &source,
+                            Some(template),
+                            fs,
+                            rendering.handlebars,
+                            rendering.variables,
+                            rendering.secrets,
+                            rendering.prerendered,
+                        )?;
+                        Some(filesystem::hash(rendered.as_bytes()))
+                    };
+                    let comparison = match comparisons.get(&target) {
+                        Some((compared_cache, comparison)) if *compared_cache == cache_file => {
+                            *comparison
+                        }
+                        _ => fs.compare_template(&target, &cache_file)?,
+                    };
+                    (Comparison::Template(comparison), hash)
+                }
+                DeleteCopy => {
+                    let cached_hash = &cache.copies[&source].hash;
+                    (
+                        Comparison::Copy(fs.compare_copy(&target, cached_hash)?),
+                        None,
+                    )
+                }
+                CreateCopy => {
+                    let hash = fs.hash_file(&source)?;
+                    (
+                        Comparison::Copy(fs.compare_copy(&target, &hash)?),
+                        Some(hash),
+                    )
+                }
+                UpdateCopy => {
+                    let cached_hash = &cache.copies[&source].hash;
+                    let hash = fs.hash_file(&source)?;
+                    (
+                        Comparison::Copy(fs.compare_copy(&target, cached_hash)?),
+                        Some(hash),
+                    )
+                }
+            })
+        }()
+        .with_context(|| format!("plan to {kind} {source:?} -> {target:?}"))?;
+
+        actions.push(PlannedAction {
+            action: kind,
+            source,
+            target,
+            state: comparison.to_string(),
+            hash,
+        });
+    }
+
+    Ok(Plan { actions })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::config::TemplateTarget;
+    use crate::filesystem::{MockFilesystem, TemplateComparison};
+
+    use super::*;
+
+    #[test]
+    fn plan_reuses_renders_and_leaves_out_hashes_of_secrets() {
+        let mut encrypted: TemplateTarget = "b_out".into();
+        encrypted.encrypted = Some(true);
+        let desired = DesiredFiles {
+            symlinks: BTreeMap::new(),
+            templates: maplit::btreemap! {
+                PathBuf::from("a_in") => "a_out".into(),
+                PathBuf::from("b_in") => encrypted,
+                PathBuf::from("c_in") => "c_out".into(),
+            },
+            copies: BTreeMap::new(),
+        };
+        let prerendered = maplit::btreemap! {
+            PathBuf::from("a_in") => Ok("a".to_string()),
+            PathBuf::from("b_in") => Ok("secret".to_string()),
+            PathBuf::from("c_in") => Ok("hunter2".to_string()),
+        };
+        let comparisons = maplit::btreemap! {
+            PathBuf::from("a_out") => (PathBuf::from("cache/a_in"), TemplateComparison::BothMissing),
+            PathBuf::from("b_out") => (PathBuf::from("cache/b_in"), TemplateComparison::BothMissing),
+            PathBuf::from("c_out") => (PathBuf::from("cache/c_in"), TemplateComparison::BothMissing),
+        };
+        // c_in uses an encrypted variable
+        let variables: Variables = toml::from_str("name = 'a'\ntoken = 'hunter2'").unwrap();
+
+        // Everything comes from the renders and comparisons done ahead of time, only the sources
+        // are read to find out which variables they use
+        let mut fs = MockFilesystem::new();
+        fs.expect_read_to_string()
+            .times(2)
+            .returning(|source| match source.to_str().unwrap() {
+                "a_in" => Ok("{{name}}".into()),
+                "c_in" => Ok("{{token}}".into()),
+                source => panic!("read {source}"),
+            });
+        let plan = plan_actions(
+            &mut fs,
+            &desired,
+            &Cache::default(),
+            &Rendering {
+                handlebars: &Handlebars::new(),
+                variables: &variables,
+                secrets: &Secrets::from_values(vec!["hunter2".into()]),
+                prerendered: &prerendered,
+                #[cfg(feature = "scripting")]
+                helpers: &Helpers::new(),
+            },
+            &comparisons,
+            &Options {
+                cache_directory: "cache".into(),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(plan.actions.len(), 3);
+        assert_eq!(plan.actions[0].action, ActionKind::CreateTemplate);
+        assert_eq!(plan.actions[0].hash, Some(filesystem::hash(b"a")));
+        assert_eq!(plan.actions[1].action, ActionKind::CreateTemplate);
+        assert_eq!(plan.actions[1].hash, None);
+        assert_eq!(plan.actions[2].action, ActionKind::CreateTemplate);
+        assert_eq!(plan.actions[2].hash, None);
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{Context, Result};
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::actions::{render_template, Prerendered};
+use crate::args::Options;
+use crate::cache;
+use crate::config::{self, Cache, TemplateTarget, Variables};
+use crate::deploy::{desired_files, DesiredFiles};
+use crate::difference::{diff_nonempty, diff_strings, print_diff};
+use crate::filesystem::TemplateComparison;
+use crate::filesystem::{self, DryRunFilesystem, Filesystem, RealFilesystem};
+use crate::handlebars_helpers::create_new_handlebars;
+use crate::merge::{apply, hunks, split_lines, Hunk};
+use crate::secrets::{self, Secrets};
+
+use handlebars::Handlebars;
+
+/// Ports changes made in the targets of templates back into their sources.
+/// Returns true if some changes have to be ported by hand.
+pub fn pull(opt: &Options, files: &[PathBuf]) -> Result<bool> {
+    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, None)
+        .context("get a configuration")?;
+    let cache: Cache =
+        cache::load(&opt.cache_file)?.context("load cache: Cannot pull without a cache.")?;
+
+    let secrets = secrets::decrypt_variables(&mut config).context("decrypt variables")?;
+    let handlebars = create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let DesiredFiles {
+        templates: desired_templates,
+        ..
+    } = desired_files(config.files).context("sort files into symlinks and templates")?;
+
+    let (mut real_fs, mut dry_run_fs);
+    let fs: &mut dyn Filesystem = if !opt.dry_run {
+        real_fs = RealFilesystem::new(opt.noconfirm);
+        &mut real_fs
+    } else {
+        dry_run_fs = DryRunFilesystem::new();
+        &mut dry_run_fs
+    };
+
+    let mut manual_changes = false;
+    for (source, target) in &cache.templates {
+        if !files.is_empty() && !files.contains(source) && !files.contains(target) {
+            continue;
+        }
+        let Some(template) = desired_templates
+            .get(source)
+            .filter(|t| &t.target == target)
+        else {
+            warn!(
+                "Template {:?} -> {:?} is no longer configured. Skipping.",
+                source, target
+            );
+            continue;
+        };
+        if template.is_encrypted() {
+            warn!(
+                "Template {:?} -> {:?} is encrypted, port its changes by hand. Skipping.",
+                source, target
+            );
+            continue;
+        }
+
+        let cache_file = opt.cache_directory.join(source);
+        match pull_template(
+            source,
+            &cache_file,
+            template,
+            fs,
+            &handlebars,
+            &config.variables,
+            &secrets,
+            opt,
+        ) {
+            Ok(manual) => manual_changes |= manual,
+            Err(e) => {
+                crate::display_error(e.context(format!("pull template {source:?} <- {target:?}")));
+                manual_changes = true;
+            }
+        }
+    }
+
+    Ok(manual_changes)
+}
+
+/// Returns true if some changes have to be ported by hand
+#[allow(clippy::too_many_arguments)]
+fn pull_template(
+    source: &Path,
+    cache: &Path,
+    target: &TemplateTarget,
+    fs: &mut dyn Filesystem,
+    handlebars: &Handlebars<'_>,
+    variables: &Variables,
+    secrets: &Secrets,
+    opt: &Options,
+) -> Result<bool> {
+    let comparison = fs
+        .compare_template(&target.target, cache)
+        .context("detect templated file's current state")?;
+    debug!("Current state: {}", comparison);
+    match comparison {
+        TemplateComparison::Identical => return Ok(false),
+        TemplateComparison::Changed => {}
+        _ => {
+            warn!(
+                "Pulling template {:?} <- {:?} but {}. Skipping.",
+                source, target.target, comparison
+            );
+            return Ok(false);
+        }
+    }
+
+    let source_contents = fs
+        .read_to_string(source)
+        .context("read template source file")?;
+    let cache_contents = fs
+        .read_to_string(cache)
+        .context("read previous render from cache")?;
+    let target_contents = fs
+        .read_to_string(&target.target)
+        .context("read template target file")?;
+
+    let pulled = pull_changes(&source_contents, &cache_contents, &target_contents);
+    if pulled.applied > 0 {
+        println!("Changes from {:?} to {:?}:", target.target, source);
+        print_diff(
+            &diff_strings(&source_contents, &pulled.text),
+            opt.diff_context_lines,
+        );
+        if opt.dry_run {
+            info!("Not writing {:?} during a dry run.", source);
+        } else if opt.noconfirm
+            || filesystem::ask_boolean(&format!("Apply these changes to {source:?}? [y/N]"))
+        {
+            fs.write(source, pulled.text.into())
+                .context("write changes into template source")?;
+        } else {
+            warn!("Leaving {:?} as is.", source);
+            return Ok(true);
+        }
+    }
+
+    if opt.dry_run {
+        if pulled.manual > 0 {
+            warn!(
+                "{} changes in {:?} touch rendered expressions and have to be ported into {:?} by hand.",
+                pulled.manual, target.target, source
+            );
+        }
+        return Ok(pulled.manual > 0);
+    }
+
+    // Whatever the new render doesn't have yet couldn't be pulled
+    let rendered = render_template(
+        source,
+        Some(target),
+        fs,
+        handlebars,
+        variables,
+        secrets,
+        &Prerendered::new(),
+    )
+    .context("render template")?;
+    let remaining = diff_strings(&rendered, &target_contents);
+    if diff_nonempty(&remaining) {
+        warn!(
+            "Some changes in {:?} touch rendered expressions. Port them into {:?} by hand:",
+            target.target, source
+        );
+        print_diff(&remaining, opt.diff_context_lines);
+        return Ok(true);
+    }
+
+    // The target is exactly what the template renders to now
+    fs.write_private(cache, rendered.into())
+        .context("write rendered template to cache")?;
+    Ok(false)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Pulled {
+    /// The source with the changes applied
+    text: String,
+    /// Amount of changes that were applied to the source
+    applied: usize,
+    /// Amount of changes that touch rendered expressions
+    manual: usize,
+}
+
+/// Applies the changes between the previous render and the target to the template source,
+/// as long as they only touch lines that are copied verbatim from it
+fn pull_changes(source: &str, cache: &str, target: &str) -> Pulled {
+    let source_lines = split_lines(source);
+    let cache_lines = split_lines(cache);
+
+    // Which line of the source each line of the render came from, if it's literal text
+    let mut origins = vec![None; cache_lines.len()];
+    let (mut source_line, mut cache_line) = (0, 0);
+    for line in diff::slice(&source_lines, &cache_lines) {
+        match line {
+            diff::Result::Left(_) => source_line += 1,
+            diff::Result::Right(_) => cache_line += 1,
+            diff::Result::Both(l, _) => {
+                if !is_expression(l) {
+                    origins[cache_line] = Some(source_line);
+                }
+                source_line += 1;
+                cache_line += 1;
+            }
+        }
+    }
+
+    let mut applied = Vec::new();
+    let mut manual = 0;
+    for hunk in hunks(&cache_lines, &split_lines(target)) {
+        match source_range(&hunk.base, &origins, source_lines.len()) {
+            Some(base) if !hunk.lines.iter().any(|l| is_expression(l)) => applied.push(Hunk {
+                base,
+                lines: hunk.lines,
+            }),
+            _ => manual += 1,
+        }
+    }
+
+    Pulled {
+        text: apply(
+            &source_lines,
+            0..source_lines.len(),
+            &applied.iter().collect::<Vec<_>>(),
+        )
+        .join("\n"),
+        applied: applied.len(),
+        manual,
+    }
+}
+
+/// Finds the lines of the source that a range of the render came from.
+/// The range has to be made of consecutive literal lines, and an insertion has to be between
+/// two lines that are next to each other in the source.
+fn source_range(
+    render: &Range<usize>,
+    origins: &[Option<usize>],
+    source_len: usize,
+) -> Option<Range<usize>> {
+    if render.is_empty() {
+        let before = match render.start {
+            0 => 0,
+            start => origins[start - 1]? + 1,
+        };
+        let after = match origins.get(render.start) {
+            Some(origin) => (*origin)?,
+            None => source_len,
+        };
+        return (before == after).then_some(before..before);
+    }
+
+    let first = origins[render.start]?;
+    for (offset, line) in render.clone().enumerate() {
+        if origins[line]? != first + offset {
+            return None;
+        }
+    }
+    Some(first..first + render.len())
+}
+
+fn is_expression(line: &str) -> bool {
+    line.contains("{{") || line.contains("}}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pull_literal_changes() {
+        let pulled = pull_changes(
+            "a\nname={{name}}\nb\nc\n",
+            "a\nname=x\nb\nc\n",
+            "A\nname=x\nb\nnew\nc\n",
+        );
+        assert_eq!(pulled.text, "A\nname={{name}}\nb\nnew\nc\n");
+        assert_eq!(pulled.applied, 2);
+        assert_eq!(pulled.manual, 0);
+    }
+
+    #[test]
+    fn leave_expressions_for_review() {
+        let pulled = pull_changes(
+            "a\nname={{name}}\nb\n",
+            "a\nname=x\nb\n",
+            "a\nname=y\nb\nextra={{ oops }}\n",
+        );
+        assert_eq!(pulled.text, "a\nname={{name}}\nb\n");
+        assert_eq!(pulled.applied, 0);
+        assert_eq!(pulled.manual, 2);
+    }
+
+    #[test]
+    fn insertion_next_to_expression_needs_review() {
+        // The new line could belong before or after the lines rendered by the expression
+        let pulled = pull_changes("a\n{{list}}\nb\n", "a\n1\n2\nb\n", "a\n1\nnew\n2\nb\n");
+        assert_eq!(pulled.applied, 0);
+        assert_eq!(pulled.manual, 1);
+    }
+}
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::path::Path;
+
+use crate::args::OutputFormat;
+use crate::filesystem::{CopyComparison, SymlinkComparison, TemplateComparison};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    DeleteSymlink,
+    DeleteTemplate,
+    CreateSymlink,
+    CreateTemplate,
+    UpdateSymlink,
+    UpdateTemplate,
+    DeleteCopy,
+    CreateCopy,
+    UpdateCopy,
+}
+
+impl fmt::Display for ActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::ActionKind::*;
+        match self {
+            DeleteSymlink => "delete symlink",
+            DeleteTemplate => "delete template",
+            CreateSymlink => "create symlink",
+            CreateTemplate => "create template",
+            UpdateSymlink => "update symlink",
+            UpdateTemplate => "update template",
+            DeleteCopy => "delete copy",
+            CreateCopy => "create copy",
+            UpdateCopy => "update copy",
+        }
+        .fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Comparison {
+    Symlink(SymlinkComparison),
+    Template(TemplateComparison),
+    Copy(CopyComparison),
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Comparison::Symlink(c) => c.fmt(f),
+            Comparison::Template(c) => c.fmt(f),
+            Comparison::Copy(c) => c.fmt(f),
+        }
+    }
+}
+
+impl Comparison {
+    /// Whether `action` only goes through in this state because of `--force`
+    fn requires_force(&self, action: ActionKind) -> bool {
+        match self {
+            Comparison::Symlink(c) => matches!(
+                c,
+                SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink
+            ),
+            Comparison::Template(TemplateComparison::OnlyTargetExists) => {
+                action == ActionKind::CreateTemplate
+            }
+            Comparison::Template(c) => matches!(
+                c,
+                TemplateComparison::Changed | TemplateComparison::TargetNotRegularFile
+            ),
+            Comparison::Copy(c) => matches!(
+                c,
+                CopyComparison::Changed | CopyComparison::TargetNotRegularFile
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Applied,
+    Forced,
+    Skipped,
+    Error,
+}
+
+/// An action that is about to be executed
+#[derive(Debug)]
+pub struct Action<'a> {
+    pub kind: ActionKind,
+    pub source: &'a Path,
+    pub target: &'a Path,
+    /// State of the target before the action, if it was inspected
+    pub comparison: Option<Comparison>,
+}
+
+impl fmt::Display for Action<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:?} -> {:?}", self.kind, self.source, self.target)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    Action {
+        action: ActionKind,
+        source: &'a Path,
+        target: &'a Path,
+        comparison: Option<Comparison>,
+        outcome: Outcome,
+        errors: Vec<String>,
+    },
+    Summary {
+        dry_run: bool,
+        applied: usize,
+        forced: usize,
+        skipped: usize,
+        errors: usize,
+    },
+}
+
+/// Collects the results of all actions in a run
+#[derive(Debug)]
+pub struct Report {
+    format: OutputFormat,
+    force: bool,
+    outcomes: Vec<Outcome>,
+    pub suggest_force: bool,
+    pub error_occurred: bool,
+}
+
+impl Report {
+    pub fn new(format: OutputFormat, force: bool) -> Report {
+        Report {
+            format,
+            force,
+            outcomes: Vec::new(),
+            suggest_force: false,
+            error_occurred: false,
+        }
+    }
+
+    /// Records the result of an action as returned by the functions in `actions`
+    pub fn record(&mut self, action: &Action<'_>, result: &anyhow::Result<bool>) {
+        let outcome = match result {
+            Ok(true)
+                if self.force
+                    && action
+                        .comparison
+                        .is_some_and(|c| c.requires_force(action.kind)) =>
+            {
+                Outcome::Forced
+            }
+            Ok(true) => Outcome::Applied,
+            Ok(false) => {
+                self.suggest_force = true;
+                Outcome::Skipped
+            }
+            Err(_) => {
+                self.error_occurred = true;
+                Outcome::Error
+            }
+        };
+        self.outcomes.push(outcome);
+
+        if self.format == OutputFormat::Json {
+            let errors = match result {
+                Err(e) => e.chain().map(|e| e.to_string()).collect(),
+                Ok(_) => Vec::new(),
+            };
+            print_record(&Record::Action {
+                action: action.kind,
+                source: action.source,
+                target: action.target,
+                comparison: action.comparison,
+                outcome,
+                errors,
+            });
+        }
+    }
+
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.outcomes.iter().filter(|o| **o == outcome).count()
+    }
+
+    /// Prints the summary of the run, if the output format has one
+    pub fn finish(&self, dry_run: bool) {
+        if self.format == OutputFormat::Json {
+            print_record(&Record::Summary {
+                dry_run,
+                applied: self.count(Outcome::Applied),
+                forced: self.count(Outcome::Forced),
+                skipped: self.count(Outcome::Skipped),
+                errors: self.count(Outcome::Error),
+            });
+        }
+    }
+}
+
+fn print_record(record: &Record<'_>) {
+    println!(
+        "{}",
+        serde_json::to_string(record).expect("records are always serializable")
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn action(kind: ActionKind, comparison: Comparison) -> Action<'static> {
+        Action {
+            kind,
+            source: Path::new("source"),
+            target: Path::new("target"),
+            comparison: Some(comparison),
+        }
+    }
+
+    #[test]
+    fn classify_outcomes() {
+        let mut report = Report::new(OutputFormat::Text, true);
+        let changed = Comparison::Template(TemplateComparison::Changed);
+        let only_target = Comparison::Template(TemplateComparison::OnlyTargetExists);
+
+        report.record(&action(ActionKind::UpdateTemplate, changed), &Ok(true));
+        report.record(&action(ActionKind::CreateTemplate, only_target), &Ok(true));
+        report.record(&action(ActionKind::UpdateTemplate, only_target), &Ok(true));
+        report.record(&action(ActionKind::UpdateTemplate, changed), &Ok(false));
+        report.record(
+            &action(ActionKind::UpdateTemplate, changed),
+            &Err(anyhow::anyhow!("oh no")),
+        );
+
+        assert_eq!(report.count(Outcome::Forced), 2);
+        assert_eq!(report.count(Outcome::Applied), 1);
+        assert_eq!(report.count(Outcome::Skipped), 1);
+        assert_eq!(report.count(Outcome::Error), 1);
+        assert!(report.suggest_force);
+        assert!(report.error_occurred);
+    }
+
+    #[test]
+    fn no_force_means_applied() {
+        let mut report = Report::new(OutputFormat::Text, false);
+        report.record(
+            &action(
+                ActionKind::CreateSymlink,
+                Comparison::Symlink(SymlinkComparison::Changed),
+            ),
+            &Ok(true),
+        );
+        assert_eq!(report.count(Outcome::Applied), 1);
+        assert_eq!(report.count(Outcome::Forced), 0);
+    }
+}
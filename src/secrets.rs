@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::config::{Configuration, Variables};
+use crate::difference::Diff;
+
+/// Shown in diffs instead of decrypted contents
+const MASK: &str = "********";
+
+/// Decrypts encrypted variables and template sources using the `decrypt_command` setting,
+/// and remembers the decrypted values so they can be kept out of diffs
+#[derive(Debug, Default)]
+pub struct Secrets {
+    decrypt_command: Option<String>,
+    values: Vec<String>,
+}
+
+/// Replaces every `{ encrypted = "..." }` in the configuration's variables with its decrypted value
+pub fn decrypt_variables(config: &mut Configuration) -> Result<Secrets> {
+    let mut secrets = Secrets {
+        decrypt_command: config.settings.decrypt_command.clone(),
+        values: Vec::new(),
+    };
+    secrets.decrypt_variables(&mut config.variables)?;
+    Ok(secrets)
+}
+
+impl Secrets {
+    fn decrypt_variables(&mut self, variables: &mut Variables) -> Result<()> {
+        for (name, value) in variables.iter_mut() {
+            self.decrypt_value(value)
+                .with_context(|| format!("decrypt variable {name:?}"))?;
+        }
+        Ok(())
+    }
+
+    fn decrypt_value(&mut self, value: &mut toml::Value) -> Result<()> {
+        match value {
+            toml::Value::Table(table) => {
+                if let Some(ciphertext) = encrypted_value(table) {
+                    let plaintext = self.decrypt(ciphertext.as_bytes())?;
+                    // Secrets printed by tools like `pass` end with a newline that's not part of them
+                    let plaintext = plaintext
+                        .strip_suffix('\n')
+                        .map(|p| p.strip_suffix('\r').unwrap_or(p))
+                        .unwrap_or(&plaintext)
+                        .to_string();
+                    self.values.push(plaintext.clone());
+                    *value = toml::Value::String(plaintext);
+                } else {
+                    for value in table.values_mut() {
+                        self.decrypt_value(value)?;
+                    }
+                }
+            }
+            toml::Value::Array(array) => {
+                for value in array {
+                    self.decrypt_value(value)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Reads an encrypted file and decrypts its contents
+    pub fn decrypt_file(&self, path: &Path) -> Result<String> {
+        let ciphertext = std::fs::read(path).context("read encrypted file")?;
+        self.decrypt(&ciphertext)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<String> {
+        let Some(command) = &self.decrypt_command else {
+            anyhow::bail!(
+                "found an encrypted value, but `decrypt_command` isn't set in [settings]"
+            );
+        };
+
+        let mut child = shell(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("spawn decrypt command")?;
+
+        // Written from another thread so a long output can't block the command while we're
+        // still writing its input
+        let mut stdin = child.stdin.take().expect("has stdin");
+        let ciphertext = ciphertext.to_vec();
+        let writer = std::thread::spawn(move || stdin.write_all(&ciphertext));
+
+        let output = child
+            .wait_with_output()
+            .context("wait for decrypt command")?;
+        anyhow::ensure!(
+            output.status.success(),
+            "decrypt command returned {}",
+            output.status
+        );
+        writer
+            .join()
+            .expect("writer thread panicked")
+            .context("give encrypted value to decrypt command")?;
+
+        String::from_utf8(output.stdout).context("decrypted value is not valid utf-8")
+    }
+
+    /// Hides decrypted values in a diff, or all of its lines if `everything` is set
+    pub fn mask_diff(&self, diff: Diff, everything: bool) -> Diff {
+        let mask = |line: String| {
+            if everything {
+                MASK.to_string()
+            } else {
+                self.mask(line)
+            }
+        };
+        diff.into_iter()
+            .map(|line| match line {
+                diff::Result::Left(l) => diff::Result::Left(mask(l)),
+                diff::Result::Both(l, r) => diff::Result::Both(mask(l), mask(r)),
+                diff::Result::Right(r) => diff::Result::Right(mask(r)),
+            })
+            .collect()
+    }
+
+    fn mask(&self, mut line: String) -> String {
+        // Diffs are made of lines, so each line of a multiline secret is hidden on its own
+        for secret in self.values.iter().flat_map(|v| v.lines()) {
+            if !secret.trim().is_empty() {
+                line = line.replace(secret, MASK);
+            }
+        }
+        line
+    }
+
+    /// Whether `value` is, or contains, a decrypted value
+    pub fn is_secret(&self, value: &toml::Value) -> bool {
+        match value {
+            toml::Value::String(string) => self.values.contains(string),
+            toml::Value::Table(table) => table.values().any(|value| self.is_secret(value)),
+            toml::Value::Array(array) => array.iter().any(|value| self.is_secret(value)),
+            _ => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn from_values(values: Vec<String>) -> Secrets {
+        Secrets {
+            decrypt_command: None,
+            values,
+        }
+    }
+}
+
+fn encrypted_value(table: &toml::value::Table) -> Option<String> {
+    match table.get("encrypted") {
+        Some(toml::Value::String(ciphertext)) if table.len() == 1 => Some(ciphertext.clone()),
+        _ => None,
+    }
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn decrypt_nested_variables() {
+        let mut variables: Variables = toml::from_str(
+            r#"
+                plain = "hello"
+                token = { encrypted = "frperg" }
+                [nested]
+                list = [{ encrypted = "nabgure" }]
+            "#,
+        )
+        .unwrap();
+        let mut secrets = Secrets {
+            // rot13
+            decrypt_command: Some("tr a-z n-za-m".into()),
+            values: Vec::new(),
+        };
+        secrets.decrypt_variables(&mut variables).unwrap();
+
+        assert_eq!(variables["plain"].as_str(), Some("hello"));
+        assert_eq!(variables["token"].as_str(), Some("secret"));
+        assert_eq!(variables["nested"]["list"][0].as_str(), Some("another"));
+        assert_eq!(secrets.values, vec!["another", "secret"]);
+    }
+
+    #[test]
+    fn mask_secrets_in_diff() {
+        let secrets = Secrets {
+            decrypt_command: None,
+            values: vec!["hunter2".into()],
+        };
+        let diff = vec![
+            diff::Result::Left("password = old".into()),
+            diff::Result::Right("password = hunter2".into()),
+            diff::Result::Both("user = me".into(), "user = me".into()),
+        ];
+
+        assert_eq!(
+            secrets.mask_diff(diff.clone(), false),
+            vec![
+                diff::Result::Left("password = old".into()),
+                diff::Result::Right("password = ********".into()),
+                diff::Result::Both("user = me".into(), "user = me".into()),
+            ]
+        );
+        assert_eq!(
+            secrets.mask_diff(diff, true),
+            vec![
+                diff::Result::Left(MASK.into()),
+                diff::Result::Right(MASK.into()),
+                diff::Result::Both(MASK.into(), MASK.into()),
+            ]
+        );
+    }
+}
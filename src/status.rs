@@ -0,0 +1,421 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+
+use std::path::{Path, PathBuf};
+
+use crate::args::Options;
+use crate::cache;
+use crate::config::{self, Cache, FileMode};
+use crate::deploy::{desired_files, load_patch, DesiredFiles};
+use crate::filesystem::{CopyComparison, SymlinkComparison, TemplateComparison};
+use crate::filesystem::{Filesystem, RealFilesystem};
+use crate::handlebars_helpers::create_new_handlebars;
+
+/// Returns true if any file drifted from its deployed state or isn't deployed yet
+pub fn status(opt: &Options) -> Result<bool> {
+    let patch = load_patch(opt)?;
+    let mut config = config::load_configuration(&opt.local_config, &opt.global_config, patch)
+        .context("get a configuration")?;
+
+    let cache: Cache = if let Some(cache) = cache::load(&opt.cache_file)? {
+        cache
+    } else {
+        warn!("Cache file not found. Assuming cache is empty.");
+        Cache::default()
+    };
+
+    // Only used to evaluate `if` conditions, nothing is rendered. Like in a deploy, the
+    // conditions can still run `command_success` and script helpers.
+    create_new_handlebars(&mut config).context("initialize handlebars")?;
+    let desired =
+        desired_files(config.files).context("sort files into symlinks, templates and copies")?;
+
+    // Comparisons only read from the filesystem
+    let mut fs = RealFilesystem::new(true);
+    let rows = status_rows(&mut fs, &cache, &desired, opt)?;
+
+    print!("{}", table(&rows));
+
+    Ok(needs_deploy(&rows))
+}
+
+/// Whether any row isn't in its deployed state
+fn needs_deploy(rows: &[Row]) -> bool {
+    rows.iter().any(|row| row.state != State::Ok)
+}
+
+/// A row for every deployed file, followed by the configured files that aren't deployed yet
+fn status_rows(
+    fs: &mut dyn Filesystem,
+    cache: &Cache,
+    desired: &DesiredFiles,
+    opt: &Options,
+) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+
+    for (source, target) in &cache.symlinks {
+        let comparison = fs
+            .compare_symlink(source, target)
+            .with_context(|| format!("compare symlink {source:?} -> {target:?}"))?;
+        let configured = desired.symlinks.get(source);
+        let desired = configured.filter(|t| &t.target == target);
+        let mut row = Row {
+            kind: "symlink",
+            source: source.clone(),
+            target: target.clone(),
+            state: if comparison == SymlinkComparison::Identical {
+                State::Ok
+            } else if comparison == SymlinkComparison::OnlySourceExists {
+                State::Missing
+            } else {
+                State::Drifted
+            },
+            description: comparison.to_string(),
+            configuration: Configuration::of(configured.is_some(), desired.is_some()),
+        };
+        if let Some(desired) = desired {
+            check_modes(fs, &mut row, desired.mode, desired.dir_mode)
+                .with_context(|| format!("check modes of {:?}", row.target))?;
+        }
+        rows.push(row);
+    }
+
+    for (source, target) in &cache.templates {
+        let comparison = fs
+            .compare_template(target, &opt.cache_directory.join(source))
+            .with_context(|| format!("compare template {source:?} -> {target:?}"))?;
+        let configured = desired.templates.get(source);
+        let desired = configured.filter(|t| &t.target == target);
+        let mut row = Row {
+            kind: "template",
+            source: source.clone(),
+            target: target.clone(),
+            state: if comparison == TemplateComparison::Identical {
+                State::Ok
+            } else if comparison == TemplateComparison::OnlyCacheExists {
+                State::Missing
+            } else {
+                State::Drifted
+            },
+            description: comparison.to_string(),
+            configuration: Configuration::of(configured.is_some(), desired.is_some()),
+        };
+        if let Some(desired) = desired {
+            check_modes(fs, &mut row, desired.mode, desired.dir_mode)
+                .with_context(|| format!("check modes of {:?}", row.target))?;
+        }
+        rows.push(row);
+    }
+
+    for (source, copy) in &cache.copies {
+        let comparison = fs
+            .compare_copy(&copy.target, &copy.hash)
+            .with_context(|| format!("compare copy {source:?} -> {:?}", copy.target))?;
+        let configured = desired.copies.get(source);
+        let desired = configured.filter(|t| t.target == copy.target);
+        let mut row = Row {
+            kind: "copy",
+            source: source.clone(),
+            target: copy.target.clone(),
+            state: if comparison == CopyComparison::Identical {
+                State::Ok
+            } else if comparison == CopyComparison::TargetMissing {
+                State::Missing
+            } else {
+                State::Drifted
+            },
+            description: comparison.to_string(),
+            configuration: Configuration::of(configured.is_some(), desired.is_some()),
+        };
+        if let Some(desired) = desired {
+            check_modes(fs, &mut row, desired.mode, desired.dir_mode)
+                .with_context(|| format!("check modes of {:?}", row.target))?;
+        }
+        rows.push(row);
+    }
+
+    let undeployed_symlinks = desired
+        .symlinks
+        .iter()
+        .filter(|(source, target)| cache.symlinks.get(*source) != Some(&target.target))
+        .map(|(source, target)| ("symlink", source, &target.target));
+    let undeployed_templates = desired
+        .templates
+        .iter()
+        .filter(|(source, target)| cache.templates.get(*source) != Some(&target.target))
+        .map(|(source, target)| ("template", source, &target.target));
+    let undeployed_copies = desired
+        .copies
+        .iter()
+        .filter(|(source, target)| {
+            cache.copies.get(*source).map(|c| &c.target) != Some(&target.target)
+        })
+        .map(|(source, target)| ("copy", source, &target.target));
+    for (kind, source, target) in undeployed_symlinks
+        .chain(undeployed_templates)
+        .chain(undeployed_copies)
+    {
+        rows.push(Row {
+            kind,
+            source: source.clone(),
+            target: target.clone(),
+            state: State::NotDeployed,
+            description: "not deployed yet".into(),
+            configuration: Configuration::Configured,
+        });
+    }
+
+    Ok(rows)
+}
+
+/// Marks a target that's otherwise fine as drifted if its modes aren't the configured ones
+fn check_modes(
+    fs: &mut dyn Filesystem,
+    row: &mut Row,
+    mode: Option<FileMode>,
+    dir_mode: Option<FileMode>,
+) -> Result<()> {
+    if row.state != State::Ok {
+        return Ok(());
+    }
+
+    let mut drift = Vec::new();
+    if let Some(mode) = mode {
+        if let Some(actual) = fs.mode(&row.target)?.filter(|m| *m != mode) {
+            drift.push(format!("mode is {actual} instead of {mode}"));
+        }
+    }
+    if let Some(dir_mode) = dir_mode {
+        let parent = row.target.parent().context("get parent of target")?;
+        if let Some(actual) = fs.mode(parent)?.filter(|m| *m != dir_mode) {
+            drift.push(format!("directory mode is {actual} instead of {dir_mode}"));
+        }
+    }
+
+    if !drift.is_empty() {
+        row.state = State::Drifted;
+        row.description = drift.join(", ");
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Ok,
+    Drifted,
+    Missing,
+    NotDeployed,
+}
+
+struct Row {
+    kind: &'static str,
+    source: PathBuf,
+    target: PathBuf,
+    state: State,
+    description: String,
+    configuration: Configuration,
+}
+
+/// Whether a deployed file still matches the configuration
+#[derive(Debug, PartialEq, Eq)]
+enum Configuration {
+    Configured,
+    /// The source is still configured, but with another target
+    TargetChanged,
+    NotConfigured,
+}
+
+impl Configuration {
+    fn of(source_configured: bool, target_configured: bool) -> Self {
+        match (source_configured, target_configured) {
+            (_, true) => Configuration::Configured,
+            (true, false) => Configuration::TargetChanged,
+            (false, false) => Configuration::NotConfigured,
+        }
+    }
+}
+
+fn table(rows: &[Row]) -> String {
+    if rows.is_empty() {
+        return "No files are configured or deployed.\n".into();
+    }
+
+    let source_width = rows
+        .iter()
+        .map(|row| display_len(&row.source))
+        .max()
+        .unwrap_or_default();
+    let target_width = rows
+        .iter()
+        .map(|row| display_len(&row.target))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = String::new();
+    for row in rows {
+        // Pad before styling, since escape codes would count towards the width
+        let state = match row.state {
+            State::Ok => format!("{:<7}", "ok").green(),
+            State::Drifted => format!("{:<7}", "drifted").red(),
+            State::Missing => format!("{:<7}", "missing").red(),
+            State::NotDeployed => format!("{:<7}", "new").yellow(),
+        };
+        let mut description = row.description.clone();
+        match row.configuration {
+            Configuration::Configured => {}
+            Configuration::TargetChanged => {
+                description += " (target changed, will be moved on deploy)";
+            }
+            Configuration::NotConfigured => {
+                description += " (no longer configured, will be removed on deploy)";
+            }
+        }
+        table += &format!(
+            "{} {:<8} {:<source_width$} -> {:<target_width$}  {}\n",
+            state,
+            row.kind,
+            row.source.display().to_string(),
+            row.target.display().to_string(),
+            description.dark_grey(),
+        );
+    }
+    table
+}
+
+fn display_len(path: &Path) -> usize {
+    path.display().to_string().chars().count()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::config::{CachedCopy, CopyTarget, SymbolicTarget, TemplateTarget};
+    use crate::filesystem::MockFilesystem;
+
+    use super::*;
+
+    use mockall::predicate::*;
+
+    fn path_eq(expected: &str) -> impl Fn(&Path) -> bool {
+        let expected = PathBuf::from(expected);
+        move |actual| actual == expected
+    }
+
+    #[test]
+    fn rows_for_every_state() {
+        // State
+        let mut moded: TemplateTarget = "b_out".into();
+        moded.mode = Some(FileMode(0o600));
+        let desired = DesiredFiles {
+            symlinks: maplit::btreemap! {
+                PathBuf::from("a_in") => SymbolicTarget::from("a_out_new"),
+            },
+            templates: maplit::btreemap! {
+                PathBuf::from("b_in") => moded,
+                PathBuf::from("d_in") => TemplateTarget::from("d_out"),
+            },
+            copies: BTreeMap::new(),
+        };
+        let cache = Cache {
+            symlinks: maplit::btreemap! {
+                PathBuf::from("a_in") => PathBuf::from("a_out"),
+            },
+            templates: maplit::btreemap! {
+                PathBuf::from("b_in") => PathBuf::from("b_out"),
+            },
+            copies: maplit::btreemap! {
+                PathBuf::from("c_in") => CachedCopy {
+                    target: "c_out".into(),
+                    hash: "hash".into(),
+                },
+            },
+            ..Cache::default()
+        };
+
+        let mut fs = MockFilesystem::new();
+        fs.expect_compare_symlink()
+            .with(function(path_eq("a_in")), function(path_eq("a_out")))
+            .times(1)
+            .returning(|_, _| Ok(SymlinkComparison::Identical));
+        fs.expect_compare_template()
+            .with(function(path_eq("b_out")), function(path_eq("cache/b_in")))
+            .times(1)
+            .returning(|_, _| Ok(TemplateComparison::Identical));
+        fs.expect_mode()
+            .with(function(path_eq("b_out")))
+            .times(1)
+            .returning(|_| Ok(Some(FileMode(0o644))));
+        fs.expect_compare_copy()
+            .with(function(path_eq("c_out")), eq("hash"))
+            .times(1)
+            .returning(|_, _| Ok(CopyComparison::TargetMissing));
+
+        // Reality
+        let rows = status_rows(
+            &mut fs,
+            &cache,
+            &desired,
+            &Options {
+                cache_directory: "cache".into(),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        let states = rows
+            .iter()
+            .map(|row| (row.source.to_str().unwrap(), &row.state, &row.configuration))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![
+                ("a_in", &State::Ok, &Configuration::TargetChanged),
+                ("b_in", &State::Drifted, &Configuration::Configured),
+                ("c_in", &State::Missing, &Configuration::NotConfigured),
+                ("a_in", &State::NotDeployed, &Configuration::Configured),
+                ("d_in", &State::NotDeployed, &Configuration::Configured),
+            ]
+        );
+        assert!(needs_deploy(&rows));
+
+        let table = table(&rows);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].contains("(target changed, will be moved on deploy)"));
+        assert!(lines[1].contains("mode is 0644 instead of 0600"));
+        assert!(lines[2].contains("(no longer configured, will be removed on deploy)"));
+        assert!(lines[3].contains("new"));
+    }
+
+    #[test]
+    fn deployed_files_need_no_deploy() {
+        let desired = DesiredFiles {
+            symlinks: BTreeMap::new(),
+            templates: BTreeMap::new(),
+            copies: maplit::btreemap! {
+                PathBuf::from("a_in") => CopyTarget::from("a_out"),
+            },
+        };
+        let cache = Cache {
+            copies: maplit::btreemap! {
+                PathBuf::from("a_in") => CachedCopy {
+                    target: "a_out".into(),
+                    hash: "hash".into(),
+                },
+            },
+            ..Cache::default()
+        };
+
+        let mut fs = MockFilesystem::new();
+        fs.expect_compare_copy()
+            .times(1)
+            .returning(|_, _| Ok(CopyComparison::Identical));
+
+        let rows = status_rows(&mut fs, &cache, &desired, &Options::default()).unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert!(!needs_deploy(&rows));
+        assert!(!needs_deploy(&[]));
+    }
+}